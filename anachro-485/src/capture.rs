@@ -0,0 +1,452 @@
+//! Recording bus traffic
+//!
+//! A `BusCapture` may be attached to an `IoQueue` with `set_capture()`, and
+//! is then shown every frame pushed into or popped out of the `IoHandle`, in
+//! its raw, COBS encoded form.
+//!
+//! With the `std` feature, `PcapngWriter` records frames to a pcapng file,
+//! using the `LINKTYPE_ANACHRO_485` link type, and `PcapngReader` reads them
+//! back. `decode_frame()` turns a recorded frame back into its header and
+//! payload.
+
+use cobs::decode_in_place;
+use postcard::from_bytes;
+use serde::{Deserialize, Deserializer};
+
+use crate::icd::{deserialize_frame, AddrPort, LineHeader};
+
+/// `LINKTYPE_USER0`, the first link type reserved for private use
+pub const LINKTYPE_ANACHRO_485: u16 = 147;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDir {
+    /// Received from the bus
+    Incoming,
+
+    /// Sent onto the bus
+    Outgoing,
+}
+
+/// A destination for captured frames
+///
+/// This is called from the IO handler's context, so should return quickly.
+pub trait BusCapture: Sync {
+    /// `tick` is the time the frame was received, if known. `frame` is COBS
+    /// encoded, and may or may not include the zero terminator.
+    fn capture(&self, dir: CaptureDir, tick: Option<u32>, frame: &[u8]);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The frame does not fit in the given buffer
+    TooLarge,
+    Cobs,
+    Deser,
+}
+
+/// The contents of a decoded frame
+#[derive(Debug)]
+pub struct CapturedMessage<'a> {
+    pub src: AddrPort,
+    pub dst: AddrPort,
+
+    /// The sender's tick when the message was sent, if included
+    pub tick: Option<u32>,
+
+    /// The payload, as it appeared on the wire. Payloads of secure ports
+    /// are still sealed.
+    pub payload: &'a [u8],
+}
+
+// The same layout as a `LineMessage`, without needing a slab
+struct WireMessage<'a> {
+    hdr: LineHeader,
+    msg: &'a [u8],
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for WireMessage<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (hdr, msg) = deserialize_frame(deserializer)?;
+        Ok(WireMessage { hdr, msg })
+    }
+}
+
+/// Decode a captured frame, using `buf` as scratch space
+pub fn decode_frame<'a>(
+    frame: &[u8],
+    buf: &'a mut [u8],
+) -> Result<CapturedMessage<'a>, DecodeError> {
+    let buf = buf.get_mut(..frame.len()).ok_or(DecodeError::TooLarge)?;
+    buf.copy_from_slice(frame);
+
+    let len = decode_in_place(buf).map_err(|_| DecodeError::Cobs)?;
+    let msg = from_bytes::<WireMessage>(&buf[..len]).map_err(|_| DecodeError::Deser)?;
+
+    Ok(CapturedMessage {
+        src: msg.hdr.src,
+        dst: msg.hdr.dst,
+        tick: msg.hdr.tick,
+        payload: msg.msg,
+    })
+}
+
+#[cfg(feature = "std")]
+pub use self::pcapng::{CapturedFrame, PcapngReader, PcapngWriter};
+
+#[cfg(feature = "std")]
+mod pcapng {
+    use std::{
+        io::{self, Read, Write},
+        sync::Mutex,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{BusCapture, CaptureDir, LINKTYPE_ANACHRO_485};
+
+    const SHB_TYPE: u32 = 0x0A0D_0D0A;
+    const IDB_TYPE: u32 = 0x0000_0001;
+    const EPB_TYPE: u32 = 0x0000_0006;
+    const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+    const OPT_END: u16 = 0;
+    const OPT_EPB_FLAGS: u16 = 2;
+
+    // Direction bits of `epb_flags`
+    const FLAG_INBOUND: u32 = 0b01;
+    const FLAG_OUTBOUND: u32 = 0b10;
+    const FLAG_DIR_MASK: u32 = 0b11;
+
+    const SNAP_LEN: u32 = 0x0004_0000;
+
+    // The longest block we will read: an EPB of a full `SNAP_LEN` frame, with
+    // room for its fixed fields and options
+    const MAX_BLOCK_LEN: usize = SNAP_LEN as usize + 256;
+
+    /// A frame read back from a capture
+    #[derive(Debug, Clone)]
+    pub struct CapturedFrame {
+        /// Microseconds since the unix epoch, when the frame was captured
+        pub timestamp_us: u64,
+        pub dir: Option<CaptureDir>,
+        pub data: Vec<u8>,
+    }
+
+    /// Records frames to a pcapng stream
+    ///
+    /// Frames are timestamped with the host's clock when they are captured.
+    /// Write errors are ignored when used as a `BusCapture`, as there is
+    /// nobody to report them to.
+    pub struct PcapngWriter<W: Write + Send> {
+        out: Mutex<W>,
+    }
+
+    impl<W: Write + Send> PcapngWriter<W> {
+        /// Start a capture, writing the file headers to `out`
+        pub fn new(mut out: W) -> io::Result<Self> {
+            // Section Header Block, with an unknown section length
+            let mut shb = Vec::new();
+            shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+            shb.extend_from_slice(&1u16.to_le_bytes());
+            shb.extend_from_slice(&0u16.to_le_bytes());
+            shb.extend_from_slice(&u64::MAX.to_le_bytes());
+            write_block(&mut out, SHB_TYPE, &shb)?;
+
+            // Interface Description Block, with the default microsecond resolution
+            let mut idb = Vec::new();
+            idb.extend_from_slice(&LINKTYPE_ANACHRO_485.to_le_bytes());
+            idb.extend_from_slice(&0u16.to_le_bytes());
+            idb.extend_from_slice(&SNAP_LEN.to_le_bytes());
+            write_block(&mut out, IDB_TYPE, &idb)?;
+
+            out.flush()?;
+
+            Ok(Self {
+                out: Mutex::new(out),
+            })
+        }
+
+        /// Record a single frame
+        pub fn write_frame(&self, dir: CaptureDir, frame: &[u8]) -> io::Result<()> {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .unwrap_or(0);
+
+            let flags = match dir {
+                CaptureDir::Incoming => FLAG_INBOUND,
+                CaptureDir::Outgoing => FLAG_OUTBOUND,
+            };
+
+            // Enhanced Packet Block
+            let mut epb = Vec::with_capacity(frame.len() + 40);
+            epb.extend_from_slice(&0u32.to_le_bytes());
+            epb.extend_from_slice(&((now >> 32) as u32).to_le_bytes());
+            epb.extend_from_slice(&(now as u32).to_le_bytes());
+            epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            epb.extend_from_slice(frame);
+            pad_to_u32(&mut epb);
+
+            epb.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+            epb.extend_from_slice(&4u16.to_le_bytes());
+            epb.extend_from_slice(&flags.to_le_bytes());
+            epb.extend_from_slice(&OPT_END.to_le_bytes());
+            epb.extend_from_slice(&0u16.to_le_bytes());
+
+            let mut out = self.out.lock().map_err(|_| io::Error::other("poisoned"))?;
+            write_block(&mut *out, EPB_TYPE, &epb)?;
+            out.flush()
+        }
+    }
+
+    impl<W: Write + Send> BusCapture for PcapngWriter<W> {
+        fn capture(&self, dir: CaptureDir, _tick: Option<u32>, frame: &[u8]) {
+            self.write_frame(dir, frame).ok();
+        }
+    }
+
+    /// Reads frames back from a pcapng stream written by `PcapngWriter`
+    ///
+    /// Only little endian captures are supported. Blocks other than
+    /// Enhanced Packet Blocks are skipped.
+    pub struct PcapngReader<R: Read> {
+        inp: R,
+    }
+
+    impl<R: Read> PcapngReader<R> {
+        pub fn new(mut inp: R) -> io::Result<Self> {
+            let (kind, body) = read_block(&mut inp)?.ok_or_else(|| invalid("empty capture"))?;
+
+            if kind != SHB_TYPE || body.get(..4) != Some(&BYTE_ORDER_MAGIC.to_le_bytes()[..]) {
+                return Err(invalid("not a little endian pcapng capture"));
+            }
+
+            Ok(Self { inp })
+        }
+
+        /// The next frame, or `None` at the end of the capture
+        pub fn next_frame(&mut self) -> io::Result<Option<CapturedFrame>> {
+            loop {
+                let (kind, body) = match read_block(&mut self.inp)? {
+                    Some(block) => block,
+                    None => return Ok(None),
+                };
+
+                if kind == EPB_TYPE {
+                    return parse_epb(&body).map(Some);
+                }
+            }
+        }
+    }
+
+    fn parse_epb(body: &[u8]) -> io::Result<CapturedFrame> {
+        let word = |idx: usize| -> io::Result<u32> {
+            body.get(idx * 4..(idx + 1) * 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| invalid("short packet block"))
+        };
+
+        let timestamp_us = ((word(1)? as u64) << 32) | (word(2)? as u64);
+        let len = word(3)? as usize;
+        let data = body
+            .get(20..20 + len)
+            .ok_or_else(|| invalid("short packet block"))?
+            .to_vec();
+
+        // Look for the direction in the options
+        let mut dir = None;
+        let mut opts = body.get(20 + ((len + 3) & !3)..).unwrap_or(&[]);
+        while opts.len() >= 4 {
+            let code = u16::from_le_bytes([opts[0], opts[1]]);
+            let olen = u16::from_le_bytes([opts[2], opts[3]]) as usize;
+            let val = opts.get(4..4 + olen).unwrap_or(&[]);
+
+            if code == OPT_END {
+                break;
+            }
+            if code == OPT_EPB_FLAGS && val.len() == 4 {
+                let flags = u32::from_le_bytes([val[0], val[1], val[2], val[3]]);
+                dir = match flags & FLAG_DIR_MASK {
+                    FLAG_INBOUND => Some(CaptureDir::Incoming),
+                    FLAG_OUTBOUND => Some(CaptureDir::Outgoing),
+                    _ => None,
+                };
+            }
+
+            opts = opts.get(4 + ((olen + 3) & !3)..).unwrap_or(&[]);
+        }
+
+        Ok(CapturedFrame {
+            timestamp_us,
+            dir,
+            data,
+        })
+    }
+
+    fn write_block<W: Write + ?Sized>(out: &mut W, kind: u32, body: &[u8]) -> io::Result<()> {
+        let total = (12 + body.len()) as u32;
+        out.write_all(&kind.to_le_bytes())?;
+        out.write_all(&total.to_le_bytes())?;
+        out.write_all(body)?;
+        out.write_all(&total.to_le_bytes())
+    }
+
+    fn read_block<R: Read>(inp: &mut R) -> io::Result<Option<(u32, Vec<u8>)>> {
+        let mut hdr = [0u8; 8];
+        match inp.read_exact(&mut hdr) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let kind = u32::from_le_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]);
+        let total = u32::from_le_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]) as usize;
+        if total < 12 || !total.is_multiple_of(4) {
+            return Err(invalid("bad block length"));
+        }
+        if total > MAX_BLOCK_LEN {
+            return Err(invalid("block too long"));
+        }
+
+        // The body, followed by the repeated length
+        let mut body = vec![0u8; total - 8];
+        inp.read_exact(&mut body)?;
+        body.truncate(total - 12);
+
+        Ok(Some((kind, body)))
+    }
+
+    fn pad_to_u32(buf: &mut Vec<u8>) {
+        while !buf.len().is_multiple_of(4) {
+            buf.push(0);
+        }
+    }
+
+    fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn round_trip() {
+            let mut file = Vec::new();
+            let writer = PcapngWriter::new(&mut file).unwrap();
+
+            // Odd lengths need padding to the next word
+            writer
+                .write_frame(CaptureDir::Incoming, &[1, 2, 3, 4, 5])
+                .unwrap();
+            writer
+                .write_frame(CaptureDir::Outgoing, &[6, 7, 8, 9])
+                .unwrap();
+            writer.write_frame(CaptureDir::Incoming, &[]).unwrap();
+
+            assert_eq!(file.len() % 4, 0);
+
+            let mut reader = PcapngReader::new(&file[..]).unwrap();
+
+            let frame = reader.next_frame().unwrap().unwrap();
+            assert_eq!(frame.dir, Some(CaptureDir::Incoming));
+            assert_eq!(frame.data, &[1, 2, 3, 4, 5]);
+
+            let frame = reader.next_frame().unwrap().unwrap();
+            assert_eq!(frame.dir, Some(CaptureDir::Outgoing));
+            assert_eq!(frame.data, &[6, 7, 8, 9]);
+
+            let frame = reader.next_frame().unwrap().unwrap();
+            assert_eq!(frame.dir, Some(CaptureDir::Incoming));
+            assert!(frame.data.is_empty());
+
+            assert!(reader.next_frame().unwrap().is_none());
+        }
+
+        #[test]
+        fn not_pcapng() {
+            assert!(PcapngReader::new(&[0u8; 32][..]).is_err());
+            assert!(PcapngReader::new(&[][..]).is_err());
+        }
+
+        #[test]
+        fn block_too_long() {
+            let mut file = Vec::new();
+            PcapngWriter::new(&mut file).unwrap();
+
+            // An EPB claiming to be far larger than any frame we write
+            file.extend_from_slice(&EPB_TYPE.to_le_bytes());
+            file.extend_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+
+            let mut reader = PcapngReader::new(&file[..]).unwrap();
+            let err = reader.next_frame().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::icd::{LineMessage, VecAddr};
+    use byte_slab::ManagedArcSlab;
+    use postcard::to_slice_cobs;
+
+    fn frame(tick: Option<u32>, buf: &mut [u8]) -> &mut [u8] {
+        let msg: LineMessage<1, 64> = LineMessage {
+            hdr: LineHeader {
+                src: AddrPort::from_parts(VecAddr::from_local_addr(3), 10),
+                dst: AddrPort::from_parts(VecAddr::local_dom_addr(), 11),
+                tick,
+            },
+            msg: ManagedArcSlab::Borrowed(&[0, 1, 2]),
+        };
+        to_slice_cobs(&msg, buf).unwrap()
+    }
+
+    #[test]
+    fn decode() {
+        for &tick in &[None, Some(1234)] {
+            let mut enc = [0u8; 64];
+            let enc = frame(tick, &mut enc);
+            let mut buf = [0u8; 64];
+            let msg = decode_frame(enc, &mut buf).unwrap();
+
+            assert_eq!(
+                msg.src,
+                AddrPort::from_parts(VecAddr::from_local_addr(3), 10)
+            );
+            assert_eq!(msg.dst, AddrPort::from_parts(VecAddr::local_dom_addr(), 11));
+            assert_eq!(msg.tick, tick);
+            assert_eq!(msg.payload, &[0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn decode_corrupt() {
+        let mut enc = [0u8; 64];
+        let enc = frame(None, &mut enc);
+        let mut buf = [0u8; 64];
+
+        // Valid COBS, but only the source address
+        let mut src_only = [0u8; 64];
+        let src = AddrPort::from_parts(VecAddr::from_local_addr(3), 10);
+        let src_only = to_slice_cobs(&src, &mut src_only).unwrap();
+        assert_eq!(
+            decode_frame(src_only, &mut buf).unwrap_err(),
+            DecodeError::Deser
+        );
+
+        // A COBS code pointing past the end of the frame
+        assert_eq!(
+            decode_frame(&[0x09, 1, 2, 0], &mut buf).unwrap_err(),
+            DecodeError::Cobs
+        );
+
+        // No room to decode
+        assert_eq!(
+            decode_frame(enc, &mut buf[..4]).unwrap_err(),
+            DecodeError::TooLarge
+        );
+    }
+}
@@ -1,36 +1,136 @@
-use crate::icd::{
-    AddrPort, LineHeader, LineMessage, VecAddr, LOCAL_BROADCAST_ADDR, LOCAL_DOM_ADDR, SLAB_SIZE,
-    TOTAL_SLABS,
+use crate::{
+    capture::{BusCapture, CaptureDir},
+    icd::{
+        AddrPort, DispatchStats, LineHeader, LineMessage, VecAddr, LOCAL_BROADCAST_ADDR,
+        LOCAL_DOM_ADDR, LOCAL_LOOPBACK_ADDR, MAX_ADDR_SEGMENTS,
+    },
+    latency::{LatencySummary, LatencyTotals},
+    security::{PortSecurity, SecurityError},
 };
 
+use crate::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering::SeqCst};
 use core::{
+    future::Future,
     num::NonZeroU16,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering::SeqCst},
+    pin::Pin,
+    task::{Context, Poll},
 };
 
-use byte_slab::{BSlab, ManagedArcSlab, SlabBox, Reroot};
-use cobs::decode_in_place;
+use byte_slab::{
+    from_slab_slice_arc, AnyAlloc, AnyBox, AnySliceArc, ManagedArcSlab, SlabBox, SlabHandle,
+    SlabWriter, Waiters,
+};
+use cobs::{decode_in_place, CobsEncoder};
+use groundhog::RollingTimer;
 use heapless::mpmc::MpMcQueue;
-use postcard::{from_bytes, to_slice, to_slice_cobs};
+use postcard::{flavors::SerFlavor, serialize_with_flavor, to_slice};
 use serde::Serialize;
 
 const TASK_QUEUE_DEPTH: usize = 4;
 const IO_QUEUE_DEPTH: usize = 32;
 
-type BBox = SlabBox<TOTAL_SLABS, SLAB_SIZE>;
-type AllocSlab = BSlab<TOTAL_SLABS, SLAB_SIZE>;
-type MASlab = ManagedArcSlab<'static, TOTAL_SLABS, SLAB_SIZE>;
+/// The largest serialized `LineHeader`, plus the length of the payload
+const MAX_FRAME_PREFIX: usize = 2 * (1 + MAX_ADDR_SEGMENTS + 3) + 5;
+
+/// The size of the tick trailer, a `u32`
+const MAX_TICK_TRAILER: usize = 4;
+
+/// The largest payload that still fits in a COBS encoded frame in an `sz`
+/// byte slab, with the largest header, tick trailer and terminator
+pub(crate) const fn max_frame_payload(sz: usize) -> usize {
+    sz.saturating_sub(MAX_FRAME_PREFIX + MAX_TICK_TRAILER + sz / 254 + 2)
+}
+
+type MASlab<const N: usize, const SZ: usize> = ManagedArcSlab<'static, N, SZ>;
+
+/// Where to allocate outgoing packets from: the bus' slab, and optionally a
+/// `SizedSlab` (or any other `AnyAlloc`) for shorter messages
+///
+/// With a sized allocator, messages and their encoded frames are placed in
+/// the smallest class that fits them, falling back to the bus' slab if no
+/// class smaller than it fits, or has elements available.
+#[derive(Clone)]
+pub struct PacketAlloc<const N: usize, const SZ: usize> {
+    full: SlabHandle<N, SZ>,
+    sized: Option<&'static dyn AnyAlloc>,
+}
+
+impl<const N: usize, const SZ: usize> PacketAlloc<N, SZ> {
+    pub const fn new(full: SlabHandle<N, SZ>) -> Self {
+        Self { full, sized: None }
+    }
+
+    pub const fn with_sized(full: SlabHandle<N, SZ>, sized: &'static dyn AnyAlloc) -> Self {
+        Self {
+            full,
+            sized: Some(sized),
+        }
+    }
+
+    /// The full size slab
+    pub fn full(&self) -> &SlabHandle<N, SZ> {
+        &self.full
+    }
+
+    /// A box of at least `len` bytes from the sized allocator, if it has one
+    /// that is smaller than the full size slab
+    fn sized_box(&self, len: usize) -> Option<AnyBox> {
+        self.sized?.alloc_any(len).filter(|abox| abox.len() < SZ)
+    }
+}
+
+/// The bytes of a packet, in the bus' slab or in a smaller one
+#[derive(Clone)]
+pub enum PacketBuf<const N: usize, const SZ: usize> {
+    Slab(MASlab<N, SZ>),
+    Sized(AnySliceArc),
+}
+
+impl<const N: usize, const SZ: usize> Deref for PacketBuf<N, SZ> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            PacketBuf::Slab(mas) => mas.deref(),
+            PacketBuf::Sized(asa) => asa.deref(),
+        }
+    }
+}
+
+/// A postcard flavor that only counts the serialized length
+struct SerializedLen(usize);
+
+impl SerFlavor for SerializedLen {
+    type Output = usize;
+
+    fn try_extend(&mut self, data: &[u8]) -> Result<(), ()> {
+        self.0 += data.len();
+        Ok(())
+    }
+
+    fn try_push(&mut self, _data: u8) -> Result<(), ()> {
+        self.0 += 1;
+        Ok(())
+    }
 
-pub struct TimeStampBox {
-    pub packet: BBox,
+    fn release(self) -> Result<usize, ()> {
+        Ok(self.0)
+    }
+}
+
+pub struct TimeStampBox<const N: usize, const SZ: usize> {
+    pub packet: SlabBox<N, SZ>,
     pub len: usize,
     pub tick: u32,
 }
 
-pub struct OutgoingSlab {
-    pub packet: MASlab,
+pub struct OutgoingSlab<const N: usize, const SZ: usize> {
+    pub packet: PacketBuf<N, SZ>,
     pub receive_ticks_min: Option<u32>,
+
+    /// When the message was handed to its socket, if known
+    pub sent_tick: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -40,10 +140,11 @@ pub struct LocalHeader {
     pub tick: u32,
 }
 
-pub struct LocalPacket {
+pub struct LocalPacket<const N: usize, const SZ: usize> {
     pub(crate) hdr: LocalHeader,
-    pub(crate) payload: MASlab,
+    pub(crate) payload: PacketBuf<N, SZ>,
     pub(crate) response_wait_ticks: Option<u32>,
+    pub(crate) sent_tick: Option<u32>,
 }
 
 pub enum AwakeIoHandler {
@@ -51,12 +152,13 @@ pub enum AwakeIoHandler {
     Yes,
 }
 
-impl LocalPacket {
-    pub fn from_hdr_payload(hdr: LocalHeader, payload: MASlab) -> Self {
+impl<const N: usize, const SZ: usize> LocalPacket<N, SZ> {
+    pub fn from_hdr_payload(hdr: LocalHeader, payload: MASlab<N, SZ>) -> Self {
         Self {
             hdr,
-            payload,
+            payload: PacketBuf::Slab(payload),
             response_wait_ticks: None,
+            sent_tick: None,
         }
     }
 
@@ -68,8 +170,25 @@ impl LocalPacket {
         self.payload.deref()
     }
 
-    pub fn payload_slab(&self) -> &MASlab {
-        &self.payload
+    /// The payload's slab, unless it was placed in a sized one
+    pub fn payload_slab(&self) -> Option<&MASlab<N, SZ>> {
+        match &self.payload {
+            PacketBuf::Slab(mas) => Some(mas),
+            PacketBuf::Sized(_) => None,
+        }
+    }
+
+    /// When the packet was sent. For received packets, this is only known
+    /// if the sending port has wire timestamps enabled, and is in the
+    /// sender's ticks.
+    pub fn sent_tick(&self) -> Option<u32> {
+        self.sent_tick
+    }
+
+    /// How long a received packet took to get here, from the sender's
+    /// socket to our IO handler
+    pub fn latency_ticks(&self) -> Option<u32> {
+        Some(self.hdr.tick.wrapping_sub(self.sent_tick?))
     }
 
     pub fn from_parts_with_alloc<T: Serialize>(
@@ -77,55 +196,232 @@ impl LocalPacket {
         src: AddrPort,
         dst: AddrPort,
         rx_ticks: Option<u32>,
-        allo: &'static AllocSlab,
+        allo: &SlabHandle<N, SZ>,
     ) -> Option<Self> {
-        let mut buf = allo.alloc_box()?;
-        let len = to_slice(&msg, buf.deref_mut()).ok()?.len();
-        let arc = buf.into_arc();
-        let ssa = arc.sub_slice_arc(0, len).ok()?;
+        Self::try_from_parts_with_alloc(msg, src, dst, rx_ticks, allo).ok()
+    }
+
+    /// Like `from_parts_with_alloc()`, but reports why the packet could
+    /// not be created
+    pub fn try_from_parts_with_alloc<T: Serialize>(
+        msg: T,
+        src: AddrPort,
+        dst: AddrPort,
+        rx_ticks: Option<u32>,
+        allo: &SlabHandle<N, SZ>,
+    ) -> Result<Self, SendError<N, SZ>> {
+        let buf = allo.alloc_box().ok_or(SendError::NoAlloc)?;
+        Self::try_from_parts_with_box(msg, src, dst, rx_ticks, buf)
+    }
+
+    /// Like `try_from_parts_with_alloc()`, with an already allocated `buf`
+    fn try_from_parts_with_box<T: Serialize>(
+        msg: T,
+        src: AddrPort,
+        dst: AddrPort,
+        rx_ticks: Option<u32>,
+        buf: SlabBox<N, SZ>,
+    ) -> Result<Self, SendError<N, SZ>> {
+        let ssa = SlabWriter::serialize(&msg, buf).map_err(|_| SendError::Serialize)?;
+
+        Ok(Self::from_parts_payload(
+            PacketBuf::Slab(ManagedArcSlab::Owned(ssa)),
+            src,
+            dst,
+            rx_ticks,
+        ))
+    }
+
+    /// Like `from_parts_with_alloc()`, but uses the sized allocator of
+    /// `allo` if it has a class that fits the serialized message
+    pub fn from_parts_with_packet_alloc<T: Serialize>(
+        msg: T,
+        src: AddrPort,
+        dst: AddrPort,
+        rx_ticks: Option<u32>,
+        allo: &PacketAlloc<N, SZ>,
+    ) -> Option<Self> {
+        let len = serialize_with_flavor(&msg, SerializedLen(0)).ok()?;
+
+        let sized = allo.sized_box(len).and_then(|mut abox| {
+            let used = to_slice(&msg, &mut abox).ok()?.len();
+            abox.into_arc().sub_slice_arc(0, used).ok()
+        });
+
+        match sized {
+            Some(asa) => Some(Self::from_parts_payload(
+                PacketBuf::Sized(asa),
+                src,
+                dst,
+                rx_ticks,
+            )),
+            None => Self::from_parts_with_alloc(msg, src, dst, rx_ticks, &allo.full),
+        }
+    }
 
-        let lcp = LocalPacket {
+    fn from_parts_payload(
+        payload: PacketBuf<N, SZ>,
+        src: AddrPort,
+        dst: AddrPort,
+        rx_ticks: Option<u32>,
+    ) -> Self {
+        LocalPacket {
             hdr: LocalHeader {
                 src,
                 dst,
 
-                // TODO: record tick?
+                // Only used for received packets, see `sent_tick`
                 tick: 0,
             },
-            payload: ManagedArcSlab::Owned(ssa),
+            payload,
             response_wait_ticks: rx_ticks,
-        };
+            sent_tick: None,
+        }
+    }
+}
+
+/// Why a message could not be handed to the `Dispatch`
+pub enum SendError<const N: usize, const SZ: usize> {
+    /// The port's outgoing queue is full. The packet is handed back, so
+    /// it may be sent again later.
+    QueueFull(LocalPacket<N, SZ>),
+
+    /// The port may not authorize sends. The packet is handed back.
+    NotAuthorized(LocalPacket<N, SZ>),
+
+    /// No slab was available to hold the message
+    NoAlloc,
 
-        Some(lcp)
+    /// The message did not fit in a slab
+    Serialize,
+}
+
+impl<const N: usize, const SZ: usize> SendError<N, SZ> {
+    /// The packet that could not be sent, if it was created at all
+    pub fn into_packet(self) -> Option<LocalPacket<N, SZ>> {
+        match self {
+            SendError::QueueFull(pkt) | SendError::NotAuthorized(pkt) => Some(pkt),
+            SendError::NoAlloc | SendError::Serialize => None,
+        }
     }
 }
 
-struct PortQueue {
+/// What to do with a message received for a port whose queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Keep the queued messages, and drop the new one
+    DropNewest = 0,
+
+    /// Drop the oldest queued message, to make room for the new one
+    DropOldest = 1,
+}
+
+impl DropPolicy {
+    fn from_u8(val: u8) -> Self {
+        match val {
+            1 => DropPolicy::DropOldest,
+            _ => DropPolicy::DropNewest,
+        }
+    }
+}
+
+struct PortQueue<const N: usize, const SZ: usize> {
     port: AtomicU16,
-    to_task: MpMcQueue<LocalPacket, TASK_QUEUE_DEPTH>,
-    to_dispatch: MpMcQueue<LocalPacket, TASK_QUEUE_DEPTH>,
+    to_task: MpMcQueue<LocalPacket<N, SZ>, TASK_QUEUE_DEPTH>,
+    to_dispatch: MpMcQueue<LocalPacket<N, SZ>, TASK_QUEUE_DEPTH>,
+
+    /// Tasks waiting in `DispatchSocket::send()` for room in `to_dispatch`
+    room: Waiters,
+    security: spin::Mutex<Option<&'static PortSecurity>>,
+    drop_policy: AtomicU8,
+    wire_ticks: AtomicBool,
 }
 
-pub struct IoQueue {
+impl<const N: usize, const SZ: usize> PortQueue<N, SZ> {
+    const UNUSED: Self = PortQueue {
+        port: AtomicU16::new(INVALID_PORT),
+        to_task: MpMcQueue::new(),
+        to_dispatch: MpMcQueue::new(),
+        room: Waiters::new(),
+        security: spin::Mutex::new(None),
+        drop_policy: AtomicU8::new(DropPolicy::DropNewest as u8),
+        wire_ticks: AtomicBool::new(false),
+    };
+
+    fn security(&self) -> Option<&'static PortSecurity> {
+        *self.security.lock()
+    }
+
+    /// Take the next packet sent by the task, waking any tasks waiting for
+    /// room to send
+    fn take_outgoing(&self) -> Option<LocalPacket<N, SZ>> {
+        let msg = self.to_dispatch.dequeue()?;
+        self.room.wake_all();
+        Some(msg)
+    }
+
+    /// Hand a message to the task, following the port's `DropPolicy` if
+    /// the queue is full. Returns whether an older message was dropped to
+    /// make room.
+    fn enqueue_task(&self, lp: LocalPacket<N, SZ>) -> Result<bool, ProcessMessageError> {
+        let lp = match self.to_task.enqueue(lp) {
+            Ok(()) => return Ok(false),
+            Err(lp) => lp,
+        };
+
+        match DropPolicy::from_u8(self.drop_policy.load(SeqCst)) {
+            DropPolicy::DropNewest => Err(ProcessMessageError::TaskQueueFull),
+            DropPolicy::DropOldest => {
+                // The task may have made room in the meantime, in which
+                // case nothing is lost
+                let dropped = self.to_task.dequeue().is_some();
+                self.to_task
+                    .enqueue(lp)
+                    .map_err(|_| ProcessMessageError::TaskQueueFull)?;
+                Ok(dropped)
+            }
+        }
+    }
+}
+
+// The queues don't drop what is left in them, which would keep a heap slab
+// alive forever
+impl<const N: usize, const SZ: usize> Drop for PortQueue<N, SZ> {
+    fn drop(&mut self) {
+        while self.to_task.dequeue().is_some() {}
+        while self.to_dispatch.dequeue().is_some() {}
+    }
+}
+
+pub struct IoQueue<const N: usize, const SZ: usize> {
     /// A queue of serialized messages sent to the IO handler
-    to_io: MpMcQueue<OutgoingSlab, IO_QUEUE_DEPTH>,
+    to_io: MpMcQueue<OutgoingSlab<N, SZ>, IO_QUEUE_DEPTH>,
 
     /// A queue of serialized messages sent to the IO handler
-    to_io_hi_prio: MpMcQueue<OutgoingSlab, IO_QUEUE_DEPTH>,
+    to_io_hi_prio: MpMcQueue<OutgoingSlab<N, SZ>, IO_QUEUE_DEPTH>,
 
     /// A queue of incoming, serialized messages sent to the
     /// dispatch handler
-    to_dispatch: MpMcQueue<TimeStampBox, IO_QUEUE_DEPTH>,
+    to_dispatch: MpMcQueue<TimeStampBox<N, SZ>, IO_QUEUE_DEPTH>,
 
     /// Has the IO Handle been given out already?
     io_given: AtomicBool,
 
     io_auth: IoAuth,
+
+    /// Shown every frame passing through the IO Handle, if set
+    capture: spin::Mutex<Option<&'static dyn BusCapture>>,
+
+    /// Reads the current tick, if set
+    timer: spin::Mutex<Option<fn() -> u32>>,
+
+    /// Time from socket to `pop_outgoing()`
+    residency: LatencyTotals,
 }
 
 /// The control and queue handle, intended to be driven by the IO Handler
-pub struct IoHandle {
-    ioq: &'static IoQueue,
+pub struct IoHandle<'a, const N: usize, const SZ: usize> {
+    ioq: &'a IoQueue<N, SZ>,
 }
 
 pub struct IoAuth {
@@ -143,16 +439,30 @@ pub struct IoAuth {
     io_empty_auth: AtomicBool,
 }
 
-impl IoHandle {
-    pub fn push_incoming(&mut self, tsb: TimeStampBox) -> Result<(), TimeStampBox> {
+impl<'a, const N: usize, const SZ: usize> IoHandle<'a, N, SZ> {
+    pub fn push_incoming(&mut self, tsb: TimeStampBox<N, SZ>) -> Result<(), TimeStampBox<N, SZ>> {
+        if let Some(cap) = *self.ioq.capture.lock() {
+            let len = tsb.len.min(tsb.packet.len());
+            cap.capture(CaptureDir::Incoming, Some(tsb.tick), &tsb.packet[..len]);
+        }
         self.ioq.to_dispatch.enqueue(tsb)
     }
 
-    pub fn pop_outgoing(&mut self) -> Option<OutgoingSlab> {
-        match self.ioq.to_io_hi_prio.dequeue() {
+    pub fn pop_outgoing(&mut self) -> Option<OutgoingSlab<N, SZ>> {
+        let ogs = match self.ioq.to_io_hi_prio.dequeue() {
             a @ Some(_) => a,
             None => self.ioq.to_io.dequeue(),
+        }?;
+
+        let now = self.ioq.now();
+        if let (Some(now), Some(sent)) = (now, ogs.sent_tick) {
+            self.ioq.residency.record(now.wrapping_sub(sent));
+        }
+
+        if let Some(cap) = *self.ioq.capture.lock() {
+            cap.capture(CaptureDir::Outgoing, now, ogs.packet.deref());
         }
+        Some(ogs)
     }
 
     pub fn auth(&self) -> &IoAuth {
@@ -182,7 +492,7 @@ impl IoAuth {
     }
 }
 
-impl IoQueue {
+impl<const N: usize, const SZ: usize> IoQueue<N, SZ> {
     pub const fn new() -> Self {
         Self {
             to_io: MpMcQueue::new(),
@@ -194,18 +504,51 @@ impl IoQueue {
                 io_flush_auth: AtomicBool::new(false),
                 io_empty_auth: AtomicBool::new(false),
             },
+            capture: spin::Mutex::new(None),
+            timer: spin::Mutex::new(None),
+            residency: LatencyTotals::new(),
         }
     }
 
+    /// Show every frame sent or received by the IO handler to `capture`
+    pub fn set_capture(&self, capture: &'static dyn BusCapture) {
+        *self.capture.lock() = Some(capture);
+    }
+
+    /// Timestamp outgoing messages with `R`, which should be the same timer
+    /// the IO handler uses to timestamp incoming messages
+    pub fn set_timer<R: RollingTimer<Tick = u32> + Default>(&self) {
+        *self.timer.lock() = Some(ticks::<R>);
+    }
+
+    /// The current tick, if a timer has been set
+    pub fn now(&self) -> Option<u32> {
+        (*self.timer.lock()).map(|now| now())
+    }
+
+    /// How long outgoing messages waited between being sent by a task and
+    /// being taken by the IO handler
+    pub fn residency(&self) -> LatencySummary {
+        self.residency.summary()
+    }
+
     // TODO: I need to probably have one for each half, the IoHandle
     // (that goes to the hardware I/O), and for Dispatch (which for now
     // just borrows the IoQ itself).
-    pub fn take_io_handle(&'static self) -> Option<IoHandle> {
+    pub fn take_io_handle(&self) -> Option<IoHandle<'_, N, SZ>> {
         self.io_given
             .compare_exchange(false, true, SeqCst, SeqCst)
             .ok()?;
 
-        Some(IoHandle { ioq: &self })
+        Some(IoHandle { ioq: self })
+    }
+}
+
+impl<const N: usize, const SZ: usize> Drop for IoQueue<N, SZ> {
+    fn drop(&mut self) {
+        while self.to_io.dequeue().is_some() {}
+        while self.to_io_hi_prio.dequeue().is_some() {}
+        while self.to_dispatch.dequeue().is_some() {}
     }
 }
 
@@ -216,18 +559,77 @@ impl IoQueue {
 /// the ability to deprovision correctly, and is intended for all ports
 /// to be assigned once, from a single thread, at the top of the
 /// program. All other uses beware (for now)
-pub struct Dispatch<const PORTS: usize> {
-    ports: [PortQueue; PORTS],
-    ioq: &'static IoQueue,
+pub struct Dispatch<'q, const PORTS: usize, const N: usize, const SZ: usize> {
+    ports: [PortQueue<N, SZ>; PORTS],
+    ioq: &'q IoQueue<N, SZ>,
     own_addr: AtomicU8,
-    shame: MpMcQueue<OutgoingSlab, 2>,
-    alloc: &'static AllocSlab,
-    // TODO: link to another Dispatch for forwarding
+    static_addr: AtomicBool,
+    shame: MpMcQueue<OutgoingSlab<N, SZ>, 2>,
+    alloc: PacketAlloc<N, SZ>,
+    counters: Counters,
+    forward: spin::Mutex<Option<&'static dyn ForwardTarget>>,
+}
+
+impl<'q, const PORTS: usize, const N: usize, const SZ: usize> Drop for Dispatch<'q, PORTS, N, SZ> {
+    fn drop(&mut self) {
+        while self.shame.dequeue().is_some() {}
+    }
+}
+
+/// Somewhere to send messages that are passing through this node, usually
+/// the `Dispatch` of another bus
+///
+/// Payloads are passed on as they were received. Secure ports authenticate
+/// the exact source and destination addresses, so they can not be used
+/// across buses.
+///
+/// Both addresses change on the way through, so the frame is always encoded
+/// again, and the payload copied once into the outgoing frame.
+pub trait ForwardTarget: Sync {
+    /// Send a message on. The first hop has already been removed from the
+    /// destination address, and the source is as received.
+    fn forward(&self, hdr: LineHeader, payload: &[u8]) -> Result<(), ProcessMessageError>;
+}
+
+/// Running totals, see `DispatchStats`
+struct Counters {
+    rx_delivered: AtomicU32,
+    rx_dropped: AtomicU32,
+    tx_sent: AtomicU32,
+    tx_dropped: AtomicU32,
+    local_delivered: AtomicU32,
+    forwarded: AtomicU32,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            rx_delivered: AtomicU32::new(0),
+            rx_dropped: AtomicU32::new(0),
+            tx_sent: AtomicU32::new(0),
+            tx_dropped: AtomicU32::new(0),
+            local_delivered: AtomicU32::new(0),
+            forwarded: AtomicU32::new(0),
+        }
+    }
+
+    fn bump(ctr: &AtomicU32) {
+        ctr.fetch_add(1, SeqCst);
+    }
 }
 
 pub const INVALID_PORT: u16 = 0;
 pub const INVALID_OWN_ADDR: u8 = LOCAL_BROADCAST_ADDR;
 
+/// What happened to a message received from the bus
+enum Received {
+    /// Delivered to one of our ports
+    Delivered,
+
+    /// Passed on to another bus, and counted there
+    Forwarded,
+}
+
 pub enum ProcessMessageError {
     Cobs,
     Deser,
@@ -239,23 +641,81 @@ pub enum ProcessMessageError {
     TaskQueueFull,
     IoQueueFull,
     NoAlloc,
+    NoRoute,
     Ser,
+    Security(SecurityError),
 }
 
-impl<const PORTS: usize> Dispatch<PORTS> {
-    pub const fn new(ioq: &'static IoQueue, alloc: &'static AllocSlab) -> Self {
-        const SINGLE_ITEM: PortQueue = PortQueue {
-            port: AtomicU16::new(INVALID_PORT),
-            to_task: MpMcQueue::new(),
-            to_dispatch: MpMcQueue::new(),
-        };
+impl<'q, const PORTS: usize, const N: usize, const SZ: usize> Dispatch<'q, PORTS, N, SZ> {
+    pub const fn new(ioq: &'q IoQueue<N, SZ>, alloc: SlabHandle<N, SZ>) -> Self {
+        Self::with_packet_alloc(ioq, PacketAlloc::new(alloc))
+    }
 
+    /// Like `new()`, but encodes outgoing frames in the sized allocator of
+    /// `alloc` where it fits them
+    pub const fn with_packet_alloc(ioq: &'q IoQueue<N, SZ>, alloc: PacketAlloc<N, SZ>) -> Self {
         Self {
-            ports: [SINGLE_ITEM; PORTS],
+            ports: [PortQueue::UNUSED; PORTS],
             ioq,
             own_addr: AtomicU8::new(INVALID_OWN_ADDR),
+            static_addr: AtomicBool::new(false),
             shame: MpMcQueue::new(),
             alloc,
+            counters: Counters::new(),
+            forward: spin::Mutex::new(None),
+        }
+    }
+
+    /// COBS encode a frame into the smallest class of the sized allocator
+    /// that fits it, or else into `boxy`, or a new box of the full size slab
+    fn encode_packet(
+        &self,
+        hdr: &LineHeader,
+        payload: &[u8],
+        boxy: Option<SlabBox<N, SZ>>,
+    ) -> Result<PacketBuf<N, SZ>, ProcessMessageError> {
+        let max_len = max_encoded_frame(hdr, payload.len())?;
+
+        if let Some(mut abox) = self.alloc.sized_box(max_len) {
+            let len = encode_frame(hdr, payload, &mut abox)?;
+            let asa = abox
+                .into_arc()
+                .sub_slice_arc(0, len)
+                .map_err(|_| ProcessMessageError::Arc)?;
+            return Ok(PacketBuf::Sized(asa));
+        }
+
+        let mut boxy = boxy
+            .or_else(|| self.alloc.full().alloc_box())
+            .ok_or(ProcessMessageError::NoAlloc)?;
+        let len = encode_frame(hdr, payload, boxy.deref_mut())?;
+        let ssa = boxy
+            .into_arc()
+            .sub_slice_arc(0, len)
+            .map_err(|_| ProcessMessageError::Arc)?;
+        Ok(PacketBuf::Slab(ManagedArcSlab::Owned(ssa)))
+    }
+
+    /// Pass messages addressed through this node on to `target`.
+    ///
+    /// A message is forwarded when the first hop of its destination is our
+    /// own address, and there are more hops after it. Our own address is
+    /// added as the first hop of the source when forwarding, so replies can
+    /// take the same path back.
+    pub fn set_forward(&self, target: &'static dyn ForwardTarget) {
+        *self.forward.lock() = Some(target);
+    }
+
+    /// Message counts since startup
+    pub fn stats(&self) -> DispatchStats {
+        let ctrs = &self.counters;
+        DispatchStats {
+            rx_delivered: ctrs.rx_delivered.load(SeqCst),
+            rx_dropped: ctrs.rx_dropped.load(SeqCst),
+            tx_sent: ctrs.tx_sent.load(SeqCst),
+            tx_dropped: ctrs.tx_dropped.load(SeqCst),
+            local_delivered: ctrs.local_delivered.load(SeqCst),
+            forwarded: ctrs.forwarded.load(SeqCst),
         }
     }
 
@@ -272,13 +732,65 @@ impl<const PORTS: usize> Dispatch<PORTS> {
         }
     }
 
+    /// Use `addr` for good. Unlike one set with `set_addr()`, it is kept by
+    /// `release_addr()`.
+    pub fn set_static_addr(&self, addr: u8) {
+        self.own_addr.store(addr, SeqCst);
+        self.static_addr.store(true, SeqCst);
+    }
+
+    /// Drop our address, so discovery starts over. Returns false (and keeps
+    /// the address) if it is static.
+    pub fn release_addr(&self) -> bool {
+        if self.static_addr.load(SeqCst) {
+            return false;
+        }
+        self.own_addr.store(INVALID_OWN_ADDR, SeqCst);
+        true
+    }
+
+    /// Forget the replay state every secure port holds for `peer`.
+    ///
+    /// This must be called whenever `peer` may have become a different node,
+    /// e.g. when discovery hands its address out again.
+    pub fn reset_peer(&self, peer: u8) {
+        self.ports
+            .iter()
+            .filter_map(|pq| pq.security())
+            .for_each(|sec| sec.reset_peer(peer));
+    }
+
     /// Register a port, and receive a socket for the corresponding port.
     /// It will return None if:
     ///
     /// * The requested port is zero (not allowed)
     /// * We have already allocated the maximum number of port (e.g. `PORTS`)
     /// * The request port has already been allocated
-    pub fn register_port<'a>(&'a self, port: u16) -> Option<DispatchSocket<'a>> {
+    pub fn register_port<'a>(&'a self, port: u16) -> Option<DispatchSocket<'a, N, SZ>> {
+        self.register_port_inner(port, None)
+    }
+
+    /// Register a port whose payloads are sealed and authenticated with the
+    /// given `PortSecurity`, and receive a socket for the corresponding port.
+    ///
+    /// Outgoing payloads are encrypted before hitting the wire, and incoming
+    /// payloads that fail authentication (or are replays) are dropped before
+    /// reaching the socket. Tasks only ever see plaintext.
+    ///
+    /// This fails for the same reasons as `register_port()`.
+    pub fn register_secure_port<'a>(
+        &'a self,
+        port: u16,
+        security: &'static PortSecurity,
+    ) -> Option<DispatchSocket<'a, N, SZ>> {
+        self.register_port_inner(port, Some(security))
+    }
+
+    fn register_port_inner<'a>(
+        &'a self,
+        port: u16,
+        security: Option<&'static PortSecurity>,
+    ) -> Option<DispatchSocket<'a, N, SZ>> {
         // Is the user requesting a valid (non-zero) port?
         let nzport = NonZeroU16::new(port)?;
 
@@ -315,42 +827,57 @@ impl<const PORTS: usize> Dispatch<PORTS> {
                     .is_ok()
             })
             .map(|slot| {
+                *slot.security.lock() = security;
+                slot.drop_policy.store(DropPolicy::DropNewest as u8, SeqCst);
+                slot.wire_ticks.store(false, SeqCst);
+
                 // Return an allocated slot
                 DispatchSocket {
                     port: nzport,
                     to_task: &slot.to_task,
                     to_dispatch: &slot.to_dispatch,
+                    room: &slot.room,
+                    drop_policy: &slot.drop_policy,
+                    wire_ticks: &slot.wire_ticks,
                     send_auth: auth,
+                    alloc: self.alloc.full().clone(),
+                    ioq: self.ioq,
                 }
             })
     }
 
-    fn process_one_incoming(&self, mut tsb: TimeStampBox) -> Result<(), ProcessMessageError> {
+    fn process_one_incoming(
+        &self,
+        mut tsb: TimeStampBox<N, SZ>,
+    ) -> Result<Received, ProcessMessageError> {
         // de-cobs
         let time = tsb.tick;
         let own_addr = self.own_addr.load(SeqCst);
 
         let len = decode_in_place(tsb.packet.deref_mut()).map_err(|_| ProcessMessageError::Cobs)?;
 
-        let arc = tsb.packet.into_arc();
-        let msg = arc
+        let msg = tsb
+            .packet
+            .into_arc()
             .sub_slice_arc(0, len)
             .map_err(|_| ProcessMessageError::Arc)?;
 
-        // deserialize to LineMessage
-        let lm = from_bytes::<LineMessage>(msg.deref()).map_err(|_| ProcessMessageError::Deser)?;
+        // deserialize to LineMessage, keeping the payload in the received slab
+        let lm = from_slab_slice_arc::<LineMessage<N, SZ>, N, SZ>(&msg).map_err(|e| match e {
+            postcard::Error::SerdeDeCustom => ProcessMessageError::ReRoot,
+            _ => ProcessMessageError::Deser,
+        })?;
 
-        // Check address
-        // TODO: Routing?
-        match lm.hdr.dst.addr.get_exact_local_addr() {
+        // Check address, and whether the message is only passing through
+        let next_hops = match lm.hdr.dst.addr.get_exact_local_addr() {
             // Accept broadcast messages
             // NOTE: This is important before we are assigned an address!
             // (and after, because we use broadcast as the 'invalid' own
             // addr)
-            Some(LOCAL_BROADCAST_ADDR) => Ok(()),
+            Some(LOCAL_BROADCAST_ADDR) => Ok(None),
 
             // Accept messages to us
-            Some(addr) if addr == own_addr => Ok(()),
+            Some(addr) if addr == own_addr => Ok(None),
 
             // Don't alert on dom messages (if they aren't for us)
             Some(LOCAL_DOM_ADDR) => Err(ProcessMessageError::DestAddr),
@@ -361,22 +888,37 @@ impl<const PORTS: usize> Dispatch<PORTS> {
                 Err(ProcessMessageError::DestAddr)
             }
 
-            None => {
-                defmt::warn!("not for anyone!");
-                Err(ProcessMessageError::DestAddr)
-            }
+            None => match lm.hdr.dst.addr.first_hop() {
+                // Accept messages to another bus, through us
+                Some(addr) if addr == own_addr && own_addr != INVALID_OWN_ADDR => {
+                    Ok(lm.hdr.dst.addr.next_hops())
+                }
+
+                // Messages through someone else
+                Some(_) => Err(ProcessMessageError::DestAddr),
+
+                None => {
+                    defmt::warn!("not for anyone!");
+                    Err(ProcessMessageError::DestAddr)
+                }
+            },
         }?;
 
+        // Only the first hop of the source is on this bus, the rest is
+        // the path back to wherever the message came from
         let good = lm
             .hdr
             .src
             .addr
-            .get_exact_local_addr()
+            .first_hop()
             .map(|addr| {
                 if own_addr == LOCAL_DOM_ADDR {
-                    // If we are a DOM, don't accept broadcast or DOM as the source
+                    // If we are a DOM, don't accept broadcast, DOM, or loopback
+                    // as the source
                     // TODO: actually check allocation of addresses?
-                    !(addr == LOCAL_BROADCAST_ADDR || addr == LOCAL_DOM_ADDR)
+                    !(addr == LOCAL_BROADCAST_ADDR
+                        || addr == LOCAL_DOM_ADDR
+                        || addr == LOCAL_LOOPBACK_ADDR)
                 } else {
                     // If we are sub, the message must come from the dom
                     addr == LOCAL_DOM_ADDR
@@ -388,6 +930,20 @@ impl<const PORTS: usize> Dispatch<PORTS> {
             return Err(ProcessMessageError::SrcAddr);
         }
 
+        // Pass it on. The payload is encoded into the outgoing frame straight
+        // from the received slab, with no copy in between
+        if let Some(next) = next_hops {
+            let target = (*self.forward.lock()).ok_or(ProcessMessageError::NoRoute)?;
+            let hdr = LineHeader {
+                src: lm.hdr.src,
+                dst: AddrPort::from_parts(next, lm.hdr.dst.port),
+                tick: lm.hdr.tick,
+            };
+            return target
+                .forward(hdr, lm.msg.deref())
+                .map(|()| Received::Forwarded);
+        }
+
         // Check if we have a matching destination port
         let pq = self
             .ports
@@ -395,27 +951,65 @@ impl<const PORTS: usize> Dispatch<PORTS> {
             .find(|pq| pq.port.load(SeqCst) == lm.hdr.dst.port)
             .ok_or(ProcessMessageError::DestPort)?;
 
-        let rrkey = arc.rerooter_key();
+        let payload = match pq.security() {
+            Some(sec) => {
+                let mut plain = self
+                    .alloc
+                    .full()
+                    .alloc_box()
+                    .ok_or(ProcessMessageError::NoAlloc)?;
+                let len = sec
+                    .open(&lm.hdr, lm.msg.deref(), plain.deref_mut())
+                    .map_err(ProcessMessageError::Security)?;
+                let ssa = plain
+                    .into_arc()
+                    .sub_slice_arc(0, len)
+                    .map_err(|_| ProcessMessageError::Arc)?;
+                ManagedArcSlab::Owned(ssa)
+            }
+            None => lm.msg,
+        };
 
         // Ship it!
-        pq.to_task
-            .enqueue(LocalPacket {
-                hdr: LocalHeader {
-                    src: lm.hdr.src,
-                    dst: lm.hdr.dst,
-                    tick: time,
-                },
-                payload: lm.msg.reroot(&rrkey).map_err(|_| ProcessMessageError::ReRoot)?,
-                response_wait_ticks: None,
-            })
-            .map_err(|_| ProcessMessageError::TaskQueueFull)
+        let port = lm.hdr.dst.port;
+        let displaced = pq.enqueue_task(LocalPacket {
+            hdr: LocalHeader {
+                src: lm.hdr.src,
+                dst: lm.hdr.dst,
+                tick: time,
+            },
+            payload: PacketBuf::Slab(payload),
+            response_wait_ticks: None,
+            sent_tick: lm.hdr.tick,
+        })?;
+
+        if displaced {
+            defmt::warn!("port {=u16} full, dropped oldest", port);
+            Counters::bump(&self.counters.rx_dropped);
+        }
+
+        Ok(Received::Delivered)
     }
 
     pub fn process_messages(&self) {
         while let Some(msg) = self.ioq.to_dispatch.dequeue() {
-            if let Err(_e) = self.process_one_incoming(msg) {
-                // TODO: print errors, but dont return early.
-                defmt::error!("message yeeted");
+            match self.process_one_incoming(msg) {
+                Ok(Received::Delivered) => Counters::bump(&self.counters.rx_delivered),
+                Ok(Received::Forwarded) => {}
+
+                // Messages for someone else are normal on a shared bus
+                Err(ProcessMessageError::DestAddr) => {}
+
+                Err(ProcessMessageError::TaskQueueFull) => {
+                    defmt::warn!("port full, message yeeted");
+                    Counters::bump(&self.counters.rx_dropped);
+                }
+
+                Err(_e) => {
+                    // TODO: print errors, but dont return early.
+                    defmt::error!("message yeeted");
+                    Counters::bump(&self.counters.rx_dropped);
+                }
             }
         }
 
@@ -427,9 +1021,12 @@ impl<const PORTS: usize> Dispatch<PORTS> {
 
         // Did we leave a packet stranded?
         if let Some(msg) = self.shame.dequeue() {
-            if let Err(msg) = self.ioq.to_io.enqueue(msg) {
-                self.shame.enqueue(msg).ok();
-                return;
+            match self.ioq.to_io.enqueue(msg) {
+                Ok(()) => Counters::bump(&self.counters.tx_sent),
+                Err(msg) => {
+                    self.shame.enqueue(msg).ok();
+                    return;
+                }
             }
         }
 
@@ -457,15 +1054,39 @@ impl<const PORTS: usize> Dispatch<PORTS> {
             loop {
                 // check if there is an allocation available FIRST, to avoid
                 // having a packet but no alloc
-                let boxy = if let Some(boxy) = self.alloc.alloc_box() {
+                let boxy = if let Some(boxy) = self.alloc.full().alloc_box() {
                     boxy
                 } else {
                     return;
                 };
 
-                if let Some(msg) = pq.to_dispatch.dequeue() {
-                    if let Err(_e) = self.process_one_outgoing(msg, pq.port.load(SeqCst), boxy) {
-                        return;
+                // Secured ports also need somewhere to put the ciphertext
+                let sealed = match pq.security() {
+                    Some(_) => match self.alloc.full().alloc_box() {
+                        Some(sealed) => Some(sealed),
+                        None => return,
+                    },
+                    None => None,
+                };
+
+                if let Some(msg) = pq.take_outgoing() {
+                    match self.process_one_outgoing(msg, pq, boxy, sealed) {
+                        Ok(()) => {}
+
+                        // A full or missing local port only loses its own
+                        // message, don't hold up everyone else
+                        Err(ProcessMessageError::TaskQueueFull)
+                        | Err(ProcessMessageError::DestPort) => {
+                            defmt::warn!("local message yeeted");
+                            Counters::bump(&self.counters.tx_dropped);
+                        }
+
+                        // Still waiting in the shame queue
+                        Err(ProcessMessageError::IoQueueFull) => return,
+                        Err(_e) => {
+                            Counters::bump(&self.counters.tx_dropped);
+                            return;
+                        }
                     }
                 } else {
                     continue 'port;
@@ -476,75 +1097,326 @@ impl<const PORTS: usize> Dispatch<PORTS> {
 
     fn process_one_outgoing(
         &self,
-        mut lp: LocalPacket,
-        port: u16,
-        mut boxy: BBox,
+        mut lp: LocalPacket<N, SZ>,
+        pq: &PortQueue<N, SZ>,
+        boxy: SlabBox<N, SZ>,
+        sealed: Option<SlabBox<N, SZ>>,
     ) -> Result<(), ProcessMessageError> {
         let own_addr = self.own_addr.load(SeqCst);
+        let port = pq.port.load(SeqCst);
 
         // We shouldn't lie about our own address
         lp.hdr.src.addr = VecAddr::from_local_addr(own_addr);
         lp.hdr.src.port = port;
 
-        let ogp = LineMessage {
-            hdr: LineHeader {
-                src: lp.hdr.src,
-                dst: lp.hdr.dst,
-            },
-            msg: lp.payload,
+        // Messages for this node never need to hit the wire
+        match lp.hdr.dst.addr.get_exact_local_addr() {
+            Some(LOCAL_LOOPBACK_ADDR) => return self.deliver_local(lp),
+            Some(addr) if addr == own_addr => return self.deliver_local(lp),
+            _ => {}
+        }
+
+        let tick = if pq.wire_ticks.load(SeqCst) {
+            lp.sent_tick
+        } else {
+            None
         };
 
-        let len = to_slice_cobs(&ogp, boxy.deref_mut())
-            .map_err(|_| ProcessMessageError::Ser)?
-            .len();
+        let hdr = LineHeader {
+            src: lp.hdr.src,
+            dst: lp.hdr.dst,
+            tick,
+        };
 
-        let arc = boxy.into_arc();
-        let ssa = arc
-            .sub_slice_arc(0, len)
-            .map_err(|_| ProcessMessageError::Arc)?;
+        let msg = match (pq.security(), sealed) {
+            (Some(sec), Some(mut sealed)) => {
+                let len = sec
+                    .seal(&hdr, lp.payload.deref(), sealed.deref_mut())
+                    .map_err(ProcessMessageError::Security)?;
+                let ssa = sealed
+                    .into_arc()
+                    .sub_slice_arc(0, len)
+                    .map_err(|_| ProcessMessageError::Arc)?;
+                ManagedArcSlab::Owned(ssa)
+            }
+            (None, _) => ManagedArcSlab::Borrowed(lp.payload.deref()),
+
+            // The port was made secure after its box was reserved
+            (Some(_), None) => return Err(ProcessMessageError::NoAlloc),
+        };
 
-        let mas = ManagedArcSlab::Owned(ssa);
         let ogs = OutgoingSlab {
-            packet: mas,
+            packet: self.encode_packet(&hdr, msg.deref(), Some(boxy))?,
             receive_ticks_min: lp.response_wait_ticks,
+            sent_tick: lp.sent_tick,
         };
 
         if (port == crate::dom::DISCOVERY_PORT) || (port == crate::dom::TOKEN_PORT) {
-            self.ioq.to_io_hi_prio.enqueue(ogs).ok();
+            match self.ioq.to_io_hi_prio.enqueue(ogs) {
+                Ok(()) => Counters::bump(&self.counters.tx_sent),
+                Err(_) => Counters::bump(&self.counters.tx_dropped),
+            }
             Ok(())
         } else {
-            self.ioq.to_io.enqueue(ogs).map_err(|ssa| {
-                self.shame.enqueue(ssa).ok();
-                ProcessMessageError::IoQueueFull
-            })
+            match self.ioq.to_io.enqueue(ogs) {
+                Ok(()) => {
+                    Counters::bump(&self.counters.tx_sent);
+                    Ok(())
+                }
+                Err(ssa) => {
+                    if self.shame.enqueue(ssa).is_err() {
+                        Counters::bump(&self.counters.tx_dropped);
+                    }
+                    Err(ProcessMessageError::IoQueueFull)
+                }
+            }
+        }
+    }
+
+    /// Deliver a message from one of our own ports straight to another.
+    ///
+    /// Payloads are never sealed on the way, even for secure ports, as
+    /// they never leave this node.
+    fn deliver_local(&self, mut lp: LocalPacket<N, SZ>) -> Result<(), ProcessMessageError> {
+        // Received now, as far as the destination port can tell
+        if let Some(now) = self.ioq.now() {
+            lp.hdr.tick = now;
+        }
+
+        let pq = self
+            .ports
+            .iter()
+            .find(|pq| pq.port.load(SeqCst) == lp.hdr.dst.port)
+            .ok_or(ProcessMessageError::DestPort)?;
+
+        let displaced = pq.enqueue_task(LocalPacket {
+            hdr: lp.hdr,
+            payload: lp.payload,
+            response_wait_ticks: None,
+            sent_tick: lp.sent_tick,
+        })?;
+
+        if displaced {
+            Counters::bump(&self.counters.tx_dropped);
         }
+
+        Counters::bump(&self.counters.local_delivered);
+        Ok(())
+    }
+}
+
+impl<'q, const PORTS: usize, const N: usize, const SZ: usize> ForwardTarget
+    for Dispatch<'q, PORTS, N, SZ>
+{
+    fn forward(&self, hdr: LineHeader, payload: &[u8]) -> Result<(), ProcessMessageError> {
+        // We can't send as the broadcast addr
+        let own_addr = self.own_addr.load(SeqCst);
+        if own_addr == INVALID_OWN_ADDR {
+            return Err(ProcessMessageError::SrcAddr);
+        }
+
+        let src = hdr
+            .src
+            .addr
+            .via(own_addr)
+            .map_err(|_| ProcessMessageError::SrcAddr)?;
+        let hdr = LineHeader {
+            src: AddrPort::from_parts(src, hdr.src.port),
+            dst: hdr.dst,
+            tick: hdr.tick,
+        };
+
+        let ogs = OutgoingSlab {
+            packet: self.encode_packet(&hdr, payload, None)?,
+            receive_ticks_min: None,
+            sent_tick: None,
+        };
+
+        // Failures are counted by the receiving side, as a drop
+        self.ioq
+            .to_io
+            .enqueue(ogs)
+            .map_err(|_| ProcessMessageError::IoQueueFull)?;
+        Counters::bump(&self.counters.forwarded);
+        Ok(())
     }
 }
 
-pub struct DispatchSocket<'a> {
+fn ticks<R: RollingTimer<Tick = u32> + Default>() -> u32 {
+    R::default().get_ticks()
+}
+
+/// Serialize the start of a frame, up to its payload: `hdr`, and the
+/// payload's length. Returns the used length of `prefix`.
+fn frame_prefix(
+    hdr: &LineHeader,
+    payload_len: usize,
+    prefix: &mut [u8; MAX_FRAME_PREFIX],
+) -> Result<usize, ProcessMessageError> {
+    let mut used = to_slice(hdr, prefix)
+        .map_err(|_| ProcessMessageError::Ser)?
+        .len();
+
+    // The payload's length, as a postcard varint
+    let mut len = payload_len;
+    loop {
+        let byte = prefix.get_mut(used).ok_or(ProcessMessageError::Ser)?;
+        used += 1;
+        if len < 0x80 {
+            *byte = len as u8;
+            break;
+        }
+        *byte = (len as u8) | 0x80;
+        len >>= 7;
+    }
+
+    Ok(used)
+}
+
+/// The most bytes `encode_frame()` may use for `hdr` and a payload of
+/// `payload_len`
+fn max_encoded_frame(hdr: &LineHeader, payload_len: usize) -> Result<usize, ProcessMessageError> {
+    let mut prefix = [0u8; MAX_FRAME_PREFIX];
+    let trailer = if hdr.tick.is_some() {
+        MAX_TICK_TRAILER
+    } else {
+        0
+    };
+    let raw = frame_prefix(hdr, payload_len, &mut prefix)? + payload_len + trailer;
+
+    // COBS overhead, and the terminator
+    Ok(raw + raw / 254 + 2)
+}
+
+/// COBS encode a `LineMessage` made of `hdr` and `payload` into `out`,
+/// including the tick trailer and the terminator, and return the used length.
+///
+/// This produces the same bytes as serializing a `LineMessage`, without
+/// needing the payload to be in a slab of its own first.
+fn encode_frame(
+    hdr: &LineHeader,
+    payload: &[u8],
+    out: &mut [u8],
+) -> Result<usize, ProcessMessageError> {
+    let mut prefix = [0u8; MAX_FRAME_PREFIX];
+    let used = frame_prefix(hdr, payload.len(), &mut prefix)?;
+
+    let mut enc = CobsEncoder::new(out);
+    enc.push(&prefix[..used])
+        .map_err(|_| ProcessMessageError::Ser)?;
+    enc.push(payload).map_err(|_| ProcessMessageError::Ser)?;
+    if let Some(tick) = hdr.tick.as_ref() {
+        let mut trailer = [0u8; MAX_TICK_TRAILER];
+        let trailer = to_slice(tick, &mut trailer).map_err(|_| ProcessMessageError::Ser)?;
+        enc.push(trailer).map_err(|_| ProcessMessageError::Ser)?;
+    }
+    let len = enc.finalize().map_err(|_| ProcessMessageError::Ser)?;
+
+    *out.get_mut(len).ok_or(ProcessMessageError::Ser)? = 0;
+    Ok(len + 1)
+}
+
+pub struct DispatchSocket<'a, const N: usize, const SZ: usize> {
     port: NonZeroU16,
-    to_task: &'a MpMcQueue<LocalPacket, TASK_QUEUE_DEPTH>,
-    to_dispatch: &'a MpMcQueue<LocalPacket, TASK_QUEUE_DEPTH>,
+    to_task: &'a MpMcQueue<LocalPacket<N, SZ>, TASK_QUEUE_DEPTH>,
+    to_dispatch: &'a MpMcQueue<LocalPacket<N, SZ>, TASK_QUEUE_DEPTH>,
+    room: &'a Waiters,
+    drop_policy: &'a AtomicU8,
+    wire_ticks: &'a AtomicBool,
     send_auth: Option<&'a IoAuth>,
+    alloc: SlabHandle<N, SZ>,
+    ioq: &'a IoQueue<N, SZ>,
 }
 
-impl<'a> DispatchSocket<'a> {
-    pub fn try_send(&self, pkt: LocalPacket) -> Result<(), LocalPacket> {
-        self.to_dispatch.enqueue(pkt)
+impl<'a, const N: usize, const SZ: usize> DispatchSocket<'a, N, SZ> {
+    pub fn try_send(&self, mut pkt: LocalPacket<N, SZ>) -> Result<(), SendError<N, SZ>> {
+        pkt.sent_tick = self.ioq.now();
+        self.to_dispatch.enqueue(pkt).map_err(SendError::QueueFull)
     }
 
-    pub fn try_send_authd(&self, pkt: LocalPacket) -> Result<(), LocalPacket> {
+    pub fn try_send_authd(&self, pkt: LocalPacket<N, SZ>) -> Result<(), SendError<N, SZ>> {
         match self.send_auth {
             Some(auth) => {
                 self.try_send(pkt)?;
                 auth.enable_one_send();
                 Ok(())
             }
-            None => Err(pkt),
+            None => Err(SendError::NotAuthorized(pkt)),
+        }
+    }
+
+    /// Serialize `msg` into a new packet, and send it to `dst`. The source
+    /// is filled in by the `Dispatch`.
+    pub fn try_send_msg<T: Serialize>(
+        &self,
+        msg: T,
+        dst: AddrPort,
+    ) -> Result<(), SendError<N, SZ>> {
+        let pkt = self.packet(msg, dst)?;
+        self.try_send(pkt)
+    }
+
+    /// Send a packet, waiting for room in the outgoing queue
+    ///
+    /// Waiting tasks are woken when the `Dispatch` takes a packet from the
+    /// queue. Up to `MAX_ALLOC_WAITERS` tasks may wait on each port, any
+    /// more are polled continuously instead.
+    pub async fn send(&self, mut pkt: LocalPacket<N, SZ>) {
+        pkt.sent_tick = self.ioq.now();
+        WaitForRoom {
+            socket: self,
+            pkt: Some(pkt),
+            slot: None,
         }
+        .await
+    }
+
+    /// Serialize `msg` into a new packet, and send it to `dst`, waiting for
+    /// an allocation and for room in the outgoing queue. This only fails
+    /// if the message can never fit in a slab.
+    pub async fn send_msg<T: Serialize>(
+        &self,
+        msg: T,
+        dst: AddrPort,
+    ) -> Result<(), SendError<N, SZ>> {
+        let buf = self.alloc.alloc_box_async().await;
+        let pkt = LocalPacket::try_from_parts_with_box(msg, self.src(), dst, None, buf)?;
+
+        self.send(pkt).await;
+        Ok(())
+    }
+
+    fn packet<T: Serialize>(
+        &self,
+        msg: T,
+        dst: AddrPort,
+    ) -> Result<LocalPacket<N, SZ>, SendError<N, SZ>> {
+        LocalPacket::try_from_parts_with_alloc(msg, self.src(), dst, None, &self.alloc)
+    }
+
+    fn src(&self) -> AddrPort {
+        // The address is a placeholder, the `Dispatch` knows better
+        AddrPort::from_parts(VecAddr::local_broadcast_addr(), self.port.get())
+    }
+
+    /// Choose what happens to incoming messages while our queue is full.
+    /// Ports start out with `DropPolicy::DropNewest`.
+    pub fn set_drop_policy(&self, policy: DropPolicy) {
+        self.drop_policy.store(policy as u8, SeqCst);
+    }
+
+    pub fn drop_policy(&self) -> DropPolicy {
+        DropPolicy::from_u8(self.drop_policy.load(SeqCst))
+    }
+
+    /// Include the send time of our messages on the wire, so receivers can
+    /// measure their latency. This costs up to five bytes per message, and
+    /// needs a timer set with `IoQueue::set_timer()`.
+    pub fn set_wire_timestamps(&self, enabled: bool) {
+        self.wire_ticks.store(enabled, SeqCst);
     }
 
-    pub fn try_recv(&self) -> Option<LocalPacket> {
+    pub fn try_recv(&self) -> Option<LocalPacket<N, SZ>> {
         self.to_task.dequeue()
     }
 
@@ -574,3 +1446,162 @@ impl<'a> DispatchSocket<'a> {
         self.port
     }
 }
+
+/// A packet waiting for room in a socket's outgoing queue
+struct WaitForRoom<'s, 'a, const N: usize, const SZ: usize> {
+    socket: &'s DispatchSocket<'a, N, SZ>,
+    pkt: Option<LocalPacket<N, SZ>>,
+    slot: Option<usize>,
+}
+
+impl<'s, 'a, const N: usize, const SZ: usize> Future for WaitForRoom<'s, 'a, N, SZ> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let pkt = match self.pkt.take() {
+            Some(pkt) => pkt,
+            None => return Poll::Ready(()),
+        };
+
+        let pkt = match self.socket.to_dispatch.enqueue(pkt) {
+            Ok(()) => return Poll::Ready(()),
+            Err(pkt) => pkt,
+        };
+
+        if self.slot.is_none() {
+            self.slot = self.socket.room.claim();
+        }
+
+        match self.slot {
+            Some(idx) => self.socket.room.register(idx, cx.waker()),
+            None => cx.waker().wake_by_ref(),
+        }
+
+        // Try again, in case room was made before we were registered
+        match self.socket.to_dispatch.enqueue(pkt) {
+            Ok(()) => Poll::Ready(()),
+            Err(pkt) => {
+                self.pkt = Some(pkt);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'s, 'a, const N: usize, const SZ: usize> Drop for WaitForRoom<'s, 'a, N, SZ> {
+    fn drop(&mut self) {
+        if let Some(idx) = self.slot.take() {
+            self.socket.room.release(idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use byte_slab::{BSlab, SizedSlab};
+
+    #[test]
+    fn static_addr_kept() {
+        static SLAB: BSlab<1, 64> = BSlab::new();
+        let ioq: IoQueue<1, 64> = IoQueue::new();
+
+        let dispatch: Dispatch<'_, 1, 1, 64> = Dispatch::new(&ioq, SLAB.handle());
+        dispatch.set_addr(5);
+        assert!(dispatch.release_addr());
+        assert_eq!(dispatch.get_addr(), None);
+
+        // Asking a static sub to rediscover doesn't leave it without an address
+        let dispatch: Dispatch<'_, 1, 1, 64> = Dispatch::new(&ioq, SLAB.handle());
+        dispatch.set_static_addr(7);
+        assert!(!dispatch.release_addr());
+        assert_eq!(dispatch.get_addr(), Some(7));
+    }
+
+    #[test]
+    fn sized_frames() {
+        static SLAB: BSlab<4, 256> = BSlab::new();
+        static SMALL: BSlab<2, 32> = BSlab::new();
+        static MEDIUM: BSlab<2, 64> = BSlab::new();
+        static LARGE: BSlab<2, 128> = BSlab::new();
+        static SIZED: SizedSlab<2, 32, 2, 64, 2, 128> = SizedSlab::new(&SMALL, &MEDIUM, &LARGE);
+        SLAB.init().unwrap();
+        SMALL.init().unwrap();
+        MEDIUM.init().unwrap();
+        LARGE.init().unwrap();
+
+        let ioq: IoQueue<4, 256> = IoQueue::new();
+        let alloc = PacketAlloc::with_sized(SLAB.handle(), &SIZED);
+        let dispatch: Dispatch<'_, 1, 4, 256> = Dispatch::with_packet_alloc(&ioq, alloc.clone());
+        let src = AddrPort::from_parts(VecAddr::from_local_addr(1), 100);
+        let dst = AddrPort::from_parts(VecAddr::from_local_addr(2), 100);
+        let hdr = LineHeader {
+            src: src.clone(),
+            dst: dst.clone(),
+            tick: Some(1234),
+        };
+
+        // A short message goes out in a short frame
+        let short = dispatch.encode_packet(&hdr, &[1; 4], None).ok().unwrap();
+        assert!(matches!(short, PacketBuf::Sized(_)));
+        assert_eq!(short.iter().position(|b| *b == 0), Some(short.len() - 1));
+
+        let mut full = [0u8; 256];
+        let len = encode_frame(&hdr, &[1; 4], &mut full).ok().unwrap();
+        assert_eq!(&full[..len], short.deref());
+
+        // Nothing smaller than the full slab fits this one
+        let long = dispatch.encode_packet(&hdr, &[1; 150], None).ok().unwrap();
+        assert!(matches!(long, PacketBuf::Slab(_)));
+        assert!(long.len() > 150);
+
+        // Packets made by tasks
+        let pkt =
+            LocalPacket::from_parts_with_packet_alloc(1234u32, src, dst, None, &alloc).unwrap();
+        assert!(matches!(pkt.payload, PacketBuf::Sized(_)));
+        assert_eq!(pkt.payload(), &1234u32.to_le_bytes());
+    }
+
+    #[test]
+    fn send_waits_for_room() {
+        use std::{
+            sync::{atomic::AtomicBool, Arc},
+            task::{Wake, Waker},
+        };
+
+        struct Flag(AtomicBool);
+
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, SeqCst);
+            }
+        }
+
+        static SLAB: BSlab<8, 64> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let ioq: IoQueue<8, 64> = IoQueue::new();
+        let dispatch: Dispatch<'_, 1, 8, 64> = Dispatch::new(&ioq, SLAB.handle());
+        let socket = dispatch.register_port(100).unwrap();
+        let dst = AddrPort::from_parts(VecAddr::from_local_addr(2), 100);
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // Fill the outgoing queue
+        for i in 0..TASK_QUEUE_DEPTH {
+            assert!(socket.try_send_msg(i as u32, dst.clone()).is_ok());
+        }
+
+        let pkt = socket.packet(1234u32, dst).ok().unwrap();
+        let mut fut = Box::pin(socket.send(pkt));
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        assert!(!flag.0.load(SeqCst));
+
+        // Woken once the `Dispatch` takes a packet
+        assert!(dispatch.ports[0].take_outgoing().is_some());
+        assert!(flag.0.load(SeqCst));
+        assert!(fut.as_mut().poll(&mut cx).is_ready());
+    }
+}
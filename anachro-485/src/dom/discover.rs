@@ -1,55 +1,59 @@
 use crate::{
-    async_sleep_millis,
-    dispatch::{DispatchSocket, LocalPacket},
+    async_sleep_micros,
+    dispatch::{Dispatch, DispatchSocket, LocalPacket},
     dom::{AddrTable32, DISCOVERY_PORT},
-    icd::{AddrPort, DomDiscoveryPayload, SubDiscoveryPayload, VecAddr, SLAB_SIZE, TOTAL_SLABS},
+    icd::{AddrPort, DomDiscoveryPayload, SubDiscoveryPayload, VecAddr},
     receive_timeout_micros,
-    timing::{
-        DOM_BROADCAST_MAX_WAIT_US, DOM_BROADCAST_MIN_WAIT_US, DOM_PING_MAX_WAIT_US,
-        DOM_PING_MIN_WAIT_US,
-    },
+    timing::BusTiming,
 };
 
 use core::{iter::FromIterator, marker::PhantomData, ops::Deref};
 
-use byte_slab::BSlab;
+use byte_slab::SlabHandle;
 use groundhog::RollingTimer;
 use heapless::{FnvIndexMap, FnvIndexSet, Vec};
 use rand::Rng;
 
-pub struct Discovery<R, A>
+pub struct Discovery<'a, R, A, const PORTS: usize, const N: usize, const SZ: usize>
 where
     R: RollingTimer<Tick = u32> + Default,
     A: Rng,
 {
     _timer: PhantomData<R>,
-    socket: DispatchSocket<'static>,
+    dispatch: &'a Dispatch<'a, PORTS, N, SZ>,
+    socket: DispatchSocket<'a, N, SZ>,
     table: &'static AddrTable32,
     rand: A,
     boost_mode: bool,
-    alloc: &'static BSlab<TOTAL_SLABS, SLAB_SIZE>,
+    alloc: SlabHandle<N, SZ>,
     last_disc: Option<u32>,
+    timing: BusTiming,
 }
 
-impl<R, A> Discovery<R, A>
+impl<'a, R, A, const PORTS: usize, const N: usize, const SZ: usize>
+    Discovery<'a, R, A, PORTS, N, SZ>
 where
     R: RollingTimer<Tick = u32> + Default,
     A: Rng,
 {
     pub fn new(
-        socket: DispatchSocket<'static>,
+        dispatch: &'a Dispatch<'a, PORTS, N, SZ>,
+        socket: DispatchSocket<'a, N, SZ>,
         rand: A,
-        alloc: &'static BSlab<TOTAL_SLABS, SLAB_SIZE>,
+        alloc: SlabHandle<N, SZ>,
         table: &'static AddrTable32,
+        timing: BusTiming,
     ) -> Self {
         Self {
             _timer: PhantomData,
+            dispatch,
             socket,
             rand,
             table,
             boost_mode: true,
             alloc,
             last_disc: None,
+            timing,
         }
     }
 
@@ -59,26 +63,25 @@ where
 
         loop {
             // Boost until we haven't heard from a new device in the
-            // last three seconds (once after boot)
+            // last boost window (once after boot)
             if let Some(ld) = self.last_disc {
-                if self.boost_mode && timer.millis_since(ld) >= 3_000 {
+                if self.boost_mode
+                    && timer.micros_since(ld) >= self.timing.dom_disco_boost_window_us
+                {
                     self.boost_mode = false;
                     self.last_disc = None;
                 }
             }
 
-            if !self.boost_mode {
-                async_sleep_millis::<R>(timer.get_ticks(), 1000u32).await;
-            } else {
-                async_sleep_millis::<R>(timer.get_ticks(), 100u32).await;
-            }
+            self.sleep_step().await;
 
             let ret = self.poll_inner().await;
 
             match ret {
                 Ok(0) => {
                     if !self.boost_mode {
-                        async_sleep_millis::<R>(timer.get_ticks(), 1000u32).await;
+                        async_sleep_micros::<R>(timer.get_ticks(), self.timing.dom_disco_step_us)
+                            .await;
                     }
                 }
                 Ok(_) => {
@@ -91,6 +94,16 @@ where
         }
     }
 
+    /// Wait between discovery steps, shorter in boost mode
+    async fn sleep_step(&self) {
+        let step_us = if self.boost_mode {
+            self.timing.dom_disco_boost_step_us
+        } else {
+            self.timing.dom_disco_step_us
+        };
+        async_sleep_micros::<R>(R::default().get_ticks(), step_us).await;
+    }
+
     pub async fn poll_inner(&mut self) -> Result<usize, ()> {
         let avail_addrs = self.table.get_available_addrs();
         let timer = R::default();
@@ -109,11 +122,7 @@ where
         self.last_disc = Some(timer.get_ticks());
         defmt::info!("READIES: {:?}", readies.deref());
 
-        if !self.boost_mode {
-            async_sleep_millis::<R>(timer.get_ticks(), 1000u32).await;
-        } else {
-            async_sleep_millis::<R>(timer.get_ticks(), 100u32).await;
-        }
+        self.sleep_step().await;
 
         let steadies = self.ping_readies(&readies).await?;
         defmt::info!("STEADIES: {:?}", steadies.deref());
@@ -121,19 +130,17 @@ where
             return Ok(0);
         }
 
-        if !self.boost_mode {
-            async_sleep_millis::<R>(timer.get_ticks(), 1000u32).await;
-        } else {
-            async_sleep_millis::<R>(timer.get_ticks(), 100u32).await;
-        }
+        self.sleep_step().await;
 
         let gos = self.ping_readies(&steadies).await?;
         defmt::info!("GOs: {:?}", gos.deref());
 
-        let table = &mut self.table;
-        gos.iter()
-            .try_for_each(|g| table.commit_reserved_addr(*g))
-            .unwrap();
+        for g in gos.iter() {
+            self.table.commit_reserved_addr(*g).unwrap();
+
+            // Whoever had this address before, this is someone new
+            self.dispatch.reset_peer(*g);
+        }
 
         Ok(gos.len())
     }
@@ -147,16 +154,16 @@ where
             let mut got = false;
             let payload = DomDiscoveryPayload::PingReq {
                 random: dom_random,
-                min_wait_us: DOM_PING_MIN_WAIT_US,
-                max_wait_us: DOM_PING_MAX_WAIT_US,
+                min_wait_us: self.timing.dom_ping_min_wait_us,
+                max_wait_us: self.timing.dom_ping_max_wait_us,
             };
 
             let msg = LocalPacket::from_parts_with_alloc(
                 payload,
                 AddrPort::from_parts(VecAddr::local_dom_addr(), DISCOVERY_PORT),
                 AddrPort::from_parts(VecAddr::from_local_addr(*ready), DISCOVERY_PORT),
-                Some(DOM_PING_MAX_WAIT_US),
-                self.alloc,
+                Some(self.timing.dom_ping_max_wait_us),
+                &self.alloc,
             )
             .ok_or(())?;
 
@@ -164,10 +171,10 @@ where
             let start = timer.get_ticks();
 
             'inner: loop {
-                let maybe_msg = receive_timeout_micros::<R, SubDiscoveryPayload>(
+                let maybe_msg = receive_timeout_micros::<R, SubDiscoveryPayload, N, SZ>(
                     &mut self.socket,
                     start,
-                    DOM_PING_MAX_WAIT_US,
+                    self.timing.dom_ping_max_wait_us,
                 )
                 .await;
 
@@ -203,8 +210,8 @@ where
 
         let payload = DomDiscoveryPayload::DiscoverInitial {
             random: dom_random,
-            min_wait_us: DOM_BROADCAST_MIN_WAIT_US,
-            max_wait_us: DOM_BROADCAST_MAX_WAIT_US,
+            min_wait_us: self.timing.dom_broadcast_min_wait_us,
+            max_wait_us: self.timing.dom_broadcast_max_wait_us,
             offers: Vec::from_iter(avail_addrs.iter().cloned()),
         };
 
@@ -212,8 +219,8 @@ where
             payload,
             AddrPort::from_parts(VecAddr::local_dom_addr(), DISCOVERY_PORT),
             AddrPort::from_parts(VecAddr::local_broadcast_addr(), DISCOVERY_PORT),
-            Some(DOM_BROADCAST_MAX_WAIT_US),
-            self.alloc,
+            Some(self.timing.dom_broadcast_max_wait_us),
+            &self.alloc,
         )
         .ok_or(())?;
         defmt::info!("BROADCAST!");
@@ -225,10 +232,10 @@ where
 
         // Collect until timeout, or max messages received
         while !resps.is_full() {
-            let maybe_msg = receive_timeout_micros::<R, SubDiscoveryPayload>(
+            let maybe_msg = receive_timeout_micros::<R, SubDiscoveryPayload, N, SZ>(
                 &mut self.socket,
                 start,
-                DOM_BROADCAST_MAX_WAIT_US,
+                self.timing.dom_broadcast_max_wait_us,
             )
             .await;
 
@@ -306,7 +313,7 @@ where
                     msg.hdr.src,
                     msg.hdr.dst,
                     None,
-                    self.alloc,
+                    &self.alloc,
                 )
                 .ok_or(())?;
 
@@ -0,0 +1,144 @@
+//! Collection of log records forwarded by subs
+//!
+//! The `LogCollector` task listens on the `LOG_PORT`, unpacks the batches of
+//! `LogRecord`s sent by each sub, and places them in a `LogQueue`, tagged
+//! with the sending address and the time they were received.
+
+use core::ops::Deref;
+
+use byte_slab::ManagedArcStr;
+use cassette::yield_now;
+use heapless::mpmc::MpMcQueue;
+use postcard::take_from_bytes;
+
+use crate::{
+    dispatch::DispatchSocket,
+    icd::{LogLevel, LogRecord},
+};
+
+pub const LOG_ENTRY_DEPTH: usize = 32;
+
+/// A log record received from a sub
+#[derive(Debug)]
+pub struct LogEntry<const N: usize, const SZ: usize> {
+    /// The local address of the sub that sent this record
+    pub addr: u8,
+
+    /// The sub's tick when the record was logged
+    pub sub_tick: u32,
+
+    /// The dom's tick when the record was received
+    pub dom_tick: u32,
+
+    pub level: LogLevel,
+    pub text: ManagedArcStr<'static, N, SZ>,
+}
+
+/// Log records received from all subs, in order of arrival
+///
+/// When full, the oldest records are discarded to make room.
+pub struct LogQueue<const N: usize, const SZ: usize> {
+    entries: MpMcQueue<LogEntry<N, SZ>, LOG_ENTRY_DEPTH>,
+}
+
+impl<const N: usize, const SZ: usize> LogQueue<N, SZ> {
+    pub const fn new() -> Self {
+        Self {
+            entries: MpMcQueue::new(),
+        }
+    }
+
+    pub fn pop(&self) -> Option<LogEntry<N, SZ>> {
+        self.entries.dequeue()
+    }
+
+    fn push(&self, mut entry: LogEntry<N, SZ>) {
+        while let Err(e) = self.entries.enqueue(entry) {
+            entry = e;
+            let _ = self.entries.dequeue();
+        }
+    }
+}
+
+impl<const N: usize, const SZ: usize> Default for LogQueue<N, SZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Why a batch of log records could not be collected
+#[derive(Debug, PartialEq)]
+pub enum LogBatchError {
+    /// The batch was not sent from a single local address
+    SrcAddr,
+    /// The batch is not held in a slab, so its records can't be kept
+    NoSlab,
+    /// A record could not be deserialized
+    Deser,
+    /// A record's text could not be rerooted into the batch's slab
+    ReRoot,
+}
+
+pub struct LogCollector<const N: usize, const SZ: usize> {
+    socket: DispatchSocket<'static, N, SZ>,
+    queue: &'static LogQueue<N, SZ>,
+}
+
+impl<const N: usize, const SZ: usize> LogCollector<N, SZ> {
+    /// Create a new collector. `socket` should be registered on the `LOG_PORT`.
+    pub fn new(socket: DispatchSocket<'static, N, SZ>, queue: &'static LogQueue<N, SZ>) -> Self {
+        Self { socket, queue }
+    }
+
+    pub async fn poll(&mut self) -> ! {
+        loop {
+            if self.poll_inner().is_err() {
+                defmt::warn!("Bad log batch!");
+            }
+            yield_now().await;
+        }
+    }
+
+    pub fn poll_inner(&mut self) -> Result<(), LogBatchError> {
+        while let Some(msg) = self.socket.try_recv() {
+            let addr = msg
+                .hdr
+                .src
+                .addr
+                .get_exact_local_addr()
+                .ok_or(LogBatchError::SrcAddr)?;
+            let arc = msg
+                .payload_slab()
+                .and_then(|mas| mas.summon_arc())
+                .ok_or(LogBatchError::NoSlab)?;
+            let key = arc.rerooter_key();
+
+            let mut remain = msg.payload.deref();
+            while !remain.is_empty() {
+                let (record, rest) = take_from_bytes::<LogRecord<N, SZ>>(remain)
+                    .map_err(|_| LogBatchError::Deser)?;
+                remain = rest;
+
+                // Empty strings can't be rerooted, but don't need to be
+                let text = if record.text.is_empty() {
+                    ManagedArcStr::Borrowed("")
+                } else {
+                    record
+                        .text
+                        .reroot_with_key(&key)
+                        .ok_or(LogBatchError::ReRoot)?
+                };
+
+                self.queue.push(LogEntry {
+                    addr,
+                    sub_tick: record.tick,
+                    dom_tick: msg.hdr.tick,
+                    level: record.level,
+                    text,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+//! Remote control of subs
+//!
+//! The `MgmtClient` sends commands to the `MgmtServer` of a single sub, and
+//! waits for its answer.
+
+use core::marker::PhantomData;
+
+use byte_slab::SlabHandle;
+use groundhog::RollingTimer;
+
+use crate::{
+    dispatch::{DispatchSocket, LocalPacket},
+    icd::{AddrPort, DispatchStats, DomMgmtPayload, NodeInfo, SubMgmtPayload, VecAddr},
+    receive_timeout_micros,
+};
+
+use super::MANAGEMENT_PORT;
+
+/// How long to wait for each response from the sub. Responses are only
+/// sent when the sub is granted a token, so this is fairly generous.
+const RESPONSE_TIMEOUT_US: u32 = 500_000;
+
+/// How many times a command is re-sent without a response before giving up
+const MAX_RETRIES: usize = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum MgmtError {
+    /// The sub did not respond
+    Timeout,
+    /// A message could not be allocated or sent
+    Send,
+    /// The sub answered with the wrong kind of response
+    Unexpected,
+}
+
+pub struct MgmtClient<R, const N: usize, const SZ: usize>
+where
+    R: RollingTimer<Tick = u32> + Default,
+{
+    _timer: PhantomData<R>,
+    socket: DispatchSocket<'static, N, SZ>,
+    alloc: SlabHandle<N, SZ>,
+}
+
+impl<R, const N: usize, const SZ: usize> MgmtClient<R, N, SZ>
+where
+    R: RollingTimer<Tick = u32> + Default,
+{
+    /// Create a new client. `socket` should be registered on the `MANAGEMENT_PORT`.
+    pub fn new(socket: DispatchSocket<'static, N, SZ>, alloc: SlabHandle<N, SZ>) -> Self {
+        Self {
+            _timer: PhantomData,
+            socket,
+            alloc,
+        }
+    }
+
+    /// Reboot the sub at local address `addr`
+    pub async fn reset(&mut self, addr: u8) -> Result<(), MgmtError> {
+        self.expect_ack(addr, DomMgmtPayload::Reset).await
+    }
+
+    /// Ask the sub at local address `addr` to make itself visible to a human
+    pub async fn identify(&mut self, addr: u8, duration_ms: u32) -> Result<(), MgmtError> {
+        self.expect_ack(addr, DomMgmtPayload::Identify { duration_ms })
+            .await
+    }
+
+    /// Ask the sub at local address `addr` to drop its address, and take
+    /// part in discovery again. Subs with a static address keep it.
+    pub async fn rediscover(&mut self, addr: u8) -> Result<(), MgmtError> {
+        self.expect_ack(addr, DomMgmtPayload::Rediscover).await
+    }
+
+    pub async fn stats(&mut self, addr: u8) -> Result<DispatchStats, MgmtError> {
+        match self.exchange(addr, DomMgmtPayload::GetStats).await? {
+            SubMgmtPayload::Stats(stats) => Ok(stats),
+            _ => Err(MgmtError::Unexpected),
+        }
+    }
+
+    pub async fn info(&mut self, addr: u8) -> Result<NodeInfo, MgmtError> {
+        match self.exchange(addr, DomMgmtPayload::GetInfo).await? {
+            SubMgmtPayload::Info(info) => Ok(info),
+            _ => Err(MgmtError::Unexpected),
+        }
+    }
+
+    async fn expect_ack(&mut self, addr: u8, cmd: DomMgmtPayload) -> Result<(), MgmtError> {
+        match self.exchange(addr, cmd).await? {
+            SubMgmtPayload::Ack => Ok(()),
+            _ => Err(MgmtError::Unexpected),
+        }
+    }
+
+    async fn exchange(
+        &mut self,
+        addr: u8,
+        cmd: DomMgmtPayload,
+    ) -> Result<SubMgmtPayload, MgmtError> {
+        // Discard any late responses to a previous exchange
+        while self.socket.try_recv().is_some() {}
+
+        for _ in 0..MAX_RETRIES {
+            let pkt = LocalPacket::from_parts_with_alloc(
+                &cmd,
+                AddrPort::from_parts(VecAddr::local_dom_addr(), MANAGEMENT_PORT),
+                AddrPort::from_parts(VecAddr::from_local_addr(addr), MANAGEMENT_PORT),
+                None,
+                &self.alloc,
+            )
+            .ok_or(MgmtError::Send)?;
+            self.socket.try_send(pkt).map_err(|_| MgmtError::Send)?;
+
+            let timer = R::default();
+            let start = timer.get_ticks();
+
+            while let Some(resp) = receive_timeout_micros::<R, SubMgmtPayload, N, SZ>(
+                &mut self.socket,
+                start,
+                RESPONSE_TIMEOUT_US,
+            )
+            .await
+            {
+                // Ignore stale responses from anyone else
+                if resp.hdr.src.addr.get_exact_local_addr() == Some(addr) {
+                    return Ok(resp.body);
+                }
+            }
+        }
+
+        Err(MgmtError::Timeout)
+    }
+}
@@ -1,22 +1,50 @@
-use core::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use crate::atomic::{AtomicU32, Ordering::SeqCst};
 use heapless::Vec;
 
 pub mod discover;
+pub mod log;
+pub mod mgmt;
+pub mod ota;
 pub mod token;
+pub mod uplink;
 
 pub struct AddrTable32 {
     active: AtomicU32,
+
+    /// Addresses used by subs with a fixed address. These are never
+    /// offered by discovery, and are always considered active.
+    fixed: u32,
 }
 
 impl AddrTable32 {
     pub const fn new() -> Self {
+        Self::with_static_range(0, 0)
+    }
+
+    /// A table where the addresses `first..=last` are reserved for subs
+    /// with a static address. Addresses outside of `1..=32` are ignored.
+    pub const fn with_static_range(first: u8, last: u8) -> Self {
+        let mut fixed = 0;
+        let mut addr = first;
+        while addr <= last && addr <= 32 {
+            if addr != 0 {
+                fixed |= 1 << (addr - 1);
+            }
+            addr += 1;
+        }
+
         Self {
             active: AtomicU32::new(0x0000_0000),
+            fixed,
         }
     }
 
+    pub fn is_static(&self, addr: u8) -> bool {
+        (1..=32).contains(&addr) && (self.fixed & (1 << (addr - 1)) != 0)
+    }
+
     pub fn get_active_addrs(&self) -> Vec<u8, 32> {
-        let mut copy = self.active.load(SeqCst);
+        let mut copy = self.active.load(SeqCst) | self.fixed;
         let mut ret = Vec::new();
 
         for i in 1..=32 {
@@ -30,7 +58,7 @@ impl AddrTable32 {
     }
 
     pub fn get_available_addrs(&self) -> Vec<u8, 32> {
-        let mut copy = !self.active.load(SeqCst);
+        let mut copy = !(self.active.load(SeqCst) | self.fixed);
         let mut ret = Vec::new();
 
         for i in 1..=32 {
@@ -44,7 +72,7 @@ impl AddrTable32 {
     }
 
     pub fn commit_reserved_addr(&self, addr: u8) -> Result<(), ()> {
-        if addr > 32 || addr == 0 {
+        if addr > 32 || addr == 0 || self.is_static(addr) {
             return Err(());
         }
 
@@ -58,7 +86,7 @@ impl AddrTable32 {
     }
 
     pub fn release_active_addr(&self, addr: u8) -> Result<(), ()> {
-        if addr > 32 || addr == 0 {
+        if addr > 32 || addr == 0 || self.is_static(addr) {
             return Err(());
         }
 
@@ -75,6 +103,9 @@ impl AddrTable32 {
 pub const NUM_PORTS: usize = 8;
 pub const DISCOVERY_PORT: u16 = 10;
 pub const TOKEN_PORT: u16 = 20;
+pub const LOG_PORT: u16 = 30;
+pub const OTA_PORT: u16 = 40;
+pub const MANAGEMENT_PORT: u16 = 50;
 
 #[cfg(TODO)]
 mod todo {
@@ -132,3 +163,35 @@ mod todo {
         };
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn static_range() {
+        let table = AddrTable32::with_static_range(30, 40);
+        assert!(!table.is_static(0));
+        assert!(!table.is_static(29));
+        assert!((30..=32).all(|addr| table.is_static(addr)));
+        assert!(!table.is_static(33));
+        assert!(!table.is_static(255));
+
+        // Static addresses are always active, and never offered
+        assert_eq!(table.get_active_addrs().as_slice(), &[30, 31, 32]);
+        assert_eq!(table.get_available_addrs().len(), 29);
+        assert!(!table.get_available_addrs().contains(&30));
+
+        // ...or handed out by discovery
+        assert!(table.commit_reserved_addr(31).is_err());
+        assert!(table.release_active_addr(31).is_err());
+        assert!(table.commit_reserved_addr(1).is_ok());
+        assert_eq!(table.get_active_addrs().as_slice(), &[1, 30, 31, 32]);
+
+        // The dom's own address is never static
+        let table = AddrTable32::with_static_range(0, 2);
+        assert_eq!(table.get_active_addrs().as_slice(), &[1, 2]);
+        assert!(!AddrTable32::new().is_static(1));
+        assert!(AddrTable32::new().get_active_addrs().is_empty());
+    }
+}
@@ -0,0 +1,280 @@
+//! Sending firmware updates over the bus
+//!
+//! The `OtaServer` pushes an image, as produced by `boot-chonker`, to one
+//! or more subs.
+//!
+//! When updating a single sub, the sub drives the transfer by requesting
+//! each chunk in turn, so lost or corrupted chunks are simply requested again.
+//!
+//! When updating many subs, each chunk is broadcast once to all of them.
+//! Each sub is then asked which chunks it is missing, and only those chunks
+//! are broadcast again, until every sub has the whole image.
+
+use core::marker::PhantomData;
+
+use byte_slab::{ManagedArcSlab, SlabHandle};
+use groundhog::RollingTimer;
+use heapless::Vec;
+
+use crate::{
+    dispatch::{DispatchSocket, LocalPacket},
+    icd::{
+        AddrPort, ChunkMap, DomOtaPayload, OtaFailure, OtaImageInfo, SubOtaPayload, VecAddr,
+        OTA_CHUNK_SIZE, OTA_MAX_CHUNKS, OTA_MISSING_WINDOW, OTA_TAG_SIZE,
+    },
+    receive_timeout_micros,
+};
+
+use super::OTA_PORT;
+
+/// How long to wait for each response from the sub. Responses are only
+/// sent when the sub is granted a token, so this is fairly generous.
+const RESPONSE_TIMEOUT_US: u32 = 500_000;
+
+/// How many times a message is re-sent without a response before giving up
+const MAX_RETRIES: usize = 10;
+
+/// How many rounds of broadcasting missing chunks are made before giving up
+const MAX_MULTICAST_ROUNDS: usize = 16;
+
+/// The most subs that may be updated at once
+pub const MAX_OTA_TARGETS: usize = 32;
+
+/// The outcome of a multicast update, for each targeted sub address
+pub type OtaResults = Vec<(u8, Result<(), OtaError>), MAX_OTA_TARGETS>;
+
+/// A source of a firmware image, split into chunks
+pub trait OtaSource {
+    /// Information about the whole image
+    fn info(&self) -> OtaImageInfo;
+
+    /// The given chunk, and its Poly1305 tag
+    fn chunk(&self, idx: u32) -> Option<(&[u8; OTA_CHUNK_SIZE], [u8; OTA_TAG_SIZE])>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OtaError {
+    /// The sub stopped responding
+    Timeout,
+    /// The image source could not provide a chunk
+    Source,
+    /// A message could not be allocated or sent
+    Send,
+    /// The image is larger than `OTA_MAX_CHUNKS`
+    TooLarge,
+    /// Some chunks were still missing after the last multicast round
+    Incomplete,
+    /// The sub reported a failure
+    Failed(OtaFailure),
+}
+
+pub struct OtaServer<R, const N: usize, const SZ: usize>
+where
+    R: RollingTimer<Tick = u32> + Default,
+{
+    _timer: PhantomData<R>,
+    socket: DispatchSocket<'static, N, SZ>,
+    alloc: SlabHandle<N, SZ>,
+}
+
+impl<R, const N: usize, const SZ: usize> OtaServer<R, N, SZ>
+where
+    R: RollingTimer<Tick = u32> + Default,
+{
+    /// Create a new server. `socket` should be registered on the `OTA_PORT`.
+    pub fn new(socket: DispatchSocket<'static, N, SZ>, alloc: SlabHandle<N, SZ>) -> Self {
+        Self {
+            _timer: PhantomData,
+            socket,
+            alloc,
+        }
+    }
+
+    /// Send the image from `source` to the sub at local address `addr`.
+    ///
+    /// On success, the sub has verified and stored the image, and is rebooting
+    /// into the bootloader.
+    pub async fn update<S: OtaSource>(&mut self, addr: u8, source: &S) -> Result<(), OtaError> {
+        let info = source.info();
+        let total = info.total_chunks;
+
+        let mut next = self
+            .exchange(addr, || DomOtaPayload::Start(info.clone()))
+            .await?;
+
+        loop {
+            next = match next {
+                SubOtaPayload::Next { idx } if idx < total => {
+                    let (data, tag) = source.chunk(idx).ok_or(OtaError::Source)?;
+                    self.exchange(addr, || DomOtaPayload::Chunk {
+                        idx,
+                        tag,
+                        data: ManagedArcSlab::Borrowed(data),
+                    })
+                    .await?
+                }
+                SubOtaPayload::Missing { start: idx, .. } => SubOtaPayload::Next { idx },
+                SubOtaPayload::Next { .. } => self.exchange(addr, || DomOtaPayload::Finish).await?,
+                SubOtaPayload::Finished => return Ok(()),
+                SubOtaPayload::Failed(fail) => return Err(OtaError::Failed(fail)),
+            };
+        }
+    }
+
+    /// Send the image from `source` to all subs in `addrs` at once.
+    ///
+    /// Returns the outcome for each sub. Subs that succeeded have verified and
+    /// stored the image, and are rebooting into the bootloader.
+    pub async fn update_many<S: OtaSource>(
+        &mut self,
+        addrs: &[u8],
+        source: &S,
+    ) -> Result<OtaResults, OtaError> {
+        let info = source.info();
+        let total = info.total_chunks;
+        if (total as usize) > OTA_MAX_CHUNKS {
+            return Err(OtaError::TooLarge);
+        }
+        if addrs.len() > MAX_OTA_TARGETS {
+            return Err(OtaError::Send);
+        }
+
+        // Subs that are still receiving, and the results of the rest
+        let mut active: Vec<u8, MAX_OTA_TARGETS> = Vec::new();
+        let mut results = OtaResults::new();
+
+        for &addr in addrs {
+            match self
+                .exchange(addr, || DomOtaPayload::Start(info.clone()))
+                .await
+            {
+                Ok(SubOtaPayload::Next { .. }) => active.push(addr).ok(),
+                Ok(SubOtaPayload::Failed(fail)) => {
+                    results.push((addr, Err(OtaError::Failed(fail)))).ok()
+                }
+                Ok(_) => results.push((addr, Err(OtaError::Timeout))).ok(),
+                Err(e) => results.push((addr, Err(e))).ok(),
+            };
+        }
+
+        // The first round sends everything
+        let mut pending = ChunkMap::new();
+        (0..total).for_each(|idx| pending.set(idx));
+
+        let mut complete: Vec<u8, MAX_OTA_TARGETS> = Vec::new();
+
+        for _ in 0..MAX_MULTICAST_ROUNDS {
+            if active.is_empty() {
+                break;
+            }
+
+            for idx in pending.iter_set(total) {
+                let (data, tag) = source.chunk(idx).ok_or(OtaError::Source)?;
+                self.broadcast(DomOtaPayload::Chunk {
+                    idx,
+                    tag,
+                    data: ManagedArcSlab::Borrowed(data),
+                })
+                .await;
+            }
+            pending.clear();
+
+            let mut still_active: Vec<u8, MAX_OTA_TARGETS> = Vec::new();
+            for &addr in active.iter() {
+                match self.exchange(addr, || DomOtaPayload::Status).await {
+                    Ok(SubOtaPayload::Missing { start, .. }) if start >= total => {
+                        complete.push(addr).ok();
+                    }
+                    Ok(SubOtaPayload::Missing { start, bits }) => {
+                        (0..OTA_MISSING_WINDOW as u32)
+                            .filter(|n| (bits[(n / 8) as usize] & (1 << (n % 8))) != 0)
+                            .for_each(|n| pending.set(start + n));
+                        still_active.push(addr).ok();
+                    }
+                    Ok(SubOtaPayload::Failed(fail)) => {
+                        results.push((addr, Err(OtaError::Failed(fail)))).ok();
+                    }
+                    Ok(_) => {
+                        results.push((addr, Err(OtaError::Timeout))).ok();
+                    }
+                    Err(e) => {
+                        results.push((addr, Err(e))).ok();
+                    }
+                }
+            }
+            active = still_active;
+        }
+
+        for &addr in active.iter() {
+            results.push((addr, Err(OtaError::Incomplete))).ok();
+        }
+
+        for &addr in complete.iter() {
+            let res = match self.exchange(addr, || DomOtaPayload::Finish).await {
+                Ok(SubOtaPayload::Finished) => Ok(()),
+                Ok(SubOtaPayload::Failed(fail)) => Err(OtaError::Failed(fail)),
+                Ok(_) => Err(OtaError::Timeout),
+                Err(e) => Err(e),
+            };
+            results.push((addr, res)).ok();
+        }
+
+        Ok(results)
+    }
+
+    /// Tell the sub at local address `addr` to discard any partial image
+    pub fn abort(&mut self, addr: u8) -> Result<(), OtaError> {
+        self.send(addr, DomOtaPayload::Abort)
+    }
+
+    async fn exchange<'a, F>(&mut self, addr: u8, msg: F) -> Result<SubOtaPayload, OtaError>
+    where
+        F: Fn() -> DomOtaPayload<'a, N, SZ>,
+    {
+        // Discard any late responses to a previous exchange
+        while self.socket.try_recv().is_some() {}
+
+        for _ in 0..MAX_RETRIES {
+            self.send(addr, msg())?;
+
+            let timer = R::default();
+            let start = timer.get_ticks();
+
+            while let Some(resp) = receive_timeout_micros::<R, SubOtaPayload, N, SZ>(
+                &mut self.socket,
+                start,
+                RESPONSE_TIMEOUT_US,
+            )
+            .await
+            {
+                // Ignore stale responses from anyone else
+                if resp.hdr.src.addr.get_exact_local_addr() == Some(addr) {
+                    return Ok(resp.body);
+                }
+            }
+        }
+
+        Err(OtaError::Timeout)
+    }
+
+    /// Broadcast a message to all subs, waiting for room to send it
+    async fn broadcast(&mut self, msg: DomOtaPayload<'_, N, SZ>) {
+        let dst = AddrPort::from_parts(VecAddr::local_broadcast_addr(), OTA_PORT);
+        if self.socket.send_msg(&msg, dst).await.is_err() {
+            defmt::warn!("OTA message too large to send!");
+        }
+    }
+
+    fn send(&mut self, addr: u8, msg: DomOtaPayload<'_, N, SZ>) -> Result<(), OtaError> {
+        let pkt = LocalPacket::from_parts_with_alloc(
+            msg,
+            AddrPort::from_parts(VecAddr::local_dom_addr(), OTA_PORT),
+            AddrPort::from_parts(VecAddr::from_local_addr(addr), OTA_PORT),
+            None,
+            &self.alloc,
+        )
+        .ok_or(OtaError::Send)?;
+
+        self.socket.try_send(pkt).map_err(|_| OtaError::Send)
+    }
+}
@@ -1,15 +1,13 @@
 use crate::{
     async_sleep_micros, async_sleep_millis,
-    dispatch::{DispatchSocket, LocalPacket},
-    icd::{
-        AddrPort, DomTokenGrantPayload, SubTokenReleasePayload, VecAddr, SLAB_SIZE, TOTAL_SLABS,
-    },
+    dispatch::{DispatchSocket, LocalPacket, PacketAlloc},
+    icd::{AddrPort, DomTokenGrantPayload, SubTokenReleasePayload, VecAddr},
     receive_timeout_micros,
+    timing::BusTiming,
 };
 
 use core::marker::PhantomData;
 
-use byte_slab::BSlab;
 use groundhog::RollingTimer;
 use rand::Rng;
 
@@ -17,29 +15,31 @@ use crate::dom::AddrTable32;
 
 use super::TOKEN_PORT;
 
-pub struct Token<R, A>
+pub struct Token<R, A, const N: usize, const SZ: usize>
 where
     R: RollingTimer<Tick = u32> + Default,
     A: Rng,
 {
     _timer: PhantomData<R>,
-    socket: DispatchSocket<'static>,
+    socket: DispatchSocket<'static, N, SZ>,
     table: &'static AddrTable32,
     rand: A,
-    alloc: &'static BSlab<TOTAL_SLABS, SLAB_SIZE>,
+    alloc: PacketAlloc<N, SZ>,
     ping_table: [Option<u32>; 32],
+    timing: BusTiming,
 }
 
-impl<R, A> Token<R, A>
+impl<R, A, const N: usize, const SZ: usize> Token<R, A, N, SZ>
 where
     R: RollingTimer<Tick = u32> + Default,
     A: Rng,
 {
     pub fn new(
-        socket: DispatchSocket<'static>,
+        socket: DispatchSocket<'static, N, SZ>,
         rand: A,
-        alloc: &'static BSlab<TOTAL_SLABS, SLAB_SIZE>,
+        alloc: PacketAlloc<N, SZ>,
         table: &'static AddrTable32,
+        timing: BusTiming,
     ) -> Self {
         Self {
             _timer: PhantomData,
@@ -48,6 +48,7 @@ where
             table,
             alloc,
             ping_table: [None; 32],
+            timing,
         }
     }
 
@@ -84,15 +85,15 @@ where
 
             let payload = DomTokenGrantPayload {
                 random,
-                max_time_us: 50_000,
+                max_time_us: self.timing.token_grant_us,
             };
 
-            let msg = LocalPacket::from_parts_with_alloc(
+            let msg = LocalPacket::from_parts_with_packet_alloc(
                 payload,
                 AddrPort::from_parts(VecAddr::local_dom_addr(), TOKEN_PORT),
                 addr_port.clone(),
-                Some(50_000),
-                self.alloc,
+                Some(self.timing.token_grant_us),
+                &self.alloc,
             )
             .ok_or(())?;
 
@@ -100,10 +101,10 @@ where
             let start = timer.get_ticks();
 
             'inner: loop {
-                let maybe_msg = receive_timeout_micros::<R, SubTokenReleasePayload>(
+                let maybe_msg = receive_timeout_micros::<R, SubTokenReleasePayload, N, SZ>(
                     &mut self.socket,
                     start,
-                    50_000,
+                    self.timing.token_grant_us,
                 )
                 .await;
 
@@ -132,8 +133,14 @@ where
             }
             if bad {
                 self.ping_table[addr as usize] = None;
-                defmt::warn!("Yeeting {=u8}", addr);
-                self.table.release_active_addr(addr)?;
+
+                // Static subs keep their address, and keep being polled
+                if self.table.is_static(addr) {
+                    defmt::warn!("Static {=u8} is not responding!", addr);
+                } else {
+                    defmt::warn!("Yeeting {=u8}", addr);
+                    self.table.release_active_addr(addr)?;
+                }
             }
 
             if timer.micros_since(last_start) <= 1000 {
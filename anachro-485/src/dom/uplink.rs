@@ -0,0 +1,223 @@
+//! A bridge between a host PC and the dom's `Dispatch`
+//!
+//! The application registers the ports it would like to expose to the host,
+//! and hands their sockets to an `UplinkBridge`. Bytes received from the host
+//! (over a serial port, USB-CDC, etc.) are fed in with `push_from_host()`, and
+//! frames to be written back to the host are taken with `pop_to_host()`.
+//!
+//! Frames in both directions are postcard serialized and COBS encoded, the
+//! same as messages on the bus. See `HostUplinkPayload` and `DomUplinkPayload`
+//! for the contents.
+
+use core::ops::{Deref, DerefMut};
+
+use byte_slab::{ManagedArcSlab, Reroot, SlabBox, SlabHandle, SlabSliceArc, SlabWriter};
+use cobs::decode_in_place;
+use heapless::{Deque, Vec};
+use postcard::from_bytes;
+
+use crate::{
+    dispatch::{Dispatch, DispatchSocket, LocalHeader, LocalPacket},
+    icd::{AddrPort, DomUplinkPayload, HostUplinkPayload, VecAddr, MAX_UPLINK_PORTS},
+};
+
+const REPLY_DEPTH: usize = 4;
+
+enum Reply {
+    Ports,
+    Dropped(u16),
+}
+
+struct PartialFrame<const N: usize, const SZ: usize> {
+    sbox: SlabBox<N, SZ>,
+    used: usize,
+    overflowed: bool,
+}
+
+pub struct UplinkBridge<const PORTS: usize, const N: usize, const SZ: usize> {
+    dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+    alloc: SlabHandle<N, SZ>,
+    sockets: Vec<DispatchSocket<'static, N, SZ>, MAX_UPLINK_PORTS>,
+    next_socket: usize,
+    rx: Option<PartialFrame<N, SZ>>,
+
+    /// Dropping the rest of a frame we had nowhere to put
+    resync: bool,
+    replies: Deque<Reply, REPLY_DEPTH>,
+}
+
+impl<const PORTS: usize, const N: usize, const SZ: usize> UplinkBridge<PORTS, N, SZ> {
+    pub fn new(
+        dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+        alloc: SlabHandle<N, SZ>,
+    ) -> Self {
+        Self {
+            dispatch,
+            alloc,
+            sockets: Vec::new(),
+            next_socket: 0,
+            rx: None,
+            resync: false,
+            replies: Deque::new(),
+        }
+    }
+
+    /// Expose a registered port to the host. Returns the socket if
+    /// `MAX_UPLINK_PORTS` are already bridged.
+    pub fn add_socket(
+        &mut self,
+        socket: DispatchSocket<'static, N, SZ>,
+    ) -> Result<(), DispatchSocket<'static, N, SZ>> {
+        self.sockets.push(socket)
+    }
+
+    /// Feed bytes received from the host. Any complete frames are processed
+    /// immediately.
+    ///
+    /// If no allocation is available for a new frame, that frame is dropped,
+    /// and receiving picks up again at the next one.
+    pub fn push_from_host(&mut self, data: &[u8]) {
+        for &byte in data {
+            if self.resync {
+                self.resync = byte != 0;
+                continue;
+            }
+
+            let frame = match self.rx.as_mut() {
+                Some(frame) => frame,
+                None => match self.alloc.alloc_box() {
+                    Some(sbox) => self.rx.insert(PartialFrame {
+                        sbox,
+                        used: 0,
+                        overflowed: false,
+                    }),
+                    None => {
+                        defmt::warn!("No alloc for uplink frame!");
+                        self.resync = byte != 0;
+                        continue;
+                    }
+                },
+            };
+
+            if frame.used < frame.sbox.len() {
+                frame.sbox[frame.used] = byte;
+                frame.used += 1;
+            } else {
+                frame.overflowed = true;
+            }
+
+            if byte != 0 {
+                continue;
+            }
+
+            if let Some(frame) = self.rx.take() {
+                if frame.overflowed {
+                    defmt::warn!("Uplink frame too long!");
+                } else if self.process_frame(frame).is_err() {
+                    defmt::warn!("Bad uplink frame!");
+                }
+            }
+        }
+    }
+
+    /// Take the next encoded frame to be written to the host, if any
+    ///
+    /// A received message too large to fit in a single frame is dropped, and
+    /// the host is told with a `Dropped` reply instead.
+    pub fn pop_to_host(&mut self) -> Option<SlabSliceArc<N, SZ>> {
+        loop {
+            // Check for an allocation FIRST, to avoid taking a packet
+            // we can't send
+            let sbox = self.alloc.alloc_box()?;
+
+            if let Some(reply) = self.replies.pop_front() {
+                let msg: DomUplinkPayload<N, SZ> = match reply {
+                    Reply::Ports => DomUplinkPayload::Ports {
+                        addr: self.dispatch.get_addr(),
+                        ports: self.sockets.iter().map(|s| s.port().get()).collect(),
+                    },
+                    Reply::Dropped(port) => DomUplinkPayload::Dropped { port },
+                };
+                return SlabWriter::serialize_cobs(&msg, sbox).ok();
+            }
+
+            let pkt = self.next_packet()?;
+            let port = pkt.hdr.dst.port;
+            let msg: DomUplinkPayload<N, SZ> = DomUplinkPayload::Recv {
+                port,
+                src: pkt.hdr.src.clone(),
+                tick: pkt.hdr.tick,
+                payload: ManagedArcSlab::Borrowed(pkt.payload()),
+            };
+
+            match SlabWriter::serialize_cobs(&msg, sbox) {
+                Ok(ssa) => return Some(ssa),
+                Err(_) => {
+                    defmt::warn!("Uplink message too large!");
+                    if self.replies.push_back(Reply::Dropped(port)).is_err() {
+                        defmt::warn!("Uplink replies full!");
+                    }
+                }
+            }
+        }
+    }
+
+    fn next_packet(&mut self) -> Option<LocalPacket<N, SZ>> {
+        let count = self.sockets.len();
+
+        // Take turns, so one busy port can't starve the rest
+        for _ in 0..count {
+            let idx = self.next_socket % count;
+            self.next_socket = (idx + 1) % count;
+
+            if let Some(pkt) = self.sockets[idx].try_recv() {
+                return Some(pkt);
+            }
+        }
+
+        None
+    }
+
+    fn process_frame(&mut self, mut frame: PartialFrame<N, SZ>) -> Result<(), ()> {
+        let len = decode_in_place(&mut frame.sbox.deref_mut()[..frame.used])?;
+        let arc = frame.sbox.into_arc();
+        let msg = arc.sub_slice_arc(0, len)?;
+
+        match from_bytes::<HostUplinkPayload<N, SZ>>(msg.deref()).map_err(drop)? {
+            HostUplinkPayload::Hello => {
+                self.replies.push_back(Reply::Ports).map_err(drop)?;
+            }
+            HostUplinkPayload::Send { port, dst, payload } => {
+                // Empty payloads can't be rerooted, but don't need to be
+                let payload = if payload.is_empty() {
+                    ManagedArcSlab::Borrowed(&[])
+                } else {
+                    payload.reroot(&arc.rerooter_key())?
+                };
+
+                let pkt = LocalPacket::from_hdr_payload(
+                    LocalHeader {
+                        // Dispatch fills in our real address
+                        src: AddrPort::from_parts(VecAddr::local_dom_addr(), port),
+                        dst,
+                        tick: 0,
+                    },
+                    payload,
+                );
+
+                let sent = self
+                    .sockets
+                    .iter()
+                    .find(|s| s.port().get() == port)
+                    .map(|s| s.try_send(pkt).is_ok())
+                    .unwrap_or(false);
+
+                if !sent {
+                    self.replies.push_back(Reply::Dropped(port)).map_err(drop)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Host side of the dom uplink
+//!
+//! Connects to a dom running an `UplinkBridge` (over any byte stream, such as
+//! a serial port), and presents each bridged dom port as a `RemoteSocket`.
+//! Messages sent on a `RemoteSocket` are sent onto the bus from that dom
+//! port, and messages received by that dom port are delivered to it.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU32, Ordering::SeqCst},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use byte_slab::ManagedArcSlab;
+use cobs::decode_in_place;
+use postcard::{from_bytes, to_slice_cobs};
+
+use crate::icd::{AddrPort, DomUplinkPayload, HostUplinkPayload, SLAB_SIZE, TOTAL_SLABS};
+
+type Writer = Arc<Mutex<Box<dyn Write + Send>>>;
+
+// Payloads are always borrowed on the host, so the slab count only needs to
+// be filled in with something
+type HostPayload<'a, const SZ: usize> = HostUplinkPayload<'a, TOTAL_SLABS, SZ>;
+type DomPayload<'a, const SZ: usize> = DomUplinkPayload<'a, TOTAL_SLABS, SZ>;
+
+/// A message received by a bridged dom port
+#[derive(Debug, Clone)]
+pub struct RemotePacket {
+    pub src: AddrPort,
+
+    /// The dom's tick when the message was received
+    pub tick: u32,
+    pub payload: Vec<u8>,
+}
+
+struct Hello {
+    addr: Option<u8>,
+    sockets: Vec<(u16, Receiver<RemotePacket>)>,
+}
+
+/// A connection to a dom's uplink
+///
+/// `SZ` must match the slab size used by the dom, as each frame sent to it
+/// must fit in a single slab.
+pub struct UplinkHost<const SZ: usize = SLAB_SIZE> {
+    writer: Writer,
+    addr: Option<u8>,
+    ports: Vec<u16>,
+    sockets: Mutex<HashMap<u16, Receiver<RemotePacket>>>,
+    dropped: Arc<AtomicU32>,
+}
+
+impl<const SZ: usize> UplinkHost<SZ> {
+    /// Connect to a dom, using `reader` and `writer` as the two halves of the
+    /// uplink (e.g. a serial port and its `try_clone()`).
+    ///
+    /// Fails if the dom doesn't answer within `timeout`.
+    pub fn connect<R, W>(reader: R, writer: W, timeout: Duration) -> io::Result<Self>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let writer: Writer = Arc::new(Mutex::new(Box::new(writer)));
+        let dropped = Arc::new(AtomicU32::new(0));
+        let (hello_tx, hello_rx) = channel();
+
+        let thread_dropped = dropped.clone();
+        thread::spawn(move || read_frames::<R, SZ>(reader, hello_tx, thread_dropped));
+
+        send_frame::<SZ>(&writer, &HostPayload::Hello)?;
+
+        let hello = hello_rx.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => io::Error::new(io::ErrorKind::TimedOut, "no hello"),
+            RecvTimeoutError::Disconnected => io::ErrorKind::UnexpectedEof.into(),
+        })?;
+
+        Ok(Self {
+            writer,
+            addr: hello.addr,
+            ports: hello.sockets.iter().map(|(port, _)| *port).collect(),
+            sockets: Mutex::new(hello.sockets.into_iter().collect()),
+            dropped,
+        })
+    }
+
+    /// The dom's address on the bus, as of connecting
+    pub fn dom_addr(&self) -> Option<u8> {
+        self.addr
+    }
+
+    /// The dom ports bridged to the host
+    pub fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+
+    /// Take the socket for a bridged dom port. Each socket may only be taken once.
+    pub fn socket(&self, port: u16) -> Option<RemoteSocket<SZ>> {
+        let rx = self.sockets.lock().ok()?.remove(&port)?;
+        Some(RemoteSocket {
+            port,
+            writer: self.writer.clone(),
+            rx,
+        })
+    }
+
+    /// The number of messages the dom reported as dropped, on any port
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(SeqCst)
+    }
+}
+
+/// A bridged dom port
+pub struct RemoteSocket<const SZ: usize = SLAB_SIZE> {
+    port: u16,
+    writer: Writer,
+    rx: Receiver<RemotePacket>,
+}
+
+impl<const SZ: usize> RemoteSocket<SZ> {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send a message from this dom port to `dst`
+    pub fn send(&self, dst: AddrPort, payload: &[u8]) -> io::Result<()> {
+        send_frame::<SZ>(
+            &self.writer,
+            &HostPayload::Send {
+                port: self.port,
+                dst,
+                payload: ManagedArcSlab::Borrowed(payload),
+            },
+        )
+    }
+
+    /// Wait for the next message. Returns `None` if the uplink has closed.
+    pub fn recv(&self) -> Option<RemotePacket> {
+        self.rx.recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<RemotePacket> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+
+    pub fn try_recv(&self) -> Option<RemotePacket> {
+        match self.rx.try_recv() {
+            Ok(pkt) => Some(pkt),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+fn send_frame<const SZ: usize>(writer: &Writer, msg: &HostPayload<SZ>) -> io::Result<()> {
+    // The dom must fit each frame into a single slab
+    let mut buf = [0u8; SZ];
+    let used = to_slice_cobs(msg, &mut buf)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+
+    let mut writer = writer.lock().map_err(|_| io::Error::other("poisoned"))?;
+    writer.write_all(used)?;
+    writer.flush()
+}
+
+fn read_frames<R: Read, const SZ: usize>(
+    mut reader: R,
+    hello: Sender<Hello>,
+    dropped: Arc<AtomicU32>,
+) {
+    let mut senders: HashMap<u16, Sender<RemotePacket>> = HashMap::new();
+    let mut hello = Some(hello);
+    let mut frame = Vec::new();
+    let mut buf = [0u8; 256];
+
+    loop {
+        let used = match reader.read(&mut buf) {
+            Ok(0) => return,
+            Ok(used) => used,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(_) => return,
+        };
+
+        for &byte in &buf[..used] {
+            frame.push(byte);
+            if byte != 0 {
+                if frame.len() > 2 * SZ {
+                    // Junk, or a lost terminator. Every frame from the dom
+                    // fits in a slab, so hold on to the newest bytes only
+                    frame.drain(..SZ);
+                }
+                continue;
+            }
+
+            let len = match decode_in_place(&mut frame) {
+                Ok(len) => len,
+                Err(_) => {
+                    frame.clear();
+                    continue;
+                }
+            };
+
+            match from_bytes::<DomPayload<SZ>>(&frame[..len]) {
+                Ok(DomUplinkPayload::Ports { addr, ports }) => {
+                    // Only the first answer sets up the sockets
+                    if let Some(hello) = hello.take() {
+                        let sockets = ports
+                            .iter()
+                            .map(|port| {
+                                let (tx, rx) = channel();
+                                senders.insert(*port, tx);
+                                (*port, rx)
+                            })
+                            .collect();
+                        hello.send(Hello { addr, sockets }).ok();
+                    }
+                }
+                Ok(DomUplinkPayload::Recv {
+                    port,
+                    src,
+                    tick,
+                    payload,
+                }) => {
+                    if let Some(tx) = senders.get(&port) {
+                        tx.send(RemotePacket {
+                            src,
+                            tick,
+                            payload: payload.to_vec(),
+                        })
+                        .ok();
+                    }
+                }
+                Ok(DomUplinkPayload::Dropped { .. }) => {
+                    dropped.fetch_add(1, SeqCst);
+                }
+                Err(_) => {}
+            }
+
+            frame.clear();
+        }
+    }
+}
@@ -1,13 +1,21 @@
-pub use byte_slab::ManagedArcSlab;
+pub use byte_slab::{ManagedArcSlab, ManagedArcStr};
+use byte_slab::{Reroot, RerooterKey};
+use core::{fmt, marker::PhantomData};
 pub use heapless::Vec;
 use rand::Rng;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
 use crate::{dispatch::LocalHeader, dom::DISCOVERY_PORT, HeaderPacket};
 
 pub const MAX_ADDR_SEGMENTS: usize = 8;
 
-// These should prooooobably be configurable
+// Suggested slab configuration for a node with RAM to spare. Smaller
+// nodes may use any other slab count or size, as long as every
+// message they send or receive fits in a single slab.
 pub const TOTAL_SLABS: usize = 128;
 pub const SLAB_SIZE: usize = 512;
 
@@ -15,6 +23,11 @@ pub const SLAB_SIZE: usize = 512;
 pub const LOCAL_DOM_ADDR: u8 = 0;
 pub const LOCAL_BROADCAST_ADDR: u8 = 255;
 
+/// Messages sent to this address are delivered to a port on the sending
+/// node, and never reach the bus. Like any other outgoing message, they are
+/// only processed once the node has an address.
+pub const LOCAL_LOOPBACK_ADDR: u8 = 254;
+
 pub const LOCAL_ADDR_LEN: usize = 1;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -33,14 +46,113 @@ impl AddrPort {
 pub struct LineHeader {
     pub(crate) src: AddrPort,
     pub(crate) dst: AddrPort,
+
+    /// The sender's tick when the message was sent, for ports with wire
+    /// timestamps enabled.
+    ///
+    /// This is not serialized with the header, but as a trailer after the
+    /// payload of a `LineMessage`. Frames without a tick are unchanged from
+    /// before wire timestamps existed, and older receivers ignore the trailer.
+    #[serde(skip)]
+    pub(crate) tick: Option<u32>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LineMessage<'a> {
+impl LineHeader {
+    pub fn src(&self) -> &AddrPort {
+        &self.src
+    }
+
+    pub fn dst(&self) -> &AddrPort {
+        &self.dst
+    }
+
+    pub fn tick(&self) -> Option<u32> {
+        self.tick
+    }
+}
+
+/// A frame on the wire: the header, the payload, then the tick trailer if
+/// the header has a tick
+#[derive(Debug)]
+pub struct LineMessage<'a, const N: usize, const SZ: usize> {
     pub(crate) hdr: LineHeader,
+    pub(crate) msg: ManagedArcSlab<'a, N, SZ>,
+}
 
-    #[serde(borrow)]
-    pub(crate) msg: ManagedArcSlab<'a, TOTAL_SLABS, SLAB_SIZE>,
+impl<'a, const N: usize, const SZ: usize> Serialize for LineMessage<'a, N, SZ> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_frame(serializer, &self.hdr, &self.msg)
+    }
+}
+
+impl<'de: 'a, 'a, const N: usize, const SZ: usize> Deserialize<'de> for LineMessage<'a, N, SZ> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (hdr, msg) = deserialize_frame(deserializer)?;
+        Ok(LineMessage { hdr, msg })
+    }
+}
+
+/// Serialize a header and payload, followed by the tick trailer if any
+pub(crate) fn serialize_frame<S, P>(
+    serializer: S,
+    hdr: &LineHeader,
+    payload: &P,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    P: Serialize + ?Sized,
+{
+    let mut frame = serializer.serialize_tuple(2 + hdr.tick.is_some() as usize)?;
+    frame.serialize_element(hdr)?;
+    frame.serialize_element(payload)?;
+    if let Some(tick) = hdr.tick.as_ref() {
+        frame.serialize_element(tick)?;
+    }
+    frame.end()
+}
+
+/// Deserialize a header and payload, and the tick trailer if there is one.
+///
+/// This must be the last thing in the input, as anything after the payload
+/// is taken as the trailer.
+pub(crate) fn deserialize_frame<'de, D, P>(deserializer: D) -> Result<(LineHeader, P), D::Error>
+where
+    D: Deserializer<'de>,
+    P: Deserialize<'de>,
+{
+    struct FrameVisitor<P>(PhantomData<P>);
+
+    impl<'de, P: Deserialize<'de>> Visitor<'de> for FrameVisitor<P> {
+        type Value = (LineHeader, P);
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a line message")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let missing = || serde::de::Error::custom("truncated frame");
+            let mut hdr: LineHeader = seq.next_element()?.ok_or_else(missing)?;
+            let payload: P = seq.next_element()?.ok_or_else(missing)?;
+
+            // Running out of input here just means there is no trailer
+            hdr.tick = seq.next_element().ok().flatten();
+
+            Ok((hdr, payload))
+        }
+    }
+
+    deserializer.deserialize_tuple(3, FrameVisitor(PhantomData))
+}
+
+impl<'a, const N: usize, const SZ: usize> Reroot for LineMessage<'a, N, SZ> {
+    type Retval = LineMessage<'static, N, SZ>;
+
+    fn reroot(self, key: &RerooterKey) -> Result<Self::Retval, ()> {
+        Ok(LineMessage {
+            hdr: self.hdr,
+            msg: self.msg.reroot(key)?,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -71,6 +183,12 @@ impl VecAddr {
         Self { bytes: vec }
     }
 
+    pub fn local_loopback_addr() -> Self {
+        let mut vec = Vec::new();
+        vec.push(LOCAL_LOOPBACK_ADDR).ok();
+        Self { bytes: vec }
+    }
+
     pub fn get_exact_local_addr(&self) -> Option<u8> {
         if self.bytes.len() != LOCAL_ADDR_LEN {
             // Not a local addr, has a chain
@@ -78,6 +196,29 @@ impl VecAddr {
         }
         self.bytes.get(0).cloned()
     }
+
+    /// The first hop of the address, which is the local address on the
+    /// bus the message is currently travelling on
+    pub fn first_hop(&self) -> Option<u8> {
+        self.bytes.first().cloned()
+    }
+
+    /// The address with the first hop removed, or `None` if it is a local addr
+    pub fn next_hops(&self) -> Option<Self> {
+        if self.bytes.len() <= LOCAL_ADDR_LEN {
+            return None;
+        }
+        Self::from_addrs(&self.bytes[1..]).ok()
+    }
+
+    /// The address with `addr` added as the first hop. Fails if there is no
+    /// room for another hop.
+    pub fn via(&self, addr: u8) -> Result<Self, ()> {
+        let mut bytes = Vec::new();
+        bytes.push(addr).map_err(drop)?;
+        bytes.extend_from_slice(&self.bytes)?;
+        Ok(Self { bytes })
+    }
 }
 
 pub const MAX_OFFERS: usize = 32;
@@ -93,6 +234,245 @@ pub struct SubTokenReleasePayload {
     pub random: u32,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// A single log record, as sent by a sub on the `LOG_PORT`.
+///
+/// A log message payload contains one or more of these, back to back.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogRecord<'a, const N: usize, const SZ: usize> {
+    pub tick: u32,
+    pub level: LogLevel,
+
+    #[serde(borrow)]
+    pub text: ManagedArcStr<'a, N, SZ>,
+}
+
+/// The size of a single firmware image chunk, as produced by `boot-chonker`
+pub const OTA_CHUNK_SIZE: usize = 256;
+pub const OTA_TAG_SIZE: usize = 16;
+
+/// The largest image that may be sent, in chunks (512KiB)
+pub const OTA_MAX_CHUNKS: usize = 2048;
+
+/// The number of chunks covered by a single `SubOtaPayload::Missing` report
+pub const OTA_MISSING_WINDOW: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OtaImageInfo {
+    pub uuid: [u8; 16],
+
+    /// The Poly1305 tag over all chunks of the image
+    pub image_tag: [u8; OTA_TAG_SIZE],
+    pub total_chunks: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DomOtaPayload<'a, const N: usize, const SZ: usize> {
+    Start(OtaImageInfo),
+    Chunk {
+        idx: u32,
+
+        /// The Poly1305 tag over this chunk only
+        tag: [u8; OTA_TAG_SIZE],
+
+        #[serde(borrow)]
+        data: ManagedArcSlab<'a, N, SZ>,
+    },
+
+    /// Ask which chunks are still missing, after a round of broadcast chunks
+    Status,
+    Finish,
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum OtaFailure {
+    NotStarted,
+    TooLarge,
+    Incomplete,
+    BadImageTag,
+    Storage,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SubOtaPayload {
+    /// The sub would like this chunk next. Sent in response to `Start`
+    /// and every `Chunk`, whether it was accepted or not.
+    Next {
+        idx: u32,
+    },
+
+    /// Sent in response to `Status`. `start` is the first missing chunk,
+    /// and bit `n` of `bits` is set if chunk `start + n` is missing. If
+    /// `start` is past the end of the image, nothing is missing.
+    Missing {
+        start: u32,
+        bits: [u8; OTA_MISSING_WINDOW / 8],
+    },
+
+    /// The image has been stored, and the sub is about to reboot
+    Finished,
+
+    Failed(OtaFailure),
+}
+
+/// A set of chunk indexes, used to track received or missing chunks
+pub(crate) struct ChunkMap {
+    bits: [u8; OTA_MAX_CHUNKS / 8],
+}
+
+impl ChunkMap {
+    pub(crate) const fn new() -> Self {
+        Self {
+            bits: [0u8; OTA_MAX_CHUNKS / 8],
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|b| *b = 0);
+    }
+
+    pub(crate) fn get(&self, idx: u32) -> bool {
+        let idx = idx as usize;
+        idx < OTA_MAX_CHUNKS && (self.bits[idx / 8] & (1 << (idx % 8))) != 0
+    }
+
+    pub(crate) fn set(&mut self, idx: u32) {
+        let idx = idx as usize;
+        if idx < OTA_MAX_CHUNKS {
+            self.bits[idx / 8] |= 1 << (idx % 8);
+        }
+    }
+
+    /// The first index below `total` that is not set
+    pub(crate) fn first_unset(&self, total: u32) -> u32 {
+        (0..total).find(|i| !self.get(*i)).unwrap_or(total)
+    }
+
+    /// Any indexes below `total` that are set, in order
+    pub(crate) fn iter_set(&self, total: u32) -> impl Iterator<Item = u32> + '_ {
+        (0..total).filter(move |i| self.get(*i))
+    }
+}
+
+/// The most dom ports that may be bridged to a host over the uplink
+pub const MAX_UPLINK_PORTS: usize = 8;
+
+/// A frame sent from a host to the dom over the uplink.
+///
+/// Frames are postcard serialized, and COBS encoded, with a zero terminator.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HostUplinkPayload<'a, const N: usize, const SZ: usize> {
+    /// Ask which ports the dom bridges. Answered with `Ports`.
+    Hello,
+
+    /// Send a message from one of the bridged dom ports
+    Send {
+        port: u16,
+        dst: AddrPort,
+
+        #[serde(borrow)]
+        payload: ManagedArcSlab<'a, N, SZ>,
+    },
+}
+
+/// A frame sent from the dom to a host over the uplink.
+///
+/// Frames are postcard serialized, and COBS encoded, with a zero terminator.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DomUplinkPayload<'a, const N: usize, const SZ: usize> {
+    /// The ports bridged by the dom, and the dom's own address (if it has one)
+    Ports {
+        addr: Option<u8>,
+        ports: Vec<u16, MAX_UPLINK_PORTS>,
+    },
+
+    /// A message received on one of the bridged dom ports
+    Recv {
+        port: u16,
+        src: AddrPort,
+        tick: u32,
+
+        #[serde(borrow)]
+        payload: ManagedArcSlab<'a, N, SZ>,
+    },
+
+    /// A `Send` from the host was dropped, because the port was unknown or full
+    Dropped { port: u16 },
+}
+
+/// Message counts kept by a `Dispatch`, since startup
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DispatchStats {
+    /// Messages received from the bus and delivered to a port
+    pub rx_delivered: u32,
+
+    /// Messages received from the bus for us that could not be delivered,
+    /// or passed on to another bus
+    pub rx_dropped: u32,
+
+    /// Messages handed to the IO handler
+    pub tx_sent: u32,
+
+    /// Outgoing messages from our own ports that were lost
+    pub tx_dropped: u32,
+
+    /// Messages delivered from one of our own ports to another
+    pub local_delivered: u32,
+
+    /// Messages from another bus, passed on to this one. These are not
+    /// counted as delivered on the bus they came from.
+    pub forwarded: u32,
+}
+
+/// Details of a sub's firmware, as provided by the application
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    /// Major, minor, and patch version
+    pub version: [u8; 3],
+    pub build_id: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub uptime_s: u32,
+    pub firmware: FirmwareInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DomMgmtPayload {
+    /// Reboot the sub
+    Reset,
+
+    /// Make the sub visible to a human, e.g. by blinking an LED
+    Identify {
+        duration_ms: u32,
+    },
+
+    GetStats,
+    GetInfo,
+
+    /// Drop the current address, and take part in discovery again. Subs
+    /// with a static address keep it.
+    Rediscover,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SubMgmtPayload {
+    /// Sent in response to `Reset`, `Identify`, and `Rediscover`
+    Ack,
+    Stats(DispatchStats),
+    Info(NodeInfo),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum DomDiscoveryPayload {
     ResetConnection,
@@ -336,3 +716,74 @@ pub fn checksum_addr_random(addr: u8, dom_random: u32, sub_random: u32) -> u32 {
         .wrapping_mul(sub_random)
         .wrapping_add(sub_random)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use postcard::{from_bytes, to_slice};
+
+    fn message(tick: Option<u32>) -> LineMessage<'static, 1, 64> {
+        LineMessage {
+            hdr: LineHeader {
+                src: AddrPort::from_parts(VecAddr::from_local_addr(3), 10),
+                dst: AddrPort::from_parts(VecAddr::local_dom_addr(), 10),
+                tick,
+            },
+            msg: ManagedArcSlab::Borrowed(&[1, 2, 3]),
+        }
+    }
+
+    #[test]
+    fn chunk_map_bounds() {
+        let mut map = ChunkMap::new();
+        let last = OTA_MAX_CHUNKS as u32 - 1;
+
+        map.set(0);
+        map.set(last);
+        assert!(map.get(0));
+        assert!(map.get(last));
+        assert!(!map.get(last - 1));
+
+        // Past the end is ignored, and never set
+        map.set(last + 1);
+        map.set(u32::MAX);
+        assert!(!map.get(last + 1));
+        assert!(!map.get(u32::MAX));
+
+        let all = OTA_MAX_CHUNKS as u32;
+        assert!(map.iter_set(all).eq([0, last].iter().copied()));
+        assert!(map.iter_set(all + 8).eq([0, last].iter().copied()));
+        assert!(map.iter_set(last).eq([0].iter().copied()));
+        assert_eq!(map.first_unset(all), 1);
+
+        (0..all).for_each(|i| map.set(i));
+        assert_eq!(map.first_unset(all), all);
+        assert_eq!(map.iter_set(all).count(), OTA_MAX_CHUNKS);
+
+        map.clear();
+        assert_eq!(map.iter_set(all).count(), 0);
+    }
+
+    fn plain_bytes() -> &'static [u8] {
+        &[1, 3, 10, 0, 1, 0, 10, 0, 3, 1, 2, 3]
+    }
+
+    #[test]
+    fn tick_trailer() {
+        // Without a tick, the frame is just the header and payload
+        let mut buf = [0u8; 64];
+        let plain = to_slice(&message(None), &mut buf).unwrap();
+        assert_eq!(plain, plain_bytes());
+        let lm = from_bytes::<LineMessage<1, 64>>(plain).unwrap();
+        assert_eq!(lm.hdr.tick, None);
+        assert_eq!(&*lm.msg, &[1, 2, 3]);
+
+        let mut buf = [0u8; 64];
+        let timed = to_slice(&message(Some(300)), &mut buf).unwrap();
+        assert_eq!(&timed[..12], plain_bytes());
+        assert_eq!(&timed[12..], &300u32.to_le_bytes());
+        let lm = from_bytes::<LineMessage<1, 64>>(timed).unwrap();
+        assert_eq!(lm.hdr.tick, Some(300));
+        assert_eq!(&*lm.msg, &[1, 2, 3]);
+    }
+}
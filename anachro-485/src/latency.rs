@@ -0,0 +1,111 @@
+//! Measuring how long messages take
+//!
+//! All times are in ticks of the `RollingTimer` given to
+//! `IoQueue::set_timer()`. Latencies between nodes are only meaningful if
+//! their timers agree.
+
+use crate::atomic::{AtomicU32, Ordering::SeqCst};
+
+/// Totals for a set of measured latencies
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: u32,
+    pub total_ticks: u32,
+    pub max_ticks: u32,
+}
+
+impl LatencySummary {
+    pub fn mean_ticks(&self) -> Option<u32> {
+        self.total_ticks.checked_div(self.count)
+    }
+}
+
+/// Running totals, which may be updated from any context
+pub(crate) struct LatencyTotals {
+    count: AtomicU32,
+    total_ticks: AtomicU32,
+    max_ticks: AtomicU32,
+}
+
+impl LatencyTotals {
+    pub(crate) const fn new() -> Self {
+        Self {
+            count: AtomicU32::new(0),
+            total_ticks: AtomicU32::new(0),
+            max_ticks: AtomicU32::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, ticks: u32) {
+        self.count.fetch_add(1, SeqCst);
+        self.total_ticks.fetch_add(ticks, SeqCst);
+        self.max_ticks.fetch_max(ticks, SeqCst);
+    }
+
+    pub(crate) fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count.load(SeqCst),
+            total_ticks: self.total_ticks.load(SeqCst),
+            max_ticks: self.max_ticks.load(SeqCst),
+        }
+    }
+}
+
+/// A histogram of latencies, with `B` buckets of `bucket_ticks` each.
+///
+/// Anything longer than the last bucket is counted in the last bucket.
+/// Usually kept per port by the receiving task, fed with
+/// `LocalPacket::latency_ticks()`.
+pub struct LatencyHistogram<const B: usize> {
+    bucket_ticks: u32,
+    buckets: [u32; B],
+    totals: LatencySummary,
+}
+
+impl<const B: usize> LatencyHistogram<B> {
+    pub const fn new(bucket_ticks: u32) -> Self {
+        Self {
+            bucket_ticks,
+            buckets: [0; B],
+            totals: LatencySummary {
+                count: 0,
+                total_ticks: 0,
+                max_ticks: 0,
+            },
+        }
+    }
+
+    pub fn record(&mut self, ticks: u32) {
+        let idx = ticks
+            .checked_div(self.bucket_ticks)
+            .unwrap_or(0)
+            .min(B.saturating_sub(1) as u32) as usize;
+
+        if let Some(bucket) = self.buckets.get_mut(idx) {
+            *bucket = bucket.saturating_add(1);
+        }
+
+        let totals = &mut self.totals;
+        totals.count = totals.count.saturating_add(1);
+        totals.total_ticks = totals.total_ticks.saturating_add(ticks);
+        totals.max_ticks = totals.max_ticks.max(ticks);
+    }
+
+    /// The count of each bucket. Bucket `i` holds latencies from
+    /// `i * bucket_ticks` up to (but not including) `(i + 1) * bucket_ticks`.
+    pub fn buckets(&self) -> &[u32; B] {
+        &self.buckets
+    }
+
+    pub fn bucket_ticks(&self) -> u32 {
+        self.bucket_ticks
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        self.totals
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.bucket_ticks);
+    }
+}
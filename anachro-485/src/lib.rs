@@ -2,12 +2,24 @@
 
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 
+pub mod capture;
 pub mod dispatch;
 pub mod dom;
+#[cfg(feature = "std")]
+pub mod host;
 pub mod icd;
+pub mod latency;
+pub mod security;
 pub mod sub;
 pub mod timing;
 
+// Targets without compare-and-swap (like thumbv6m) get their atomics from a
+// critical section instead
+#[cfg(feature = "atomic-polyfill")]
+pub(crate) use atomic_polyfill as atomic;
+#[cfg(not(feature = "atomic-polyfill"))]
+pub(crate) use core::sync::atomic;
+
 use dispatch::{DispatchSocket, LocalHeader};
 use groundhog::{self, RollingTimer};
 use postcard::from_bytes;
@@ -66,8 +78,8 @@ pub struct HeaderPacket<T> {
     pub body: T,
 }
 
-pub async fn receive_timeout_micros<R, T>(
-    interface: &mut DispatchSocket<'static>,
+pub async fn receive_timeout_micros<R, T, const N: usize, const SZ: usize>(
+    interface: &mut DispatchSocket<'_, N, SZ>,
     start: R::Tick,
     duration: R::Tick,
 ) -> Option<HeaderPacket<T>>
@@ -0,0 +1,548 @@
+//! Authenticated encryption of port payloads
+//!
+//! A port registered with a `PortSecurity` has the `msg` of every
+//! `LineMessage` it sends sealed with XChaCha20-Poly1305, and rejects any
+//! incoming message that fails authentication or is a replay.
+//!
+//! On the wire, a sealed payload looks like this:
+//!
+//! * 0..8: Sender node id (u64, little endian)
+//! * 8..12: Sender epoch (u32, little endian)
+//! * 12..16: Sender counter (u32, little endian)
+//! * 16..32: Poly1305 tag
+//! * 32..: Ciphertext
+//!
+//! The `LineHeader` (source and destination `AddrPort`s) is used as the
+//! associated data, so a sealed payload cannot be redirected to another
+//! address or port.
+
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    Key, Tag, XChaCha20Poly1305, XNonce,
+};
+use heapless::Vec;
+use postcard::to_slice;
+
+use crate::{
+    dom::AddrTable32,
+    icd::{LineHeader, LOCAL_BROADCAST_ADDR, LOCAL_DOM_ADDR},
+};
+
+/// The number of local addresses that may be tracked as peers.
+///
+/// This covers the dom (address 0), and the 32 sub addresses
+/// handed out by discovery.
+pub const MAX_PEERS: usize = 33;
+
+/// The number of sending ports tracked per peer.
+///
+/// Each sending port has its own counter, so replays are tracked per
+/// (address, port) pair. Messages from any further ports of a peer are
+/// rejected, until `reset_peer()` is called.
+pub const MAX_PEER_PORTS: usize = 4;
+
+pub const NODE_ID_LEN: usize = 8;
+pub const EPOCH_LEN: usize = 4;
+pub const COUNTER_LEN: usize = 4;
+pub const TAG_LEN: usize = 16;
+
+/// The number of bytes a sealed payload adds to the plaintext
+pub const SEALED_OVERHEAD: usize = NODE_ID_LEN + EPOCH_LEN + COUNTER_LEN + TAG_LEN;
+
+// Two `AddrPort`s, each with up to 8 address bytes, a length, and a
+// varint port.
+const MAX_AAD_LEN: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SecurityError {
+    /// There is no key for the given peer
+    NoKey,
+    /// The port has not been started with `PortSecurity::start()`
+    NotStarted,
+    /// The send counter for this epoch has been used up
+    CounterExhausted,
+    /// The message is not addressed from/to a single local address
+    BadAddr,
+    /// The output buffer is too small
+    NoRoom,
+    /// The message is too short to be a sealed payload
+    Truncated,
+    /// The message failed authentication
+    Auth,
+    /// The message has already been seen (or is older than one that has)
+    Replay,
+    /// The peer sends from more than `MAX_PEER_PORTS` ports
+    TooManyPorts,
+}
+
+/// The keys used by a secured port
+pub enum SecurityKeys {
+    /// One key, shared by every node on the network
+    Network([u8; 32]),
+
+    /// One key per node pair, indexed by the local address of the peer.
+    ///
+    /// Addresses handed out by discovery may belong to a different node at
+    /// any time, so keys are only used for the dom (index 0), and for subs
+    /// in the static range of `statics`. A sub will generally only need an
+    /// entry for the dom.
+    PerPeer {
+        keys: &'static [Option<[u8; 32]>],
+        statics: &'static AddrTable32,
+    },
+}
+
+impl SecurityKeys {
+    fn key_for(&self, peer: u8) -> Option<&[u8; 32]> {
+        match self {
+            SecurityKeys::Network(key) => Some(key),
+            SecurityKeys::PerPeer { keys, statics } => {
+                if peer != LOCAL_DOM_ADDR && !statics.is_static(peer) {
+                    return None;
+                }
+                keys.get(peer as usize)?.as_ref()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SendState {
+    node_id: u64,
+    epoch: u32,
+    counter: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct SeenState {
+    epoch: u32,
+    counter: u32,
+}
+
+const UNSEEN: SeenState = SeenState {
+    epoch: 0,
+    counter: 0,
+};
+
+/// The newest message seen from one port of a peer
+#[derive(Clone, Copy)]
+struct SeenPort {
+    port: u16,
+    last: SeenState,
+}
+
+type PeerPorts = Vec<SeenPort, MAX_PEER_PORTS>;
+
+/// Security state for a single port
+///
+/// Nonces are made of the sender's node id, epoch, port, and counter. As a
+/// nonce must NEVER be reused with the same key, the node id given to
+/// `start()` MUST be unique to this node (e.g. a unique device id, or a random
+/// number drawn at boot), and the epoch MUST be larger on every boot (e.g. a
+/// boot sequence number kept in flash). Local addresses are NOT part of the
+/// nonce, as discovery hands the same address to different nodes over time.
+///
+/// Receivers track the highest (epoch, counter) pair seen from each port of
+/// each peer address, and reject anything that is not newer. The dispatcher
+/// forgets a peer with `reset_peer()` whenever its address is handed out
+/// again (see `Dispatch::reset_peer()`).
+pub struct PortSecurity {
+    keys: SecurityKeys,
+    send: spin::Mutex<Option<SendState>>,
+    seen: spin::Mutex<[PeerPorts; MAX_PEERS]>,
+}
+
+impl PortSecurity {
+    pub const fn new(keys: SecurityKeys) -> Self {
+        const NO_PORTS: PeerPorts = Vec::new();
+
+        Self {
+            keys,
+            send: spin::Mutex::new(None),
+            seen: spin::Mutex::new([NO_PORTS; MAX_PEERS]),
+        }
+    }
+
+    /// Allow sending, using the given node id and epoch.
+    ///
+    /// The node id MUST NOT be used by any other node sharing a key, and the
+    /// epoch MUST be greater than any epoch used on a previous boot.
+    pub fn start(&self, epoch: u32, node_id: u64) {
+        *self.send.lock() = Some(SendState {
+            node_id,
+            epoch,
+            counter: 0,
+        });
+    }
+
+    /// Forget the replay state of a peer, e.g. after it has rebooted
+    pub fn reset_peer(&self, peer: u8) {
+        if let Some(ports) = self.seen.lock().get_mut(peer as usize) {
+            ports.clear();
+        }
+    }
+
+    /// Seal `plain` into `out`, returning the number of bytes used.
+    ///
+    /// `hdr` must already contain the final source address and port.
+    pub(crate) fn seal(
+        &self,
+        hdr: &LineHeader,
+        plain: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, SecurityError> {
+        let dst = hdr
+            .dst
+            .addr
+            .get_exact_local_addr()
+            .ok_or(SecurityError::BadAddr)?;
+        let key = self.key_for_dst(dst)?;
+
+        let total = SEALED_OVERHEAD + plain.len();
+        if out.len() < total {
+            return Err(SecurityError::NoRoom);
+        }
+
+        let (node_id, epoch, counter) = {
+            let mut send = self.send.lock();
+            let state = send.as_mut().ok_or(SecurityError::NotStarted)?;
+            state.counter = state
+                .counter
+                .checked_add(1)
+                .ok_or(SecurityError::CounterExhausted)?;
+            (state.node_id, state.epoch, state.counter)
+        };
+
+        let mut aad_buf = [0u8; MAX_AAD_LEN];
+        let aad = to_slice(hdr, &mut aad_buf).map_err(|_| SecurityError::BadAddr)?;
+        let nonce = make_nonce(node_id, epoch, hdr.src.port, counter);
+
+        let (head, body) = out.split_at_mut(SEALED_OVERHEAD);
+        let body = &mut body[..plain.len()];
+        body.copy_from_slice(plain);
+
+        let tag = XChaCha20Poly1305::new(&Key::from(*key))
+            .encrypt_in_place_detached(&nonce, aad, body)
+            .map_err(|_| SecurityError::NoRoom)?;
+
+        head[..8].copy_from_slice(&node_id.to_le_bytes());
+        head[8..12].copy_from_slice(&epoch.to_le_bytes());
+        head[12..16].copy_from_slice(&counter.to_le_bytes());
+        head[16..].copy_from_slice(&tag);
+
+        Ok(total)
+    }
+
+    /// Authenticate and decrypt `sealed` into `out`, returning the number of
+    /// plaintext bytes.
+    ///
+    /// The replay state of the sender is only updated if the message is authentic.
+    pub(crate) fn open(
+        &self,
+        hdr: &LineHeader,
+        sealed: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, SecurityError> {
+        let src = hdr
+            .src
+            .addr
+            .get_exact_local_addr()
+            .ok_or(SecurityError::BadAddr)?;
+        let key = self.keys.key_for(src).ok_or(SecurityError::NoKey)?;
+
+        if sealed.len() < SEALED_OVERHEAD {
+            return Err(SecurityError::Truncated);
+        }
+        let (head, body) = sealed.split_at(SEALED_OVERHEAD);
+        if out.len() < body.len() {
+            return Err(SecurityError::NoRoom);
+        }
+
+        let mut dword = [0u8; 8];
+        dword.copy_from_slice(&head[..8]);
+        let node_id = u64::from_le_bytes(dword);
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&head[8..12]);
+        let epoch = u32::from_le_bytes(word);
+        word.copy_from_slice(&head[12..16]);
+        let counter = u32::from_le_bytes(word);
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&head[16..]);
+        let tag = Tag::from(tag);
+
+        let incoming = SeenState { epoch, counter };
+
+        // Cheap early rejection, checked again below once authenticated
+        self.check_fresh(src, hdr.src.port, incoming)?;
+
+        let mut aad_buf = [0u8; MAX_AAD_LEN];
+        let aad = to_slice(hdr, &mut aad_buf).map_err(|_| SecurityError::BadAddr)?;
+        let nonce = make_nonce(node_id, epoch, hdr.src.port, counter);
+
+        let plain = &mut out[..body.len()];
+        plain.copy_from_slice(body);
+
+        XChaCha20Poly1305::new(&Key::from(*key))
+            .decrypt_in_place_detached(&nonce, aad, plain, &tag)
+            .map_err(|_| SecurityError::Auth)?;
+
+        let mut seen = self.seen.lock();
+        let ports = seen.get_mut(src as usize).ok_or(SecurityError::BadAddr)?;
+        match ports.iter_mut().find(|seen| seen.port == hdr.src.port) {
+            Some(seen) if incoming <= seen.last => return Err(SecurityError::Replay),
+            Some(seen) => seen.last = incoming,
+            None if incoming <= UNSEEN => return Err(SecurityError::Replay),
+            None => ports
+                .push(SeenPort {
+                    port: hdr.src.port,
+                    last: incoming,
+                })
+                .map_err(|_| SecurityError::TooManyPorts)?,
+        }
+
+        Ok(body.len())
+    }
+
+    fn key_for_dst(&self, dst: u8) -> Result<&[u8; 32], SecurityError> {
+        match (&self.keys, dst) {
+            // Only a network key can be used to reach everyone
+            (SecurityKeys::PerPeer { .. }, LOCAL_BROADCAST_ADDR) => Err(SecurityError::NoKey),
+            (keys, dst) => keys.key_for(dst).ok_or(SecurityError::NoKey),
+        }
+    }
+
+    fn check_fresh(&self, src: u8, port: u16, incoming: SeenState) -> Result<(), SecurityError> {
+        let seen = self.seen.lock();
+        let ports = seen.get(src as usize).ok_or(SecurityError::BadAddr)?;
+        let last = match ports.iter().find(|seen| seen.port == port) {
+            Some(seen) => seen.last,
+            None if ports.is_full() => return Err(SecurityError::TooManyPorts),
+            None => UNSEEN,
+        };
+
+        if incoming <= last {
+            Err(SecurityError::Replay)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn make_nonce(node_id: u64, epoch: u32, port: u16, counter: u32) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[0..8].copy_from_slice(&node_id.to_le_bytes());
+    nonce[8..12].copy_from_slice(&epoch.to_le_bytes());
+    nonce[12..14].copy_from_slice(&port.to_le_bytes());
+    // nonce[14..20]: reserved
+    nonce[20..24].copy_from_slice(&counter.to_le_bytes());
+    XNonce::from(nonce)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::icd::{AddrPort, VecAddr};
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn header(src: u8, src_port: u16, dst: u8) -> LineHeader {
+        LineHeader {
+            src: AddrPort::from_parts(VecAddr::from_local_addr(src), src_port),
+            dst: AddrPort::from_parts(VecAddr::from_local_addr(dst), 10),
+            tick: None,
+        }
+    }
+
+    fn pair() -> (PortSecurity, PortSecurity) {
+        let tx = PortSecurity::new(SecurityKeys::Network(KEY));
+        let rx = PortSecurity::new(SecurityKeys::Network(KEY));
+        tx.start(1, 0x1234);
+        (tx, rx)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (tx, rx) = pair();
+        let hdr = header(1, 20, 0);
+
+        let mut sealed = [0u8; 64];
+        let len = tx.seal(&hdr, b"hello", &mut sealed).unwrap();
+        assert_eq!(len, SEALED_OVERHEAD + 5);
+        assert_ne!(&sealed[SEALED_OVERHEAD..len], b"hello");
+
+        let mut plain = [0u8; 64];
+        let used = rx.open(&hdr, &sealed[..len], &mut plain).unwrap();
+        assert_eq!(&plain[..used], b"hello");
+
+        // The header is authenticated too
+        let mut moved = header(1, 20, 0);
+        moved.dst.port = 11;
+        let len = tx.seal(&hdr, b"hello", &mut sealed).unwrap();
+        assert_eq!(
+            rx.open(&moved, &sealed[..len], &mut plain),
+            Err(SecurityError::Auth)
+        );
+    }
+
+    #[test]
+    fn tampered_tag() {
+        let (tx, rx) = pair();
+        let hdr = header(1, 20, 0);
+
+        let mut sealed = [0u8; 64];
+        let len = tx.seal(&hdr, b"hello", &mut sealed).unwrap();
+
+        let mut tampered = sealed;
+        tampered[NODE_ID_LEN + EPOCH_LEN + COUNTER_LEN] ^= 0x01;
+        let mut plain = [0u8; 64];
+        assert_eq!(
+            rx.open(&hdr, &tampered[..len], &mut plain),
+            Err(SecurityError::Auth)
+        );
+
+        // A failed message doesn't count as seen
+        assert!(rx.open(&hdr, &sealed[..len], &mut plain).is_ok());
+    }
+
+    #[test]
+    fn replayed_counter() {
+        let (tx, rx) = pair();
+        let hdr = header(1, 20, 0);
+        let mut plain = [0u8; 64];
+
+        let mut first = [0u8; 64];
+        let first_len = tx.seal(&hdr, b"one", &mut first).unwrap();
+        let mut second = [0u8; 64];
+        let second_len = tx.seal(&hdr, b"two", &mut second).unwrap();
+
+        assert!(rx.open(&hdr, &second[..second_len], &mut plain).is_ok());
+        assert_eq!(
+            rx.open(&hdr, &second[..second_len], &mut plain),
+            Err(SecurityError::Replay)
+        );
+        assert_eq!(
+            rx.open(&hdr, &first[..first_len], &mut plain),
+            Err(SecurityError::Replay)
+        );
+
+        // Other ports of the same peer count on their own
+        for port in 21..(20 + MAX_PEER_PORTS as u16) {
+            let other = PortSecurity::new(SecurityKeys::Network(KEY));
+            other.start(1, 0x5678);
+            let hdr = header(1, port, 0);
+            let mut sealed = [0u8; 64];
+            let len = other.seal(&hdr, b"one", &mut sealed).unwrap();
+            assert!(rx.open(&hdr, &sealed[..len], &mut plain).is_ok());
+        }
+
+        let hdr = header(1, 99, 0);
+        let mut sealed = [0u8; 64];
+        let len = tx.seal(&hdr, b"one", &mut sealed).unwrap();
+        assert_eq!(
+            rx.open(&hdr, &sealed[..len], &mut plain),
+            Err(SecurityError::TooManyPorts)
+        );
+
+        // Until the peer is forgotten
+        rx.reset_peer(1);
+        assert!(rx.open(&hdr, &sealed[..len], &mut plain).is_ok());
+        assert!(rx
+            .open(&header(1, 20, 0), &first[..first_len], &mut plain)
+            .is_ok());
+    }
+
+    #[test]
+    fn reused_addr() {
+        // Two nodes that boot with the same epoch, and are handed the same
+        // address by discovery, must not share a nonce
+        let (first, rx) = pair();
+        let second = PortSecurity::new(SecurityKeys::Network(KEY));
+        second.start(1, 0x5678);
+        let hdr = header(1, 20, 0);
+
+        let mut one = [0u8; 64];
+        let len = first.seal(&hdr, b"hello", &mut one).unwrap();
+        let mut two = [0u8; 64];
+        assert_eq!(second.seal(&hdr, b"hello", &mut two), Ok(len));
+        assert_ne!(&one[SEALED_OVERHEAD..len], &two[SEALED_OVERHEAD..len]);
+
+        // The node id is part of the nonce, it can't be changed in transit
+        let mut plain = [0u8; 64];
+        let mut moved = one;
+        moved[..NODE_ID_LEN].copy_from_slice(&two[..NODE_ID_LEN]);
+        assert_eq!(
+            rx.open(&hdr, &moved[..len], &mut plain),
+            Err(SecurityError::Auth)
+        );
+
+        // Once the address has been handed out again, the new node starts
+        // over
+        assert!(rx.open(&hdr, &one[..len], &mut plain).is_ok());
+        assert_eq!(
+            rx.open(&hdr, &two[..len], &mut plain),
+            Err(SecurityError::Replay)
+        );
+        rx.reset_peer(1);
+        assert!(rx.open(&hdr, &two[..len], &mut plain).is_ok());
+    }
+
+    #[test]
+    fn per_peer_static_only() {
+        static KEYS: [Option<[u8; 32]>; 3] = [Some([1; 32]), Some([2; 32]), Some([3; 32])];
+        static TABLE: AddrTable32 = AddrTable32::with_static_range(1, 1);
+
+        let dom = PortSecurity::new(SecurityKeys::PerPeer {
+            keys: &KEYS,
+            statics: &TABLE,
+        });
+        dom.start(1, 0x1234);
+        let mut sealed = [0u8; 64];
+
+        // Static subs, and the dom, have keys
+        assert!(dom.seal(&header(0, 20, 1), b"hi", &mut sealed).is_ok());
+
+        // Dynamic addresses don't, even if one is configured
+        assert_eq!(
+            dom.seal(&header(0, 20, 2), b"hi", &mut sealed),
+            Err(SecurityError::NoKey)
+        );
+        let mut plain = [0u8; 64];
+        assert_eq!(
+            dom.open(&header(2, 20, 0), &sealed, &mut plain),
+            Err(SecurityError::NoKey)
+        );
+        assert_eq!(
+            dom.seal(&header(0, 20, LOCAL_BROADCAST_ADDR), b"hi", &mut sealed),
+            Err(SecurityError::NoKey)
+        );
+    }
+
+    #[test]
+    fn wrong_epoch() {
+        let (tx, rx) = pair();
+        let hdr = header(1, 20, 0);
+        let mut plain = [0u8; 64];
+
+        // The epoch is part of the nonce, it can't be changed in transit
+        let mut sealed = [0u8; 64];
+        let len = tx.seal(&hdr, b"hello", &mut sealed).unwrap();
+        let mut bumped = sealed;
+        bumped[NODE_ID_LEN..][..EPOCH_LEN].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(
+            rx.open(&hdr, &bumped[..len], &mut plain),
+            Err(SecurityError::Auth)
+        );
+
+        // A reboot must use a larger epoch, even if the counter starts over
+        tx.start(5, 0x1234);
+        let len = tx.seal(&hdr, b"hello", &mut sealed).unwrap();
+        assert!(rx.open(&hdr, &sealed[..len], &mut plain).is_ok());
+
+        tx.start(4, 0x1234);
+        let len = tx.seal(&hdr, b"hello", &mut sealed).unwrap();
+        assert_eq!(
+            rx.open(&hdr, &sealed[..len], &mut plain),
+            Err(SecurityError::Replay)
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use core::marker::PhantomData;
 
-use byte_slab::BSlab;
+use byte_slab::SlabHandle;
 use cassette::yield_now;
 use groundhog::RollingTimer;
 use rand::Rng;
@@ -8,33 +8,37 @@ use rand::Rng;
 use crate::{
     async_sleep_micros,
     dispatch::{Dispatch, DispatchSocket, LocalPacket, INVALID_OWN_ADDR},
-    icd::{DomDiscoveryPayload, SubDiscoveryPayload, SLAB_SIZE, TOTAL_SLABS},
+    icd::{DomDiscoveryPayload, SubDiscoveryPayload, LOCAL_DOM_ADDR},
     receive_timeout_micros,
-    timing::{SUB_BROADACKACK_WAIT_US, SUB_INITIAL_DISCO_WAIT_US, SUB_PING_WAIT_US},
+    timing::BusTiming,
 };
 
-pub struct Discovery<R, A>
+pub struct Discovery<'a, R, A, const PORTS: usize, const N: usize, const SZ: usize>
 where
     R: RollingTimer<Tick = u32> + Default,
     A: Rng,
 {
     _timer: PhantomData<R>,
-    dispatch: &'static Dispatch<8>,
-    socket: DispatchSocket<'static>,
+    dispatch: &'a Dispatch<'a, PORTS, N, SZ>,
+    socket: DispatchSocket<'a, N, SZ>,
     rand: A,
-    alloc: &'static BSlab<TOTAL_SLABS, SLAB_SIZE>,
+    alloc: SlabHandle<N, SZ>,
+    timing: BusTiming,
+    static_addr: Option<u8>,
 }
 
-impl<R, A> Discovery<R, A>
+impl<'a, R, A, const PORTS: usize, const N: usize, const SZ: usize>
+    Discovery<'a, R, A, PORTS, N, SZ>
 where
     R: RollingTimer<Tick = u32> + Default,
     A: Rng,
 {
     pub fn new(
         rand: A,
-        dispatch: &'static Dispatch<8>,
-        socket: DispatchSocket<'static>,
-        alloc: &'static BSlab<TOTAL_SLABS, SLAB_SIZE>,
+        dispatch: &'a Dispatch<'a, PORTS, N, SZ>,
+        socket: DispatchSocket<'a, N, SZ>,
+        alloc: SlabHandle<N, SZ>,
+        timing: BusTiming,
     ) -> Self {
         Self {
             _timer: PhantomData,
@@ -42,10 +46,32 @@ where
             socket,
             alloc,
             dispatch,
+            timing,
+            static_addr: None,
+        }
+    }
+
+    /// Create a sub that always uses `addr`, and never takes part in
+    /// discovery. `addr` must be within the dom's static range.
+    pub fn new_static(
+        addr: u8,
+        rand: A,
+        dispatch: &'a Dispatch<'a, PORTS, N, SZ>,
+        socket: DispatchSocket<'a, N, SZ>,
+        alloc: SlabHandle<N, SZ>,
+        timing: BusTiming,
+    ) -> Self {
+        Self {
+            static_addr: Some(addr),
+            ..Self::new(rand, dispatch, socket, alloc, timing)
         }
     }
 
     pub async fn obtain_addr(&mut self) -> Result<(), ()> {
+        if let Some(addr) = self.static_addr {
+            return self.hold_static_addr(addr).await;
+        }
+
         loop {
             if let Some(_) = self.dispatch.get_addr() {
                 yield_now().await;
@@ -55,19 +81,40 @@ where
         }
     }
 
+    async fn hold_static_addr(&mut self, addr: u8) -> Result<(), ()> {
+        // The dom only hands out (and keeps) addresses 1..=32
+        if !(1..=32).contains(&addr) {
+            return Err(());
+        }
+
+        defmt::info!("Using static addr {=u8}", addr);
+
+        loop {
+            // Management keeps a static address when asked to rediscover,
+            // but don't let anyone else take it from us either
+            if self.dispatch.get_addr() != Some(addr) {
+                self.dispatch.set_static_addr(addr);
+            }
+
+            // Discovery isn't for us, but don't let it pile up
+            while self.socket.try_recv().is_some() {}
+            yield_now().await;
+        }
+    }
+
     pub async fn obtain_addr_inner(&mut self) -> Result<Option<u8>, ()> {
         defmt::info!("Sub start discovery...");
         let timer = R::default();
 
         self.socket.auth_flush().ok();
-        async_sleep_micros::<R>(timer.get_ticks(), 2_000).await;
+        async_sleep_micros::<R>(timer.get_ticks(), self.timing.sub_send_poll_us).await;
 
         self.dispatch.set_addr(INVALID_OWN_ADDR);
 
-        let msg = match receive_timeout_micros::<R, DomDiscoveryPayload>(
+        let msg = match receive_timeout_micros::<R, DomDiscoveryPayload, N, SZ>(
             &mut self.socket,
             timer.get_ticks(),
-            SUB_INITIAL_DISCO_WAIT_US,
+            self.timing.sub_initial_disco_wait_us,
         )
         .await
         {
@@ -106,7 +153,7 @@ where
             resp.hdr.src,
             resp.hdr.dst,
             None,
-            self.alloc,
+            &self.alloc,
         )
         .ok_or(())?;
         self.socket.try_send_authd(msg).map_err(drop)?;
@@ -116,10 +163,10 @@ where
 
         let start = timer.get_ticks();
         loop {
-            let msg = match receive_timeout_micros::<R, DomDiscoveryPayload>(
+            let msg = match receive_timeout_micros::<R, DomDiscoveryPayload, N, SZ>(
                 &mut self.socket,
                 start,
-                SUB_BROADACKACK_WAIT_US + remaining_sleep,
+                self.timing.sub_broadackack_wait_us + remaining_sleep,
             )
             .await
             {
@@ -155,10 +202,10 @@ where
         loop {
             defmt::info!("Sub got loop {=u8}...", success_ct);
             let start = timer.get_ticks();
-            let msg = match receive_timeout_micros::<R, DomDiscoveryPayload>(
+            let msg = match receive_timeout_micros::<R, DomDiscoveryPayload, N, SZ>(
                 &mut self.socket,
                 start,
-                SUB_PING_WAIT_US,
+                self.timing.sub_ping_wait_us,
             )
             .await
             {
@@ -180,13 +227,16 @@ where
                     resp.hdr.src,
                     resp.hdr.dst,
                     None,
-                    self.alloc,
+                    &self.alloc,
                 )
                 .ok_or(())?;
                 self.socket.try_send_authd(msg).map_err(drop)?;
 
                 success_ct += 1;
                 if success_ct >= 2 {
+                    // We only re-join after losing the dom, which may have
+                    // been reflashed since
+                    self.dispatch.reset_peer(LOCAL_DOM_ADDR);
                     defmt::info!("Sub got yeyeyeye...");
                     return Ok(Some(addr));
                 }
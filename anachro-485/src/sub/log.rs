@@ -0,0 +1,329 @@
+//! Forwarding of log records from a sub to the dom
+//!
+//! Records are written into a `LogSink`, which packs them back to back into
+//! slab allocations. A `LogForwarder` task periodically ships the filled
+//! allocations to the dom on the `LOG_PORT`. They are queued like any other
+//! outgoing message, and hit the wire the next time the sub holds a token.
+
+use core::{
+    fmt::{Arguments, Write},
+    marker::PhantomData,
+    ops::DerefMut,
+};
+
+use crate::atomic::{AtomicU32, Ordering::SeqCst};
+
+use byte_slab::{ManagedArcSlab, ManagedArcStr, SlabBox, SlabHandle, SlabSliceArc};
+use groundhog::RollingTimer;
+use heapless::{mpmc::MpMcQueue, String};
+use postcard::to_slice;
+
+use crate::{
+    async_sleep_millis,
+    dispatch::{max_frame_payload, Dispatch, DispatchSocket, LocalHeader, LocalPacket},
+    dom::LOG_PORT,
+    icd::{AddrPort, LogLevel, LogRecord, VecAddr},
+};
+
+/// The maximum length of a single log line. Longer lines are truncated.
+pub const MAX_LOG_LEN: usize = 128;
+
+/// The number of filled batches that may wait to be sent
+pub const LOG_QUEUE_DEPTH: usize = 4;
+
+type LogBatch<const N: usize, const SZ: usize> = SlabSliceArc<N, SZ>;
+
+struct PartialBatch<const N: usize, const SZ: usize> {
+    sbox: SlabBox<N, SZ>,
+    used: usize,
+}
+
+/// A buffer of log records, waiting to be sent to the dom
+///
+/// Intended to be placed in a static, so that any task (or interrupt)
+/// may log to it. Batches are capped so they always fit in a single frame.
+/// If the sink is busy, e.g. an interrupt logs while a task is halfway
+/// through writing a record, the record is dropped rather than waiting, and
+/// counted in `dropped()`.
+pub struct LogSink<R, const N: usize, const SZ: usize>
+where
+    R: RollingTimer<Tick = u32> + Default,
+{
+    _timer: PhantomData<R>,
+    alloc: SlabHandle<N, SZ>,
+    current: spin::Mutex<Option<PartialBatch<N, SZ>>>,
+    ready: MpMcQueue<LogBatch<N, SZ>, LOG_QUEUE_DEPTH>,
+    dropped: AtomicU32,
+}
+
+impl<R, const N: usize, const SZ: usize> LogSink<R, N, SZ>
+where
+    R: RollingTimer<Tick = u32> + Default,
+{
+    const BATCH_CAP: usize = max_frame_payload(SZ);
+
+    pub const fn new(alloc: SlabHandle<N, SZ>) -> Self {
+        Self {
+            _timer: PhantomData,
+            alloc,
+            current: spin::Mutex::new(None),
+            ready: MpMcQueue::new(),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Log a formatted message, e.g. `SINK.log(LogLevel::Info, format_args!("x: {}", x))`
+    pub fn log(&self, level: LogLevel, args: Arguments) {
+        let mut text: String<MAX_LOG_LEN> = String::new();
+
+        // On overflow, we keep whatever fit
+        let _ = text.write_fmt(args);
+
+        self.log_str(level, &text);
+    }
+
+    /// Log a pre-formatted message
+    pub fn log_str(&self, level: LogLevel, text: &str) {
+        let record: LogRecord<N, SZ> = LogRecord {
+            tick: R::default().get_ticks(),
+            level,
+            text: ManagedArcStr::Borrowed(text),
+        };
+
+        // Spinning here could deadlock an interrupt against the task it interrupted
+        let mut current = match self.current.try_lock() {
+            Some(current) => current,
+            None => {
+                self.dropped.fetch_add(1, SeqCst);
+                return;
+            }
+        };
+
+        // Try the current batch first, then try once more with a fresh one
+        for _ in 0..2 {
+            let batch = match current.as_mut() {
+                Some(batch) => batch,
+                None => match self.alloc.alloc_box() {
+                    Some(sbox) => current.insert(PartialBatch { sbox, used: 0 }),
+                    None => break,
+                },
+            };
+
+            let used = batch.used;
+            if let Ok(used_now) =
+                to_slice(&record, &mut batch.sbox.deref_mut()[used..Self::BATCH_CAP])
+            {
+                batch.used += used_now.len();
+                return;
+            }
+
+            // Didn't fit! If this was an empty batch, it never will.
+            if used == 0 {
+                break;
+            }
+
+            if let Some(full) = current.take() {
+                self.push_ready(full);
+            }
+        }
+
+        self.dropped.fetch_add(1, SeqCst);
+    }
+
+    /// The number of records (or batches) dropped due to lack of space, or
+    /// because the sink was busy
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(SeqCst)
+    }
+
+    /// Take the next batch to send, including any partially filled one
+    pub fn take_batch(&self) -> Option<LogBatch<N, SZ>> {
+        if let Some(batch) = self.ready.dequeue() {
+            return Some(batch);
+        }
+
+        let mut current = self.current.lock();
+        match current.take()? {
+            // Nothing written yet, hang on to it
+            partial @ PartialBatch { used: 0, .. } => {
+                *current = Some(partial);
+                None
+            }
+            PartialBatch { sbox, used } => sbox.into_arc().sub_slice_arc(0, used).ok(),
+        }
+    }
+
+    fn push_ready(&self, batch: PartialBatch<N, SZ>) {
+        let PartialBatch { sbox, used } = batch;
+        let ok = sbox
+            .into_arc()
+            .sub_slice_arc(0, used)
+            .ok()
+            .and_then(|ssa| self.ready.enqueue(ssa).ok());
+
+        if ok.is_none() {
+            self.dropped.fetch_add(1, SeqCst);
+        }
+    }
+}
+
+/// A task that ships the contents of a `LogSink` to the dom
+pub struct LogForwarder<R, const PORTS: usize, const N: usize, const SZ: usize>
+where
+    R: RollingTimer<Tick = u32> + Default + 'static,
+{
+    dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+    socket: DispatchSocket<'static, N, SZ>,
+    sink: &'static LogSink<R, N, SZ>,
+    pending: Option<LocalPacket<N, SZ>>,
+    interval_ms: u32,
+}
+
+impl<R, const PORTS: usize, const N: usize, const SZ: usize> LogForwarder<R, PORTS, N, SZ>
+where
+    R: RollingTimer<Tick = u32> + Default + 'static,
+{
+    /// Create a new forwarder. `socket` should be registered on the `LOG_PORT`.
+    ///
+    /// Batches are flushed every `interval_ms` milliseconds.
+    pub fn new(
+        dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+        socket: DispatchSocket<'static, N, SZ>,
+        sink: &'static LogSink<R, N, SZ>,
+        interval_ms: u32,
+    ) -> Self {
+        Self {
+            dispatch,
+            socket,
+            sink,
+            pending: None,
+            interval_ms,
+        }
+    }
+
+    pub async fn poll(&mut self) -> ! {
+        let timer = R::default();
+
+        loop {
+            async_sleep_millis::<R>(timer.get_ticks(), self.interval_ms).await;
+            self.poll_inner();
+        }
+    }
+
+    pub fn poll_inner(&mut self) {
+        // We can't send without an address, so let logs pile up until
+        // discovery is done
+        let addr = match self.dispatch.get_addr() {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        loop {
+            let pkt = match self.pending.take() {
+                Some(pkt) => pkt,
+                None => match self.sink.take_batch() {
+                    Some(batch) => LocalPacket::from_hdr_payload(
+                        LocalHeader {
+                            src: AddrPort::from_parts(VecAddr::from_local_addr(addr), LOG_PORT),
+                            dst: AddrPort::from_parts(VecAddr::local_dom_addr(), LOG_PORT),
+                            tick: 0,
+                        },
+                        ManagedArcSlab::Owned(batch),
+                    ),
+                    None => return,
+                },
+            };
+
+            if let Err(e) = self.socket.try_send(pkt) {
+                // Queue full, try again next time
+                self.pending = e.into_packet();
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::icd::{LineHeader, LineMessage, MAX_ADDR_SEGMENTS};
+    use byte_slab::{BSlab, SlabWriter};
+    use groundhog::RollingTimer;
+
+    #[derive(Default)]
+    struct TestTimer;
+
+    impl RollingTimer for TestTimer {
+        type Tick = u32;
+        const TICKS_PER_SECOND: u32 = 1_000;
+
+        fn get_ticks(&self) -> u32 {
+            0
+        }
+
+        fn is_initialized(&self) -> bool {
+            true
+        }
+    }
+
+    static SLAB: BSlab<4, 128> = BSlab::new();
+
+    // A tick, a level, and the length of a short text
+    const RECORD_OVERHEAD: usize = 4 + 1 + 1;
+
+    #[test]
+    fn batch_fills_frame() {
+        SLAB.init().unwrap();
+        let sink: LogSink<TestTimer, 4, 128> = LogSink::new(SLAB.handle());
+        let cap = max_frame_payload(128);
+        let text = [b'x'; MAX_LOG_LEN];
+        let text = core::str::from_utf8(&text).unwrap();
+
+        // Exactly fill a batch with two records, then spill into the next
+        let first = cap / 2;
+        sink.log_str(LogLevel::Info, &text[..first - RECORD_OVERHEAD]);
+        sink.log_str(LogLevel::Info, &text[..cap - first - RECORD_OVERHEAD]);
+        sink.log_str(LogLevel::Info, "");
+        assert_eq!(sink.dropped(), 0);
+
+        let full = sink.take_batch().unwrap();
+        assert_eq!(full.len(), cap);
+        assert_eq!(sink.take_batch().unwrap().len(), RECORD_OVERHEAD);
+
+        // The full batch still fits a frame with the largest header
+        let hdr = LineHeader {
+            src: AddrPort::from_parts(
+                VecAddr::from_addrs(&[0xFF; MAX_ADDR_SEGMENTS]).unwrap(),
+                u16::MAX,
+            ),
+            dst: AddrPort::from_parts(
+                VecAddr::from_addrs(&[0xFF; MAX_ADDR_SEGMENTS]).unwrap(),
+                u16::MAX,
+            ),
+            tick: Some(u32::MAX),
+        };
+        let msg = LineMessage {
+            hdr,
+            msg: ManagedArcSlab::Owned(full),
+        };
+        let sbox = SLAB.alloc_box().unwrap();
+        assert!(SlabWriter::serialize_cobs(&msg, sbox).is_ok());
+    }
+
+    #[test]
+    fn busy_sink_counts_drops() {
+        static BUSY_SLAB: BSlab<2, 128> = BSlab::new();
+        BUSY_SLAB.init().unwrap();
+        let sink: LogSink<TestTimer, 2, 128> = LogSink::new(BUSY_SLAB.handle());
+
+        // As if an interrupt logged while a task was writing a record
+        let held = sink.current.lock();
+        sink.log_str(LogLevel::Info, "dropped");
+        assert_eq!(sink.dropped(), 1);
+        drop(held);
+
+        sink.log_str(LogLevel::Info, "kept");
+        assert_eq!(sink.dropped(), 1);
+        assert!(sink.take_batch().is_some());
+    }
+}
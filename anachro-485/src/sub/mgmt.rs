@@ -0,0 +1,165 @@
+//! Remote control of a sub by the dom
+//!
+//! The `MgmtServer` answers `DomMgmtPayload` commands sent to the
+//! `MANAGEMENT_PORT`. Anything that depends on the hardware, such as
+//! rebooting or blinking an LED, is provided by the application through the
+//! `MgmtHandler` trait.
+
+use core::{marker::PhantomData, ops::Deref};
+
+use byte_slab::SlabHandle;
+use cassette::yield_now;
+use groundhog::RollingTimer;
+use postcard::from_bytes;
+
+use crate::{
+    async_sleep_millis,
+    dispatch::{Dispatch, DispatchSocket, LocalPacket},
+    dom::MANAGEMENT_PORT,
+    icd::{AddrPort, DomMgmtPayload, FirmwareInfo, NodeInfo, SubMgmtPayload, VecAddr},
+};
+
+/// How long to wait after acknowledging a command before acting on it,
+/// to give the acknowledgement a chance to be sent
+const ACT_DELAY_MS: u32 = 500;
+
+/// Hardware specific management actions
+pub trait MgmtHandler {
+    /// Reboot the sub
+    fn reset(&mut self) -> !;
+
+    /// Start making the sub visible to a human, for about `duration_ms`.
+    /// This should not block.
+    fn identify(&mut self, duration_ms: u32);
+
+    fn firmware(&self) -> FirmwareInfo;
+}
+
+pub struct MgmtServer<R, H, const PORTS: usize, const N: usize, const SZ: usize>
+where
+    R: RollingTimer<Tick = u32> + Default,
+    H: MgmtHandler,
+{
+    _timer: PhantomData<R>,
+    dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+    socket: DispatchSocket<'static, N, SZ>,
+    alloc: SlabHandle<N, SZ>,
+    handler: H,
+    uptime_s: u32,
+    last_second: u32,
+}
+
+impl<R, H, const PORTS: usize, const N: usize, const SZ: usize> MgmtServer<R, H, PORTS, N, SZ>
+where
+    R: RollingTimer<Tick = u32> + Default,
+    H: MgmtHandler,
+{
+    /// Create a new server. `socket` should be registered on the `MANAGEMENT_PORT`.
+    pub fn new(
+        dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+        socket: DispatchSocket<'static, N, SZ>,
+        alloc: SlabHandle<N, SZ>,
+        handler: H,
+    ) -> Self {
+        Self {
+            _timer: PhantomData,
+            dispatch,
+            socket,
+            alloc,
+            handler,
+            uptime_s: 0,
+            last_second: R::default().get_ticks(),
+        }
+    }
+
+    pub async fn poll(&mut self) -> ! {
+        loop {
+            self.tick_uptime();
+
+            let msg = match self.socket.try_recv() {
+                Some(msg) => msg,
+                None => {
+                    yield_now().await;
+                    continue;
+                }
+            };
+
+            // Only the dom may manage us
+            if msg.hdr.src.addr != VecAddr::local_dom_addr() {
+                continue;
+            }
+
+            let cmd = match from_bytes::<DomMgmtPayload>(msg.payload.deref()) {
+                Ok(cmd) => cmd,
+                Err(_) => {
+                    defmt::warn!("Bad management message!");
+                    continue;
+                }
+            };
+
+            let reply = match cmd {
+                DomMgmtPayload::Identify { duration_ms } => {
+                    self.handler.identify(duration_ms);
+                    SubMgmtPayload::Ack
+                }
+                DomMgmtPayload::GetStats => SubMgmtPayload::Stats(self.dispatch.stats()),
+                DomMgmtPayload::GetInfo => SubMgmtPayload::Info(NodeInfo {
+                    uptime_s: self.uptime_s,
+                    firmware: self.handler.firmware(),
+                }),
+                DomMgmtPayload::Reset | DomMgmtPayload::Rediscover => SubMgmtPayload::Ack,
+            };
+
+            if self.reply(reply).is_err() {
+                defmt::warn!("Failed to send management reply!");
+            }
+
+            match cmd {
+                DomMgmtPayload::Reset => {
+                    defmt::info!("Resetting, by request");
+                    self.wait_for_reply().await;
+                    self.handler.reset();
+                }
+                DomMgmtPayload::Rediscover => {
+                    self.wait_for_reply().await;
+
+                    // Discovery notices we have no address, and starts over
+                    if self.dispatch.release_addr() {
+                        defmt::info!("Rediscovering, by request");
+                    } else {
+                        defmt::info!("Keeping static address");
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn tick_uptime(&mut self) {
+        let timer = R::default();
+        while timer.seconds_since(self.last_second) >= 1 {
+            self.uptime_s = self.uptime_s.wrapping_add(1);
+            self.last_second = self.last_second.wrapping_add(R::TICKS_PER_SECOND);
+        }
+    }
+
+    async fn wait_for_reply(&mut self) {
+        let timer = R::default();
+        async_sleep_millis::<R>(timer.get_ticks(), ACT_DELAY_MS).await;
+    }
+
+    fn reply(&mut self, reply: SubMgmtPayload) -> Result<(), ()> {
+        let addr = self.dispatch.get_addr().ok_or(())?;
+
+        let msg = LocalPacket::from_parts_with_alloc(
+            reply,
+            AddrPort::from_parts(VecAddr::from_local_addr(addr), MANAGEMENT_PORT),
+            AddrPort::from_parts(VecAddr::local_dom_addr(), MANAGEMENT_PORT),
+            None,
+            &self.alloc,
+        )
+        .ok_or(())?;
+
+        self.socket.try_send(msg).map_err(drop)
+    }
+}
@@ -1,2 +1,5 @@
 pub mod discover;
+pub mod log;
+pub mod mgmt;
+pub mod ota;
 pub mod token;
@@ -0,0 +1,439 @@
+//! Receiving firmware updates over the bus
+//!
+//! The dom sends a `boot-chonker` image in chunks, either directly to this
+//! sub, or broadcast to many subs at once. Each chunk is checked against its
+//! own Poly1305 tag before being written, and may arrive in any order. Once
+//! all chunks have arrived, the whole image is read back and checked against
+//! the image tag before the metadata is written. After that, the sub reboots,
+//! and the bootloader takes over.
+//!
+//! Flash access is provided by the application, through the `OtaStorage`
+//! trait. When using `anachro-boot`, this should write to the section
+//! pointed to by `Bootdata::nxt_image` and `Bootdata::nxt_metadata`, and
+//! the fields of `OtaMetadata` map one to one onto its `Metadata`.
+
+use core::{marker::PhantomData, ops::Deref};
+
+use byte_slab::SlabHandle;
+use cassette::yield_now;
+use groundhog::RollingTimer;
+use poly1305::{
+    universal_hash::{NewUniversalHash, UniversalHash},
+    Block, Key, Poly1305,
+};
+use postcard::from_bytes;
+
+use crate::{
+    async_sleep_millis,
+    dispatch::{Dispatch, DispatchSocket, LocalPacket},
+    dom::OTA_PORT,
+    icd::{
+        AddrPort, ChunkMap, DomOtaPayload, OtaFailure, OtaImageInfo, SubOtaPayload, VecAddr,
+        LOCAL_BROADCAST_ADDR, OTA_CHUNK_SIZE, OTA_MAX_CHUNKS, OTA_MISSING_WINDOW, OTA_TAG_SIZE,
+    },
+};
+
+/// How long to wait after acknowledging a finished image before
+/// rebooting, to give the acknowledgement a chance to be sent
+const REBOOT_DELAY_MS: u32 = 500;
+
+/// Storage for an incoming firmware image
+pub trait OtaStorage {
+    type Error;
+
+    /// The size of an erasable page, in bytes. Must be a multiple
+    /// of `OTA_CHUNK_SIZE`.
+    const PAGE_SIZE: usize;
+
+    /// The total space available for the image, in bytes
+    fn capacity(&self) -> usize;
+
+    /// The `boot_seq_number` of the running image's metadata
+    fn boot_seq(&self) -> u32;
+
+    /// Erase the given page of the image area
+    fn erase_page(&mut self, page: usize) -> Result<(), Self::Error>;
+
+    /// Write a chunk at the given byte offset of the image area.
+    /// The containing page has already been erased.
+    fn write_chunk(
+        &mut self,
+        offset: usize,
+        data: &[u8; OTA_CHUNK_SIZE],
+    ) -> Result<(), Self::Error>;
+
+    /// Read back a previously written chunk at the given byte offset
+    fn read_chunk(&self, offset: usize, data: &mut [u8; OTA_CHUNK_SIZE])
+        -> Result<(), Self::Error>;
+
+    /// Write the metadata of a completely received and checked image
+    fn write_metadata(&mut self, meta: &OtaMetadata) -> Result<(), Self::Error>;
+
+    /// Reboot into the bootloader
+    fn reboot(&mut self) -> !;
+}
+
+/// The metadata of a received image, with the same fields as `anachro-boot`'s
+/// `Metadata`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtaMetadata {
+    pub image_uuid: [u8; 16],
+
+    /// The Poly1305 tag over all pages of the image. `anachro-boot` checks
+    /// whole pages, so this only matches if the image fills its last page,
+    /// as images from `boot-chonker` do.
+    pub image_poly1305_tag: [u8; OTA_TAG_SIZE],
+
+    /// The size of the image, in pages of `OtaStorage::PAGE_SIZE`
+    pub image_len_pages: usize,
+
+    /// One more than the running image's, so the bootloader prefers the
+    /// new image
+    pub boot_seq_number: u32,
+}
+
+impl OtaMetadata {
+    fn new(info: &OtaImageInfo, page_size: usize, running_seq: u32) -> Self {
+        let image_len = info.total_chunks as usize * OTA_CHUNK_SIZE;
+
+        Self {
+            image_uuid: info.uuid,
+            image_poly1305_tag: info.image_tag,
+            image_len_pages: image_len.div_ceil(page_size),
+            boot_seq_number: running_seq.wrapping_add(1),
+        }
+    }
+}
+
+/// Why a reply could not be sent
+#[derive(Debug, PartialEq)]
+enum ReplyError {
+    /// We don't have an address on the bus yet
+    NoAddr,
+    /// No slab was available to hold the reply
+    NoAlloc,
+    /// The reply could not be handed to the `Dispatch`
+    Send,
+}
+
+struct Receiving {
+    info: OtaImageInfo,
+    received: ChunkMap,
+}
+
+pub struct OtaClient<R, S, const PORTS: usize, const N: usize, const SZ: usize>
+where
+    R: RollingTimer<Tick = u32> + Default,
+    S: OtaStorage,
+{
+    _timer: PhantomData<R>,
+    dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+    socket: DispatchSocket<'static, N, SZ>,
+    alloc: SlabHandle<N, SZ>,
+    storage: S,
+    key: &'static [u8; 32],
+    state: Option<Receiving>,
+    failure: Option<OtaFailure>,
+}
+
+impl<R, S, const PORTS: usize, const N: usize, const SZ: usize> OtaClient<R, S, PORTS, N, SZ>
+where
+    R: RollingTimer<Tick = u32> + Default,
+    S: OtaStorage,
+{
+    /// Create a new client. `socket` should be registered on the `OTA_PORT`, and
+    /// `key` must match the key used by `boot-chonker` to sign the image.
+    pub fn new(
+        dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+        socket: DispatchSocket<'static, N, SZ>,
+        alloc: SlabHandle<N, SZ>,
+        storage: S,
+        key: &'static [u8; 32],
+    ) -> Self {
+        Self {
+            _timer: PhantomData,
+            dispatch,
+            socket,
+            alloc,
+            storage,
+            key,
+            state: None,
+            failure: None,
+        }
+    }
+
+    pub async fn poll(&mut self) -> ! {
+        loop {
+            let msg = match self.socket.try_recv() {
+                Some(msg) => msg,
+                None => {
+                    yield_now().await;
+                    continue;
+                }
+            };
+
+            let body = match from_bytes::<DomOtaPayload<N, SZ>>(msg.payload.deref()) {
+                Ok(body) => body,
+                Err(_) => {
+                    defmt::warn!("Bad OTA message!");
+                    continue;
+                }
+            };
+
+            // Broadcast messages are never answered, otherwise every
+            // sub would try to reply at once
+            let broadcast = msg.hdr.dst.addr.get_exact_local_addr() == Some(LOCAL_BROADCAST_ADDR);
+
+            let reply = match self.handle(body) {
+                Ok(reply) => reply,
+                Err(fail) => {
+                    // Remember why an update in progress failed, for `Status`
+                    if self.state.take().is_some() {
+                        self.failure = Some(fail);
+                    }
+                    Some(SubOtaPayload::Failed(fail))
+                }
+            };
+
+            let reply = match reply {
+                Some(reply) if !broadcast => reply,
+                _ => continue,
+            };
+
+            let finished = matches!(reply, SubOtaPayload::Finished);
+
+            match self.reply(reply) {
+                Ok(()) => {}
+                Err(ReplyError::NoAddr) => defmt::warn!("No address for OTA reply!"),
+                Err(_) => defmt::warn!("Failed to send OTA reply!"),
+            }
+
+            if finished {
+                let timer = R::default();
+                async_sleep_millis::<R>(timer.get_ticks(), REBOOT_DELAY_MS).await;
+                self.storage.reboot();
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        msg: DomOtaPayload<'_, N, SZ>,
+    ) -> Result<Option<SubOtaPayload>, OtaFailure> {
+        match msg {
+            DomOtaPayload::Start(info) => {
+                let total = info.total_chunks as usize;
+                if total > OTA_MAX_CHUNKS || total * OTA_CHUNK_SIZE > self.storage.capacity() {
+                    return Err(OtaFailure::TooLarge);
+                }
+
+                defmt::info!("Starting OTA, {=u32} chunks", info.total_chunks);
+                self.failure = None;
+                self.state = Some(Receiving {
+                    info,
+                    received: ChunkMap::new(),
+                });
+
+                Ok(Some(SubOtaPayload::Next { idx: 0 }))
+            }
+            DomOtaPayload::Chunk { idx, tag, data } => {
+                let state = self.state.as_mut().ok_or(OtaFailure::NotStarted)?;
+                let total = state.info.total_chunks;
+
+                // Repeated, out of range, or corrupted chunks are ignored
+                if idx < total && !state.received.get(idx) && data.len() == OTA_CHUNK_SIZE {
+                    let mut chunk = [0u8; OTA_CHUNK_SIZE];
+                    chunk.copy_from_slice(&data);
+
+                    if chunk_tag(self.key, &chunk)
+                        .verify(&Block::from(tag))
+                        .is_ok()
+                    {
+                        store_chunk(&mut self.storage, &mut state.received, idx, &chunk)?;
+                    } else {
+                        defmt::warn!("Bad tag on chunk {=u32}!", idx);
+                    }
+                }
+
+                Ok(Some(SubOtaPayload::Next {
+                    idx: state.received.first_unset(total),
+                }))
+            }
+            DomOtaPayload::Status => {
+                let state = match self.state.as_ref() {
+                    Some(state) => state,
+                    None => return Err(self.failure.unwrap_or(OtaFailure::NotStarted)),
+                };
+                let (start, bits) = missing_window(&state.received, state.info.total_chunks);
+                Ok(Some(SubOtaPayload::Missing { start, bits }))
+            }
+            DomOtaPayload::Finish => {
+                let state = self.state.take().ok_or(OtaFailure::NotStarted)?;
+                let total = state.info.total_chunks;
+
+                if state.received.first_unset(total) != total {
+                    return Err(OtaFailure::Incomplete);
+                }
+
+                // Check what actually ended up in storage
+                let mut image_poly = Poly1305::new(&Key::from(*self.key));
+                let mut chunk = [0u8; OTA_CHUNK_SIZE];
+                for idx in 0..total {
+                    self.storage
+                        .read_chunk((idx as usize) * OTA_CHUNK_SIZE, &mut chunk)
+                        .map_err(|_| OtaFailure::Storage)?;
+                    chunk
+                        .chunks_exact(OTA_TAG_SIZE)
+                        .for_each(|blk| image_poly.update(&to_block(blk)));
+                }
+
+                image_poly
+                    .verify(&Block::from(state.info.image_tag))
+                    .map_err(|_| OtaFailure::BadImageTag)?;
+
+                let meta = OtaMetadata::new(&state.info, S::PAGE_SIZE, self.storage.boot_seq());
+                self.storage
+                    .write_metadata(&meta)
+                    .map_err(|_| OtaFailure::Storage)?;
+
+                defmt::info!("OTA complete, rebooting!");
+                Ok(Some(SubOtaPayload::Finished))
+            }
+            DomOtaPayload::Abort => {
+                self.state = None;
+                Err(OtaFailure::NotStarted)
+            }
+        }
+    }
+
+    fn reply(&mut self, reply: SubOtaPayload) -> Result<(), ReplyError> {
+        let addr = self.dispatch.get_addr().ok_or(ReplyError::NoAddr)?;
+
+        let msg = LocalPacket::from_parts_with_alloc(
+            reply,
+            AddrPort::from_parts(VecAddr::from_local_addr(addr), OTA_PORT),
+            AddrPort::from_parts(VecAddr::local_dom_addr(), OTA_PORT),
+            None,
+            &self.alloc,
+        )
+        .ok_or(ReplyError::NoAlloc)?;
+
+        self.socket.try_send(msg).map_err(|_| ReplyError::Send)
+    }
+}
+
+/// The first missing chunk, and which of the following chunks are missing,
+/// as reported in `SubOtaPayload::Missing`
+fn missing_window(received: &ChunkMap, total: u32) -> (u32, [u8; OTA_MISSING_WINDOW / 8]) {
+    let start = received.first_unset(total);
+    let mut bits = [0u8; OTA_MISSING_WINDOW / 8];
+    (0..OTA_MISSING_WINDOW as u32)
+        .filter(|n| {
+            let idx = start + n;
+            idx < total && !received.get(idx)
+        })
+        .for_each(|n| bits[(n / 8) as usize] |= 1 << (n % 8));
+
+    (start, bits)
+}
+
+/// Write a verified chunk, erasing its page first if this is the first
+/// chunk to arrive in that page
+fn store_chunk<S: OtaStorage>(
+    storage: &mut S,
+    received: &mut ChunkMap,
+    idx: u32,
+    chunk: &[u8; OTA_CHUNK_SIZE],
+) -> Result<(), OtaFailure> {
+    let per_page = (S::PAGE_SIZE / OTA_CHUNK_SIZE) as u32;
+    let page = idx / per_page;
+    let page_start = page * per_page;
+
+    if !(page_start..page_start + per_page).any(|i| received.get(i)) {
+        storage
+            .erase_page(page as usize)
+            .map_err(|_| OtaFailure::Storage)?;
+    }
+
+    storage
+        .write_chunk((idx as usize) * OTA_CHUNK_SIZE, chunk)
+        .map_err(|_| OtaFailure::Storage)?;
+    received.set(idx);
+
+    Ok(())
+}
+
+fn chunk_tag(key: &[u8; 32], chunk: &[u8; OTA_CHUNK_SIZE]) -> Poly1305 {
+    let mut poly = Poly1305::new(&Key::from(*key));
+    chunk
+        .chunks_exact(OTA_TAG_SIZE)
+        .for_each(|blk| poly.update(&to_block(blk)));
+    poly
+}
+
+fn to_block(data: &[u8]) -> Block {
+    let mut blk = [0u8; OTA_TAG_SIZE];
+    blk.copy_from_slice(data);
+    Block::from(blk)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_bits() {
+        let mut received = ChunkMap::new();
+        let total = 300;
+
+        // Nothing received, the window starts at zero and is full
+        let (start, bits) = missing_window(&received, total);
+        assert_eq!(start, 0);
+        assert!(bits.iter().all(|b| *b == 0xFF));
+
+        // The window starts at the first gap, and skips received chunks
+        (0..10).for_each(|i| received.set(i));
+        received.set(11);
+        received.set(17);
+        let (start, bits) = missing_window(&received, total);
+        assert_eq!(start, 10);
+        assert_eq!(bits[0], 0b0111_1101);
+        assert_eq!(bits[1], 0xFF);
+
+        // Chunks past the end of the image are never missing
+        (0..290).for_each(|i| received.set(i));
+        let (start, bits) = missing_window(&received, total);
+        assert_eq!(start, 290);
+        assert_eq!(bits[0], 0xFF);
+        assert_eq!(bits[1], 0b0000_0011);
+        assert!(bits[2..].iter().all(|b| *b == 0));
+
+        // Complete, the start is past the end
+        (290..total).for_each(|i| received.set(i));
+        let (start, bits) = missing_window(&received, total);
+        assert_eq!(start, total);
+        assert!(bits.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn boot_metadata() {
+        let info = OtaImageInfo {
+            uuid: [1; 16],
+            image_tag: [2; OTA_TAG_SIZE],
+            total_chunks: 33,
+        };
+
+        // A partly filled last page still counts
+        let page_size = 16 * OTA_CHUNK_SIZE;
+        let meta = OtaMetadata::new(&info, page_size, 7);
+        assert_eq!(meta.image_uuid, [1; 16]);
+        assert_eq!(meta.image_poly1305_tag, [2; OTA_TAG_SIZE]);
+        assert_eq!(meta.image_len_pages, 3);
+        assert_eq!(meta.boot_seq_number, 8);
+
+        assert_eq!(
+            OtaMetadata::new(&info, page_size, u32::MAX).boot_seq_number,
+            0
+        );
+    }
+}
@@ -1,43 +1,42 @@
 use core::marker::PhantomData;
 
-use byte_slab::BSlab;
 use groundhog::RollingTimer;
 use rand::Rng;
 
 use crate::{
     async_sleep_micros, async_sleep_millis,
-    dispatch::{Dispatch, DispatchSocket, LocalPacket},
+    dispatch::{Dispatch, DispatchSocket, LocalPacket, PacketAlloc},
     dom::TOKEN_PORT,
-    icd::{
-        AddrPort, DomTokenGrantPayload,
-        SubTokenReleasePayload, VecAddr, SLAB_SIZE, TOTAL_SLABS,
-    },
+    icd::{AddrPort, DomTokenGrantPayload, SubTokenReleasePayload, VecAddr},
     receive_timeout_micros,
+    timing::BusTiming,
 };
 
-pub struct Token<R, A>
+pub struct Token<R, A, const PORTS: usize, const N: usize, const SZ: usize>
 where
     R: RollingTimer<Tick = u32> + Default,
     A: Rng,
 {
     _timer: PhantomData<R>,
-    dispatch: &'static Dispatch<8>,
-    socket: DispatchSocket<'static>,
+    dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+    socket: DispatchSocket<'static, N, SZ>,
     _rand: A,
-    alloc: &'static BSlab<TOTAL_SLABS, SLAB_SIZE>,
+    alloc: PacketAlloc<N, SZ>,
     bad_ticks: u8,
+    timing: BusTiming,
 }
 
-impl<R, A> Token<R, A>
+impl<R, A, const PORTS: usize, const N: usize, const SZ: usize> Token<R, A, PORTS, N, SZ>
 where
     R: RollingTimer<Tick = u32> + Default,
     A: Rng,
 {
     pub fn new(
         rand: A,
-        dispatch: &'static Dispatch<8>,
-        socket: DispatchSocket<'static>,
-        alloc: &'static BSlab<TOTAL_SLABS, SLAB_SIZE>,
+        dispatch: &'static Dispatch<'static, PORTS, N, SZ>,
+        socket: DispatchSocket<'static, N, SZ>,
+        alloc: PacketAlloc<N, SZ>,
+        timing: BusTiming,
     ) -> Self {
         Self {
             _timer: PhantomData,
@@ -46,6 +45,7 @@ where
             alloc,
             dispatch,
             bad_ticks: 0,
+            timing,
         }
     }
 
@@ -70,7 +70,7 @@ where
             Some(addr) => addr,
         };
 
-        let maybe_msg = receive_timeout_micros::<R, DomTokenGrantPayload>(
+        let maybe_msg = receive_timeout_micros::<R, DomTokenGrantPayload, N, SZ>(
             &mut self.socket,
             timer.get_ticks(),
             1_000_000,
@@ -107,7 +107,7 @@ where
 
                 // The IO will wait 1ms on spurious auth, in the case of
                 // ACTUALLY sending, it will take a bit longer Or not?
-                async_sleep_micros::<R>(timer.get_ticks(), 2_100).await;
+                async_sleep_micros::<R>(timer.get_ticks(), self.timing.sub_send_poll_us).await;
             }
         }
 
@@ -115,12 +115,12 @@ where
             random: msg.body.random,
         };
 
-        let msg = LocalPacket::from_parts_with_alloc(
+        let msg = LocalPacket::from_parts_with_packet_alloc(
             payload,
             AddrPort::from_parts(VecAddr::from_local_addr(addr), TOKEN_PORT),
             AddrPort::from_parts(VecAddr::local_dom_addr(), TOKEN_PORT),
             None,
-            self.alloc,
+            &self.alloc,
         )
         .ok_or(())?;
 
@@ -1,11 +1,128 @@
-pub const DOM_BROADCAST_MIN_WAIT_US: u32 = 10_000;
-pub const DOM_BROADCAST_MAX_WAIT_US: u32 = 50_000;
+//! Bus timing profiles
+//!
+//! Every wait on the bus is derived from how long it takes to send a frame,
+//! which depends on the baud rate and the largest frame size. The values used
+//! at 1Mbit with 512 byte frames are used as a floor, so faster buses don't
+//! end up with waits shorter than the software can keep up with.
 
-pub const DOM_PING_MIN_WAIT_US: u32 = 10_000;
-pub const DOM_PING_MAX_WAIT_US: u32 = 50_000;
+/// 8N1: A start bit, eight data bits, and a stop bit
+pub const BITS_PER_BYTE: u32 = 10;
 
-pub const SUB_INITIAL_DISCO_WAIT_US: u32 = 2_000_000;
-pub const SUB_BROADACKACK_WAIT_US: u32 = 2_000_000;
+/// How often discovery steps are taken, outside of boost mode
+pub const DOM_DISCO_STEP_US: u32 = 1_000_000;
 
-// TODO: Define as a multiple of dom disco step interval
-pub const SUB_PING_WAIT_US: u32 = 2_500_000;
+/// How often the IO layer checks for something to do, when idle
+pub const IO_POLL_US: u32 = 1_000;
+
+/// All timing values used by the bus, in microseconds
+///
+/// The same profile should be passed to the dom and sub tasks, as well as the
+/// IO layer. Use `BusTiming::from_baud()` to build one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusTiming {
+    pub baud: u32,
+    pub max_frame_bytes: u32,
+
+    /// The time to send a single byte
+    pub byte_us: u32,
+
+    /// The time to send a maximum sized frame
+    pub frame_us: u32,
+
+    pub dom_broadcast_min_wait_us: u32,
+    pub dom_broadcast_max_wait_us: u32,
+    pub dom_ping_min_wait_us: u32,
+    pub dom_ping_max_wait_us: u32,
+
+    /// The time a sub may hold the token, and the time the dom waits
+    /// for it to be released
+    pub token_grant_us: u32,
+
+    /// How long the dom waits between discovery steps
+    pub dom_disco_step_us: u32,
+
+    /// How long the dom waits between discovery steps in boost mode
+    pub dom_disco_boost_step_us: u32,
+
+    /// How long the dom stays in boost mode after discovering a sub
+    pub dom_disco_boost_window_us: u32,
+
+    pub sub_initial_disco_wait_us: u32,
+    pub sub_broadackack_wait_us: u32,
+    pub sub_ping_wait_us: u32,
+
+    /// How long a sub holding the token waits between sends
+    pub sub_send_poll_us: u32,
+
+    /// How long the IO layer waits for the first byte of a frame
+    pub io_first_byte_us: u32,
+
+    /// How long the line must be quiet to end a frame
+    pub io_quiet_us: u32,
+}
+
+impl BusTiming {
+    /// Build the timing profile for a bus of `baud`, with frames of up to
+    /// `max_frame_bytes`.
+    ///
+    /// Panics if `baud` is zero, at compile time when used in a const.
+    pub const fn from_baud(baud: u32, max_frame_bytes: u32) -> Self {
+        assert!(baud != 0, "A bus needs a baud rate");
+
+        let byte_us = to_u32((BITS_PER_BYTE as u64 * 1_000_000).div_ceil(baud as u64));
+        let frame_us = byte_us.saturating_mul(max_frame_bytes);
+
+        let dom_ping_max_wait_us = max(frame_us.saturating_mul(10), 50_000);
+        let io_quiet_us = max(byte_us.saturating_mul(10), 100);
+
+        Self {
+            baud,
+            max_frame_bytes,
+            byte_us,
+            frame_us,
+            dom_broadcast_min_wait_us: max(frame_us.saturating_mul(2), 10_000),
+            dom_broadcast_max_wait_us: max(frame_us.saturating_mul(10), 50_000),
+            dom_ping_min_wait_us: max(frame_us.saturating_mul(2), 10_000),
+            dom_ping_max_wait_us,
+            token_grant_us: max(frame_us.saturating_mul(10), 50_000),
+            dom_disco_step_us: DOM_DISCO_STEP_US,
+            dom_disco_boost_step_us: DOM_DISCO_STEP_US / 10,
+            dom_disco_boost_window_us: 3 * DOM_DISCO_STEP_US,
+            sub_initial_disco_wait_us: 2 * DOM_DISCO_STEP_US,
+            sub_broadackack_wait_us: 2 * DOM_DISCO_STEP_US,
+
+            // Two discovery steps, plus time for the dom to ping a
+            // number of other readies ahead of us
+            sub_ping_wait_us: (2 * DOM_DISCO_STEP_US)
+                .saturating_add(dom_ping_max_wait_us.saturating_mul(10)),
+
+            // The IO layer may take a poll interval to notice each auth
+            sub_send_poll_us: (2 * IO_POLL_US) + 100,
+            io_first_byte_us: max(io_quiet_us.saturating_mul(10), IO_POLL_US),
+            io_quiet_us,
+        }
+    }
+}
+
+impl Default for BusTiming {
+    /// 1Mbit, with frames up to a single slab
+    fn default() -> Self {
+        Self::from_baud(1_000_000, crate::icd::SLAB_SIZE as u32)
+    }
+}
+
+const fn to_u32(val: u64) -> u32 {
+    if val > u32::MAX as u64 {
+        u32::MAX
+    } else {
+        val as u32
+    }
+}
+
+const fn max(a: u32, b: u32) -> u32 {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
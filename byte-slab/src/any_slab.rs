@@ -0,0 +1,319 @@
+//! Allocations from a slab of any size
+//!
+//! `AnyBox`, `AnyArc` and `AnySliceArc` behave like `SlabBox`, `SlabArc` and
+//! `SlabSliceArc`, but don't carry the `N` and `SZ` of their `BSlab` in their
+//! type. Code that holds allocations from several slabs, such as the classes
+//! of a `SizedSlab`, can store them without being generic over each one.
+//!
+//! `AnyAlloc` is the allocating side of this, for a `BSlab` or a `SizedSlab`
+//! behind a `&'static dyn AnyAlloc`.
+
+use core::{
+    mem::forget,
+    ops::{Deref, DerefMut},
+};
+
+use serde::Serialize;
+
+use crate::{
+    atomic::Ordering, byte_slab::BSlab, sized_slab::SliceError, slab_arc::SlabArc,
+    slab_box::SlabBox, slab_slice_arc::SlabSliceArc,
+};
+
+/// Access to the elements of a `BSlab`, without its size
+pub(crate) trait AnySlab: Sync {
+    /// The element's buffer
+    fn buf(&'static self, idx: usize) -> *mut [u8];
+
+    /// Add a reference to an element
+    fn retain(&'static self, idx: usize);
+
+    /// Drop a reference to an element, freeing it with the last one
+    fn release(&'static self, idx: usize);
+}
+
+impl<const N: usize, const SZ: usize> AnySlab for BSlab<N, SZ> {
+    fn buf(&'static self, idx: usize) -> *mut [u8] {
+        unsafe { self.get_idx_unchecked(idx).buf.get() }
+    }
+
+    fn retain(&'static self, idx: usize) {
+        let arc = unsafe { self.get_idx_unchecked(idx).arc };
+
+        let old_ct = arc.fetch_add(1, Ordering::SeqCst);
+        assert!(old_ct >= 1);
+    }
+
+    fn release(&'static self, idx: usize) {
+        drop(SlabArc { slab: self, idx });
+    }
+}
+
+/// Something to take allocations of any size from
+pub trait AnyAlloc: Sync {
+    /// Allocate a Box of at least `len` bytes.
+    ///
+    /// This function will return `None` if `len` is too large, or if no
+    /// pages are available.
+    fn alloc_any(&'static self, len: usize) -> Option<AnyBox>;
+}
+
+impl<const N: usize, const SZ: usize> AnyAlloc for BSlab<N, SZ> {
+    #[track_caller]
+    fn alloc_any(&'static self, len: usize) -> Option<AnyBox> {
+        if len > SZ {
+            return None;
+        }
+        self.alloc_box().map(AnyBox::from)
+    }
+}
+
+/// A `SlabBox` from a slab of any size
+///
+/// ## Example
+///
+/// ```rust
+/// use byte_slab::{AnyAlloc, AnyBox, AnySliceArc, BSlab};
+///
+/// static SMALL: BSlab<4, 64> = BSlab::new();
+/// static LARGE: BSlab<4, 1024> = BSlab::new();
+///
+/// fn main() {
+///     SMALL.init().unwrap();
+///     LARGE.init().unwrap();
+///
+///     let allocs: [&'static dyn AnyAlloc; 2] = [&SMALL, &LARGE];
+///     let slices: Vec<AnySliceArc> = allocs
+///         .iter()
+///         .map(|alloc| {
+///             let mut abox: AnyBox = alloc.alloc_any(16).unwrap();
+///             abox[..3].copy_from_slice(&[1, 2, 3]);
+///             abox.into_arc().sub_slice_arc(0, 3).unwrap()
+///         })
+///         .collect();
+///
+///     assert!(slices.iter().all(|asa| &asa[..] == &[1, 2, 3]));
+/// }
+/// ```
+pub struct AnyBox {
+    slab: &'static dyn AnySlab,
+    idx: usize,
+}
+
+impl AnyBox {
+    /// Convert the `AnyBox` into an `AnyArc`
+    pub fn into_arc(self) -> AnyArc {
+        let new_arc = AnyArc {
+            slab: self.slab,
+            idx: self.idx,
+        };
+
+        forget(self);
+
+        new_arc
+    }
+}
+
+impl<const N: usize, const SZ: usize> From<SlabBox<N, SZ>> for AnyBox {
+    fn from(sbox: SlabBox<N, SZ>) -> Self {
+        let abox = AnyBox {
+            slab: sbox.slab,
+            idx: sbox.idx,
+        };
+
+        forget(sbox);
+
+        abox
+    }
+}
+
+impl Drop for AnyBox {
+    fn drop(&mut self) {
+        self.slab.release(self.idx);
+    }
+}
+
+impl Deref for AnyBox {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.slab.buf(self.idx) }
+    }
+}
+
+impl DerefMut for AnyBox {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.slab.buf(self.idx) }
+    }
+}
+
+/// A `SlabArc` from a slab of any size
+pub struct AnyArc {
+    slab: &'static dyn AnySlab,
+    idx: usize,
+}
+
+impl AnyArc {
+    /// Create an `AnySliceArc` with a full view of the underlying data
+    pub fn full_sub_slice_arc(&self) -> AnySliceArc {
+        AnySliceArc {
+            arc: self.clone(),
+            start: 0,
+            len: self.len(),
+        }
+    }
+
+    /// Create an `AnySliceArc` with a partial view of the underlying data.
+    ///
+    /// This function will fail if `start` and `len` do not describe a valid
+    /// region of the `AnyArc`.
+    pub fn sub_slice_arc(&self, start: usize, len: usize) -> Result<AnySliceArc, SliceError> {
+        let good_start = start < self.len();
+        let good_len = (start + len) <= self.len();
+
+        if good_start && good_len {
+            Ok(AnySliceArc {
+                arc: self.clone(),
+                start,
+                len,
+            })
+        } else {
+            Err(SliceError::OutOfBounds)
+        }
+    }
+}
+
+impl<const N: usize, const SZ: usize> From<SlabArc<N, SZ>> for AnyArc {
+    fn from(arc: SlabArc<N, SZ>) -> Self {
+        let any_arc = AnyArc {
+            slab: arc.slab,
+            idx: arc.idx,
+        };
+
+        forget(arc);
+
+        any_arc
+    }
+}
+
+impl Drop for AnyArc {
+    fn drop(&mut self) {
+        self.slab.release(self.idx);
+    }
+}
+
+impl Deref for AnyArc {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.slab.buf(self.idx) }
+    }
+}
+
+impl Clone for AnyArc {
+    fn clone(&self) -> Self {
+        self.slab.retain(self.idx);
+
+        Self {
+            slab: self.slab,
+            idx: self.idx,
+        }
+    }
+}
+
+/// A `SlabSliceArc` from a slab of any size
+#[derive(Clone)]
+pub struct AnySliceArc {
+    arc: AnyArc,
+    start: usize,
+    len: usize,
+}
+
+impl AnySliceArc {
+    /// Create a (smaller) `AnySliceArc` from this one, with a partial view
+    /// of the underlying data.
+    ///
+    /// This function will fail if `start` and `len` do not describe a valid
+    /// region of the `AnySliceArc`.
+    pub fn sub_slice_arc(&self, start: usize, len: usize) -> Result<AnySliceArc, SliceError> {
+        let good_start = start < self.len;
+        let good_len = (start + len) <= self.len;
+
+        if good_start && good_len {
+            Ok(AnySliceArc {
+                arc: self.arc.clone(),
+                start: self.start + start,
+                len,
+            })
+        } else {
+            Err(SliceError::OutOfBounds)
+        }
+    }
+}
+
+impl<const N: usize, const SZ: usize> From<SlabSliceArc<N, SZ>> for AnySliceArc {
+    fn from(ssa: SlabSliceArc<N, SZ>) -> Self {
+        AnySliceArc {
+            arc: ssa.arc.into(),
+            start: ssa.start,
+            len: ssa.len,
+        }
+    }
+}
+
+impl Deref for AnySliceArc {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.arc.deref()[self.start..][..self.len]
+    }
+}
+
+impl Serialize for AnySliceArc {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let data: &[u8] = self.deref();
+        data.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{AnyAlloc, AnyArc, AnyBox, BSlab, SliceError};
+    use std::ops::Deref;
+
+    #[test]
+    fn shares_refcount() {
+        static SLAB: BSlab<2, 64> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let mut abox = SLAB.alloc_any(10).unwrap();
+        assert_eq!(abox.len(), 64);
+        abox[..4].copy_from_slice(&[1, 2, 3, 4]);
+        let arc = abox.into_arc();
+
+        let ssa = arc.sub_slice_arc(1, 3).unwrap();
+        let ssa_2 = ssa.sub_slice_arc(1, 1).unwrap();
+        assert_eq!(&[2, 3, 4], ssa.deref());
+        assert_eq!(&[3], ssa_2.deref());
+        assert_eq!(ssa.sub_slice_arc(2, 2).err(), Some(SliceError::OutOfBounds));
+
+        // Typed and untyped handles to the same element
+        let typed: AnyArc = SLAB.alloc_box().unwrap().into_arc().into();
+        assert!(SLAB.alloc_box().is_none());
+        drop(typed);
+
+        let other: AnyBox = SLAB.alloc_box().unwrap().into();
+        drop(arc);
+        drop(ssa);
+        assert!(SLAB.alloc_box().is_none());
+        drop(ssa_2);
+        assert!(SLAB.alloc_box().is_some());
+        drop(other);
+
+        // Too big for this slab
+        assert!(SLAB.alloc_any(65).is_none());
+    }
+}
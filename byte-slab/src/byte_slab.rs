@@ -2,19 +2,39 @@
 //!
 //! The `BSlab` represents the storage of all boxes and their related metadata.
 //!
-//! Currently, it maintains its free list as an MPMC queue, though that is an implementation
-//! detail that may change. This implementation is convenient, but not particularly memory-dense.
+//! By default, it maintains its free list as an MPMC queue. This implementation is convenient,
+//! but not particularly memory-dense. With the `atomic_free_list` feature, the reference counts
+//! of each element are used as the free list instead, which needs no extra memory and no call
+//! to `init()`.
+//!
+//! The atomic free list is not a separate bitmap of free elements: sizing a bitmap from `N`
+//! needs `generic_const_exprs`, which is not available on stable. Instead, allocation does a
+//! compare-and-swap scan over the reference counts, starting after the last allocated element.
+//! This is O(N) in the worst case, which is fine for the small slabs this crate is used with.
 //!
 //! The slab is statically allocated, and the size of each Box, as well as the total number of
 //! Boxes available is selected through compile time `const` values.
 
+#[cfg(feature = "std")]
+use crate::heap_slab::HeapRefs;
+#[cfg(feature = "leak_check")]
+use crate::leak_check::{AllocSites, Outstanding};
 use crate::slab_box::SlabBox;
+#[cfg(feature = "stats")]
+use crate::stats::{SlabStats, StatCounters};
+use crate::waiters::Waiters;
+use crate::atomic::{AtomicU8, AtomicUsize, Ordering};
 use core::{
     cell::UnsafeCell,
-    mem::MaybeUninit,
-    slice::from_raw_parts,
-    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
 };
+#[cfg(feature = "leak_check")]
+use core::panic::Location;
+#[cfg(not(feature = "atomic_free_list"))]
+use core::{mem::MaybeUninit, slice::from_raw_parts};
+#[cfg(not(feature = "atomic_free_list"))]
 use heapless::mpmc::MpMcQueue;
 
 /// A slab of byte-array elements
@@ -32,9 +52,18 @@ use heapless::mpmc::MpMcQueue;
 /// it would have a total storage space of 1024 bytes.
 pub struct BSlab<const N: usize, const SZ: usize> {
     /// The underlying storage of the BSlab
+    #[cfg(not(feature = "atomic_free_list"))]
     bufs: MaybeUninit<[UnsafeCell<[u8; SZ]>; N]>,
 
+    /// The underlying storage of the BSlab, zeroed at const time
+    #[cfg(feature = "atomic_free_list")]
+    bufs: [UnsafeCell<[u8; SZ]>; N],
+
     /// The reference counts for each of the buffers
+    ///
+    /// A count of zero means the buffer is free. With the `atomic_free_list`
+    /// feature this is the only free list, and allocation claims a buffer by
+    /// moving its count from zero to one.
     arcs: [AtomicUsize; N],
 
     /// The free-list queue used by the BSlab
@@ -44,10 +73,29 @@ pub struct BSlab<const N: usize, const SZ: usize> {
     /// end up in `.data` instead of `.bss` which makes the firmware much
     /// larger, and the flashing much slower. This is a workaround to force
     /// the contents to be in `.bss`.
+    #[cfg(not(feature = "atomic_free_list"))]
     alloc_q: UnsafeCell<MaybeUninit<MpMcQueue<usize, N>>>,
 
+    /// Where the next search for a free buffer starts, so that allocations
+    /// don't all start by scanning past the same busy buffers
+    #[cfg(feature = "atomic_free_list")]
+    next_free: AtomicUsize,
+
     // Initialization state
     state: AtomicU8,
+
+    /// Tasks waiting in `alloc_box_async()`
+    pub(crate) waiters: Waiters,
+
+    #[cfg(feature = "stats")]
+    stats: StatCounters,
+
+    #[cfg(feature = "leak_check")]
+    sites: AllocSites<N>,
+
+    /// Keeps the slab alive, if it belongs to a `HeapSlab`
+    #[cfg(feature = "std")]
+    pub(crate) heap_refs: HeapRefs,
 }
 
 // BSlab may be `Sync`, as all safety elements are checked at runtime
@@ -61,38 +109,60 @@ pub(crate) struct SlabIdxData<const SZ: usize> {
     pub(crate) arc: &'static AtomicUsize,
 }
 
-// TODO: I should switch to `atomic-polyfill` to support thumbv6
 /// Storage of a slab of runtime-allocatable byte chunks
 impl<const N: usize, const SZ: usize> BSlab<N, SZ> {
 
     /// Create a new `BSlab` in a constant context.
     ///
     /// NOTE: The `BSlab` MUST be initialized with a call to `BSlab::init()` before
-    /// usage, or all allocations will fail!
+    /// usage, or all allocations will fail! This is not necessary with the
+    /// `atomic_free_list` feature.
     pub const fn new() -> Self {
         // thanks, mara, for the const repeated initializer trick!
         const ZERO_ARC: AtomicUsize = AtomicUsize::new(0);
         Self {
+            #[cfg(not(feature = "atomic_free_list"))]
             bufs: MaybeUninit::uninit(),
+            #[cfg(feature = "atomic_free_list")]
+            bufs: [Self::ZERO_BUF; N],
             arcs: [ZERO_ARC; N],
+            #[cfg(not(feature = "atomic_free_list"))]
             alloc_q: UnsafeCell::new(MaybeUninit::uninit()),
+            #[cfg(feature = "atomic_free_list")]
+            next_free: AtomicUsize::new(0),
             state: AtomicU8::new(Self::UNINIT),
+            waiters: Waiters::new(),
+            #[cfg(feature = "stats")]
+            stats: StatCounters::new(),
+            #[cfg(feature = "leak_check")]
+            sites: AllocSites::new(),
+            #[cfg(feature = "std")]
+            heap_refs: HeapRefs::new(),
         }
     }
 
+    #[cfg(feature = "atomic_free_list")]
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO_BUF: UnsafeCell<[u8; SZ]> = UnsafeCell::new([0; SZ]);
+
     const UNINIT: u8 = 0;
+    #[cfg(not(feature = "atomic_free_list"))]
     const INITIALIZING: u8 = 1;
     const INITIALIZED: u8 = 2;
 
     /// Is the buffer initialized?
+    ///
+    /// With the `atomic_free_list` feature, the buffer is always ready for use.
     pub fn is_init(&self) -> Result<(), ()> {
-        if Self::INITIALIZED == self.state.load(Ordering::SeqCst) {
+        let init = Self::INITIALIZED == self.state.load(Ordering::SeqCst);
+        if init || cfg!(feature = "atomic_free_list") {
             Ok(())
         } else {
             Err(())
         }
     }
 
+    #[cfg(not(feature = "atomic_free_list"))]
     pub(crate) fn get_q(&self) -> Result<&MpMcQueue<usize, N>, ()> {
         self.is_init()?;
         unsafe {
@@ -104,7 +174,39 @@ impl<const N: usize, const SZ: usize> BSlab<N, SZ> {
     ///
     /// This function will return `None` if the buffer has not been initialized,
     /// or if there are no pages available.
+    #[track_caller]
     pub fn alloc_box(&'static self) -> Option<SlabBox<N, SZ>> {
+        let sbox = self.try_alloc();
+
+        #[cfg(feature = "stats")]
+        if sbox.is_none() {
+            self.stats.alloc_failed();
+        }
+
+        #[cfg(feature = "leak_check")]
+        if let Some(sbox) = sbox.as_ref() {
+            self.sites.allocated(sbox.idx, Location::caller());
+        }
+
+        sbox
+    }
+
+    /// Allocate, without counting a failure
+    fn try_alloc(&'static self) -> Option<SlabBox<N, SZ>> {
+        let idx = self.take_free()?;
+
+        #[cfg(feature = "stats")]
+        self.stats.alloc();
+
+        #[cfg(feature = "std")]
+        self.heap_refs.acquire();
+
+        Some(SlabBox { slab: self, idx })
+    }
+
+    /// Take an element from the free list, with a refcount of one
+    #[cfg(not(feature = "atomic_free_list"))]
+    fn take_free(&'static self) -> Option<usize> {
         let idx = self.get_q().ok()?.dequeue()?;
         let arc = unsafe { self.get_idx_unchecked(idx).arc };
 
@@ -112,7 +214,111 @@ impl<const N: usize, const SZ: usize> BSlab<N, SZ> {
         // so we can disregard the previous value
         arc.store(1, Ordering::SeqCst);
 
-        Some(SlabBox { slab: self, idx })
+        Some(idx)
+    }
+
+    /// Take an element from the free list, with a refcount of one
+    #[cfg(feature = "atomic_free_list")]
+    fn take_free(&'static self) -> Option<usize> {
+        let start = self.next_free.load(Ordering::Relaxed);
+
+        // Claim the first free buffer at or after `start`, wrapping around
+        let idx = (0..N).map(|i| (start + i) % N).find(|idx| {
+            self.arcs[*idx]
+                .compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        })?;
+
+        self.next_free.store((idx + 1) % N, Ordering::Relaxed);
+
+        Some(idx)
+    }
+
+    /// Return an element to the free list, once its last user is gone
+    ///
+    /// The caller must hold the only reference, with the refcount still at
+    /// one. The refcount is only released once the bookkeeping for the element
+    /// is done, as with the atomic free list, a refcount of zero means the
+    /// element may be allocated again right away.
+    pub(crate) fn free_idx(&self, idx: usize) {
+        #[cfg(feature = "leak_check")]
+        self.sites.freed(idx);
+
+        #[cfg(feature = "stats")]
+        self.stats.free();
+
+        self.arcs[idx].store(0, Ordering::SeqCst);
+
+        // TODO: Why is this necessary?
+        #[cfg(not(feature = "atomic_free_list"))]
+        if let Ok(q) = self.get_q() {
+            while let Err(_) = q.enqueue(idx) {}
+        }
+
+        self.waiters.wake_all();
+
+        // Must be last, this may free a heap slab
+        #[cfg(feature = "std")]
+        unsafe {
+            self.release_heap_ref();
+        }
+    }
+
+    /// A snapshot of the allocation statistics
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> SlabStats {
+        self.stats.snapshot(N)
+    }
+
+    /// The number of elements currently available for allocation
+    #[cfg(feature = "stats")]
+    pub fn free_count(&self) -> usize {
+        self.stats().free_count
+    }
+
+    /// Allocate a new Box of `SZ`, waiting for one to be freed if there are
+    /// no pages available.
+    ///
+    /// Waiting tasks are woken whenever a `SlabBox` or the last reference to
+    /// a `SlabArc` is dropped. Up to `MAX_ALLOC_WAITERS` tasks may wait to be
+    /// woken at once, any more are polled continuously instead.
+    ///
+    /// This will wait forever if the buffer is never initialized.
+    #[track_caller]
+    pub fn alloc_box_async(&'static self) -> impl Future<Output = SlabBox<N, SZ>> {
+        WaitForBox {
+            slab: self,
+            slot: None,
+            #[cfg(feature = "leak_check")]
+            site: Location::caller(),
+        }
+    }
+
+    /// The elements that are currently allocated, and where they were allocated
+    #[cfg(feature = "leak_check")]
+    pub fn outstanding(&'static self) -> impl Iterator<Item = Outstanding> {
+        (0..N)
+            .filter(move |idx| self.arcs[*idx].load(Ordering::SeqCst) != 0)
+            .map(move |idx| Outstanding {
+                idx,
+                site: self.sites.site(idx),
+            })
+    }
+
+    /// Panic if any element is still allocated, reporting where the first one
+    /// was allocated. Useful at the end of a test.
+    #[cfg(feature = "leak_check")]
+    #[track_caller]
+    pub fn assert_all_freed(&'static self) {
+        let mut outstanding = self.outstanding();
+
+        if let Some(first) = outstanding.next() {
+            panic!(
+                "{} slab element(s) were never freed, including {}",
+                outstanding.count() + 1,
+                first
+            );
+        }
     }
 
     /// Get the metadata handle for a given index
@@ -127,6 +333,27 @@ impl<const N: usize, const SZ: usize> BSlab<N, SZ> {
     ///
     /// This function will fail if the BSlab has already been initialized, OR if it
     /// is already in-process of being initialized (e.g. in a multithreaded context).
+    ///
+    /// With the `atomic_free_list` feature there is nothing to initialize, and
+    /// this only checks that it has not been called before.
+    #[cfg(feature = "atomic_free_list")]
+    pub fn init(&self) -> Result<(), ()> {
+        self.state
+            .compare_exchange(
+                Self::UNINIT,
+                Self::INITIALIZED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .map(drop)
+            .map_err(drop)
+    }
+
+    /// Initialize the buffer.
+    ///
+    /// This function will fail if the BSlab has already been initialized, OR if it
+    /// is already in-process of being initialized (e.g. in a multithreaded context).
+    #[cfg(not(feature = "atomic_free_list"))]
     pub fn init(&self) -> Result<(), ()> {
         // Begin initialization. Returns an error if the slab was not previously
         // uninitialized
@@ -174,3 +401,52 @@ impl<const N: usize, const SZ: usize> BSlab<N, SZ> {
         Ok(())
     }
 }
+
+struct WaitForBox<const N: usize, const SZ: usize> {
+    slab: &'static BSlab<N, SZ>,
+    slot: Option<usize>,
+    #[cfg(feature = "leak_check")]
+    site: &'static Location<'static>,
+}
+
+impl<const N: usize, const SZ: usize> Future for WaitForBox<N, SZ> {
+    type Output = SlabBox<N, SZ>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(sbox) = self.slab.try_alloc() {
+            return Poll::Ready(self.allocated(sbox));
+        }
+
+        if self.slot.is_none() {
+            self.slot = self.slab.waiters.claim();
+        }
+
+        match self.slot {
+            Some(idx) => self.slab.waiters.register(idx, cx.waker()),
+            None => cx.waker().wake_by_ref(),
+        }
+
+        // Try again, in case a box was freed before we were registered
+        match self.slab.try_alloc() {
+            Some(sbox) => Poll::Ready(self.allocated(sbox)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<const N: usize, const SZ: usize> WaitForBox<N, SZ> {
+    fn allocated(&self, sbox: SlabBox<N, SZ>) -> SlabBox<N, SZ> {
+        #[cfg(feature = "leak_check")]
+        self.slab.sites.allocated(sbox.idx, self.site);
+
+        sbox
+    }
+}
+
+impl<const N: usize, const SZ: usize> Drop for WaitForBox<N, SZ> {
+    fn drop(&mut self) {
+        if let Some(idx) = self.slot.take() {
+            self.slab.waiters.release(idx);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Deserializing owned values straight from a slab allocation
+//!
+//! Only available with the `postcard` feature. Messages that borrow from the
+//! bytes they were decoded from, such as those containing `ManagedArcSlab`s,
+//! are rerooted to the allocation they came from, so the result holds its
+//! own references and no longer borrows anything.
+//!
+//! With the `std` feature, `slab_slice_arc()` also lets plain `SlabSliceArc`
+//! fields be deserialized this way, with `#[serde(deserialize_with)]`.
+
+use core::ops::Deref;
+
+use postcard::Error;
+use serde::Deserialize;
+
+use crate::managed_arc_slab::Reroot;
+use crate::slab_arc::{RerooterKey, SlabArc};
+use crate::slab_slice_arc::SlabSliceArc;
+
+#[cfg(feature = "std")]
+pub use self::root::slab_slice_arc;
+
+/// Deserialize a `T` from the start of `arc`, returning an owned value.
+///
+/// Any trailing bytes are ignored. Fails with `SerdeDeCustom` if `T`
+/// borrowed anything that could not be rerooted to `arc`.
+///
+/// ## Example
+///
+/// ```rust
+/// use byte_slab::{from_slab_arc, BSlab, ManagedArcSlab};
+///
+/// static SLAB: BSlab<4, 128> = BSlab::new();
+///
+/// fn main() {
+///     SLAB.init().unwrap();
+///
+///     let msg: ManagedArcSlab<4, 128> = ManagedArcSlab::from_slice(&[1, 2, 3]);
+///     let mut sbox = SLAB.alloc_box().unwrap();
+///     postcard::to_slice(&msg, &mut sbox[..]).unwrap();
+///
+///     let arc = sbox.into_arc();
+///     let owned = from_slab_arc::<ManagedArcSlab<4, 128>, 4, 128>(&arc).unwrap();
+///     drop(arc);
+///
+///     // The message keeps the allocation alive by itself
+///     assert_eq!(&*owned, &[1, 2, 3]);
+///     assert!(matches!(owned, ManagedArcSlab::Owned(_)));
+/// }
+/// ```
+pub fn from_slab_arc<'a, T, const N: usize, const SZ: usize>(
+    arc: &'a SlabArc<N, SZ>,
+) -> postcard::Result<T::Retval>
+where
+    T: Deserialize<'a> + Reroot,
+{
+    from_key_bytes::<T>(&arc.rerooter_key(), arc.deref())
+}
+
+/// Deserialize a `T` from `ssa`, returning an owned value.
+///
+/// See `from_slab_arc()`.
+pub fn from_slab_slice_arc<'a, T, const N: usize, const SZ: usize>(
+    ssa: &'a SlabSliceArc<N, SZ>,
+) -> postcard::Result<T::Retval>
+where
+    T: Deserialize<'a> + Reroot,
+{
+    from_key_bytes::<T>(&ssa.arc.rerooter_key(), ssa.deref())
+}
+
+fn from_key_bytes<'a, T>(key: &RerooterKey<'a>, bytes: &'a [u8]) -> postcard::Result<T::Retval>
+where
+    T: Deserialize<'a> + Reroot,
+{
+    #[cfg(feature = "std")]
+    let t: T = root::with_root(key, || postcard::from_bytes(bytes))?;
+    #[cfg(not(feature = "std"))]
+    let t: T = postcard::from_bytes(bytes)?;
+
+    t.reroot(key).map_err(|_| Error::SerdeDeCustom)
+}
+
+#[cfg(feature = "std")]
+mod root {
+    use core::{any::TypeId, cell::Cell, mem::ManuallyDrop};
+
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    use crate::{
+        byte_slab::BSlab,
+        slab_arc::{RerooterKey, SlabArc},
+        slab_slice_arc::SlabSliceArc,
+    };
+
+    /// The allocation being deserialized from, as a `RerooterKey` without
+    /// its lifetime
+    #[derive(Clone, Copy)]
+    struct Root {
+        start: usize,
+        end: usize,
+        slab: *const (),
+        slab_type: TypeId,
+        idx: usize,
+    }
+
+    std::thread_local! {
+        static ROOT: Cell<Option<Root>> = const { Cell::new(None) };
+    }
+
+    /// Restores the outer root, once a (possibly nested) call is done
+    struct RestoreRoot(Option<Root>);
+
+    impl Drop for RestoreRoot {
+        fn drop(&mut self) {
+            ROOT.with(|root| root.set(self.0));
+        }
+    }
+
+    /// Run `f`, with `key` as the root of this thread's `slab_slice_arc()`
+    pub(super) fn with_root<R>(key: &RerooterKey<'_>, f: impl FnOnce() -> R) -> R {
+        let root = Root {
+            start: key.start as usize,
+            end: key.end as usize,
+            slab: key.slab,
+            slab_type: key.slab_type,
+            idx: key.idx,
+        };
+        let _restore = RestoreRoot(ROOT.with(|cell| cell.replace(Some(root))));
+        f()
+    }
+
+    /// Deserialize a `SlabSliceArc` field, for use with
+    /// `#[serde(deserialize_with = "byte_slab::de::slab_slice_arc")]`.
+    ///
+    /// The field must be deserialized with `from_slab_arc()` or
+    /// `from_slab_slice_arc()`, and becomes a view of the allocation it was
+    /// read from. Anything else fails with a custom error. Only available
+    /// with the `std` feature, as the allocation is passed to this function
+    /// in a thread local.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use byte_slab::{from_slab_arc, BSlab, Reroot, RerooterKey, SlabSliceArc};
+    /// use serde::Deserialize;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Contains {
+    ///     #[serde(deserialize_with = "byte_slab::de::slab_slice_arc")]
+    ///     data: SlabSliceArc<4, 128>,
+    /// }
+    ///
+    /// impl Reroot for Contains {
+    ///     type Retval = Contains;
+    ///
+    ///     fn reroot(self, _key: &RerooterKey) -> Result<Contains, ()> {
+    ///         Ok(self)
+    ///     }
+    /// }
+    ///
+    /// static SLAB: BSlab<4, 128> = BSlab::new();
+    ///
+    /// fn main() {
+    ///     SLAB.init().unwrap();
+    ///
+    ///     let mut sbox = SLAB.alloc_box().unwrap();
+    ///     postcard::to_slice(&[1u8, 2, 3][..], &mut sbox[..]).unwrap();
+    ///
+    ///     let arc = sbox.into_arc();
+    ///     let owned = from_slab_arc::<Contains, 4, 128>(&arc).unwrap();
+    ///     drop(arc);
+    ///
+    ///     assert_eq!(&*owned.data, &[1, 2, 3]);
+    ///
+    ///     // Not from a slab
+    ///     assert!(postcard::from_bytes::<Contains>(&[3, 1, 2, 3]).is_err());
+    /// }
+    /// ```
+    pub fn slab_slice_arc<'de, D, const N: usize, const SZ: usize>(
+        deserializer: D,
+    ) -> Result<SlabSliceArc<N, SZ>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let bytes: &'de [u8] = Deserialize::deserialize(deserializer)?;
+
+        ROOT.with(|root| root.get())
+            .and_then(|root| root.slice_arc(bytes))
+            .ok_or_else(|| D::Error::custom("not deserialized from a slab"))
+    }
+
+    impl Root {
+        /// A view of `bytes` in this root, if they are in it
+        fn slice_arc<const N: usize, const SZ: usize>(
+            &self,
+            bytes: &[u8],
+        ) -> Option<SlabSliceArc<N, SZ>> {
+            if self.slab_type != TypeId::of::<BSlab<N, SZ>>() {
+                return None;
+            }
+
+            let b_start = bytes.as_ptr() as usize;
+            let offset = if bytes.is_empty() {
+                0
+            } else if (self.start <= b_start) && (b_start + bytes.len() <= self.end) {
+                b_start - self.start
+            } else {
+                return None;
+            };
+
+            // The root arc is still held by `from_slab_arc()`, this takes
+            // another reference to it
+            let bslab: &'static BSlab<N, SZ> = unsafe { &*self.slab.cast::<BSlab<N, SZ>>() };
+            let root = ManuallyDrop::new(SlabArc {
+                slab: bslab,
+                idx: self.idx,
+            });
+            root.sub_slice_arc(offset, bytes.len()).ok()
+        }
+    }
+}
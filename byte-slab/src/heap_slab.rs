@@ -0,0 +1,230 @@
+//! A `BSlab` on the heap, for tests and host-side tooling
+//!
+//! Only available with the `std` feature. A `HeapSlab` hands out the same
+//! `SlabBox`es as a static `BSlab`, but its storage is freed once the last
+//! handle, box and arc referring to it are gone, instead of being leaked.
+//! Code that takes a `SlabHandle` works with either kind of slab.
+
+use crate::atomic::{AtomicUsize, Ordering};
+use crate::byte_slab::BSlab;
+use crate::slab_box::SlabBox;
+use crate::slab_handle::SlabHandle;
+use core::{future::Future, ops::Deref};
+use std::boxed::Box;
+
+/// A reference counted handle to a heap allocated `BSlab`
+///
+/// Each handle, and each allocated element, keeps the slab alive. Cloning a
+/// handle is cheap, and all clones allocate from the same slab.
+///
+/// A `HeapSlab` may be turned into a `SlabHandle`, for code that also needs
+/// to work with static slabs.
+///
+/// ## Example
+///
+/// ```rust
+/// use byte_slab::HeapSlab;
+///
+/// let slab: HeapSlab<4, 128> = HeapSlab::new();
+/// let mut sbox = slab.alloc_box().unwrap();
+/// sbox[0] = 42;
+///
+/// // The slab stays around until the box is dropped too
+/// drop(slab);
+/// assert_eq!(sbox[0], 42);
+/// ```
+#[derive(Clone)]
+pub struct HeapSlab<const N: usize, const SZ: usize> {
+    handle: SlabHandle<N, SZ>,
+}
+
+impl<const N: usize, const SZ: usize> HeapSlab<N, SZ> {
+    /// Allocate and initialize a new slab
+    pub fn new() -> Self {
+        let slab: &'static BSlab<N, SZ> = Box::leak(Box::new(BSlab::new()));
+        slab.heap_refs.start();
+
+        // A brand new slab has never been initialized
+        let _ = slab.init();
+
+        Self {
+            handle: SlabHandle { slab },
+        }
+    }
+
+    /// Allocate a new Box of `SZ`.
+    ///
+    /// This function will return `None` if there are no pages available.
+    #[track_caller]
+    pub fn alloc_box(&self) -> Option<SlabBox<N, SZ>> {
+        self.handle.alloc_box()
+    }
+
+    /// Allocate a new Box of `SZ`, waiting for one to be freed if there are
+    /// no pages available. See `BSlab::alloc_box_async()`.
+    #[track_caller]
+    pub fn alloc_box_async(&self) -> impl Future<Output = SlabBox<N, SZ>> + '_ {
+        self.handle.alloc_box_async()
+    }
+
+    /// Get a handle to this slab, which keeps it alive too
+    pub fn handle(&self) -> SlabHandle<N, SZ> {
+        self.handle.clone()
+    }
+
+    /// The number of handles and allocated elements keeping this slab alive,
+    /// including this one. The slab is freed when this one is dropped, if
+    /// this returns 1.
+    pub fn ref_count(&self) -> usize {
+        self.handle.slab.heap_refs.count()
+    }
+}
+
+impl<const N: usize, const SZ: usize> Default for HeapSlab<N, SZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const SZ: usize> From<HeapSlab<N, SZ>> for SlabHandle<N, SZ> {
+    fn from(slab: HeapSlab<N, SZ>) -> Self {
+        slab.handle
+    }
+}
+
+impl<const N: usize, const SZ: usize> Deref for HeapSlab<N, SZ> {
+    type Target = BSlab<N, SZ>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+/// The number of handles and allocated elements keeping a heap slab alive.
+///
+/// Always zero for a static `BSlab`, which is never freed.
+pub(crate) struct HeapRefs {
+    refs: AtomicUsize,
+}
+
+impl HeapRefs {
+    pub(crate) const fn new() -> Self {
+        Self {
+            refs: AtomicUsize::new(0),
+        }
+    }
+
+    /// Mark the slab as heap allocated, with one handle
+    fn start(&self) {
+        self.refs.store(1, Ordering::SeqCst);
+    }
+
+    /// Add a reference, if the slab is heap allocated. Only called while
+    /// holding a reference already, so a heap slab can't be at zero.
+    pub(crate) fn acquire(&self) {
+        if self.refs.load(Ordering::SeqCst) != 0 {
+            self.refs.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.refs.load(Ordering::SeqCst)
+    }
+
+    /// Drop a reference, returning true if it was the last one
+    pub(crate) fn release(&self) -> bool {
+        if self.refs.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+
+        self.refs.fetch_sub(1, Ordering::SeqCst) == 1
+    }
+}
+
+impl<const N: usize, const SZ: usize> BSlab<N, SZ> {
+    /// Drop a reference to a heap slab, freeing it if it was the last one.
+    ///
+    /// The slab must not be used after this call.
+    pub(crate) unsafe fn release_heap_ref(&self) {
+        if self.heap_refs.release() {
+            let slab: *const Self = self;
+            drop(Box::from_raw(slab as *mut Self));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{thread, vec::Vec};
+
+    #[test]
+    fn slabs_are_independent() {
+        let hdls: Vec<_> = (0..8u8)
+            .map(|i| {
+                thread::spawn(move || {
+                    let slab: HeapSlab<4, 64> = HeapSlab::new();
+                    let allocs: Vec<_> = (0..4)
+                        .map(|_| {
+                            let mut sbox = slab.alloc_box().unwrap();
+                            sbox.iter_mut().for_each(|b| *b = i);
+                            sbox
+                        })
+                        .collect();
+
+                    assert!(slab.alloc_box().is_none());
+                    assert!(allocs.iter().all(|sbox| sbox.iter().all(|b| *b == i)));
+                })
+            })
+            .collect();
+
+        hdls.into_iter().for_each(|hdl| hdl.join().unwrap());
+    }
+
+    #[test]
+    fn outlives_handles() {
+        let slab: HeapSlab<2, 64> = HeapSlab::new();
+        let slab_2 = slab.clone();
+
+        let mut sbox = slab.alloc_box().unwrap();
+        sbox[0] = 1;
+        let arc = sbox.into_arc();
+        let arc_2 = arc.clone();
+
+        drop(slab);
+        assert!(slab_2.alloc_box().is_some());
+        drop(slab_2);
+
+        drop(arc);
+        assert_eq!(arc_2[0], 1);
+    }
+
+    #[test]
+    fn handles_keep_slab() {
+        let slab: HeapSlab<2, 64> = HeapSlab::new();
+        let handle = slab.handle();
+        let handle_2: SlabHandle<2, 64> = slab.into();
+
+        let sbox = handle.alloc_box().unwrap();
+        drop(handle);
+        assert!(handle_2.alloc_box().is_some());
+        drop(handle_2);
+        assert_eq!(sbox.len(), 64);
+    }
+
+    #[test]
+    fn counts_refs() {
+        let slab: HeapSlab<2, 64> = HeapSlab::new();
+        assert_eq!(slab.ref_count(), 1);
+
+        let handle = slab.handle();
+        let arc = slab.alloc_box().unwrap().into_arc();
+        let arc_2 = arc.clone();
+        assert_eq!(slab.ref_count(), 3);
+
+        drop(handle);
+        drop(arc);
+        drop(arc_2);
+        assert_eq!(slab.ref_count(), 1);
+    }
+}
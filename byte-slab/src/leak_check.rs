@@ -0,0 +1,65 @@
+//! Finding slab elements that are never freed
+//!
+//! Only available with the `leak_check` feature. Each allocation records the
+//! source location of the `alloc_box()` (or `alloc_box_async()`) call that
+//! made it, which is forgotten again when the element is freed. This is
+//! intended for tests and debug builds, as it costs a pointer per element.
+
+use crate::atomic::{AtomicPtr, Ordering};
+use core::{fmt, panic::Location, ptr::null_mut};
+
+/// An element of a `BSlab` that is still in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outstanding {
+    /// The index of the element in the slab
+    pub idx: usize,
+
+    /// Where the element was allocated. This is `None` if the element was
+    /// allocated while the location was being recorded
+    pub site: Option<&'static Location<'static>>,
+}
+
+impl fmt::Display for Outstanding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.site {
+            Some(site) => write!(f, "element {} (allocated at {})", self.idx, site),
+            None => write!(f, "element {} (allocated at an unknown site)", self.idx),
+        }
+    }
+}
+
+/// The allocation site of each element, kept by the `BSlab`
+pub(crate) struct AllocSites<const N: usize> {
+    sites: [AtomicPtr<Location<'static>>; N],
+}
+
+impl<const N: usize> AllocSites<N> {
+    pub(crate) const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NO_SITE: AtomicPtr<Location<'static>> = AtomicPtr::new(null_mut());
+
+        Self {
+            sites: [NO_SITE; N],
+        }
+    }
+
+    pub(crate) fn allocated(&self, idx: usize, site: &'static Location<'static>) {
+        if let Some(slot) = self.sites.get(idx) {
+            let site: *const Location<'static> = site;
+            slot.store(site as *mut _, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn freed(&self, idx: usize) {
+        if let Some(slot) = self.sites.get(idx) {
+            slot.store(null_mut(), Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn site(&self, idx: usize) -> Option<&'static Location<'static>> {
+        let site = self.sites.get(idx)?.load(Ordering::SeqCst);
+
+        // Only ever set from a `&'static Location`
+        unsafe { site.as_ref() }
+    }
+}
@@ -4,8 +4,9 @@
 //! fixed-size chunks. It is similar to heapless::Pool, however it also allows conversion
 //! of the allocations (`SlabBox`es) into shared, reference counted objects (`SlabArc`s).
 //!
-//! Currently, it maintains its free list as an MPMC queue, though that is an implementation
-//! detail that may change. This implementation is convenient, but not particularly memory-dense.
+//! By default, it maintains its free list as an MPMC queue. This implementation is convenient,
+//! but not particularly memory-dense. The `atomic_free_list` feature instead uses the reference
+//! count of each element as the free list, which takes no extra memory and needs no `init()`.
 //!
 //! The slab is statically allocated, and the size of each Box, as well as the total number of
 //! Boxes available is selected through compile time `const` values.
@@ -35,6 +36,16 @@
 //!     is freed for reuse automatically when the reference count reaches zero.
 //! * `ManagedArcSlab` - a convenience type that may contain EITHER a borrowed `&[u8]` slice,
 //!     or a `SlabSliceArc`.
+//! * `SlabWriter` - a cursor for writing into a `SlabBox`, which yields a `SlabSliceArc` of
+//!     exactly the written bytes. With the `postcard` feature, it can also serialize messages.
+//! * `SlabChain` - a chain of `SlabSliceArc`s acting as one buffer, for data larger than a
+//!     single element. It may be iterated, serialized, or copied into one contiguous buffer.
+//! * `SizedSlab` - a handle to three `BSlab`s of different element sizes, which hands out
+//!     allocations from the smallest one that fits.
+//! * `HeapSlab` - a reference counted `BSlab` on the heap, which is freed once it is no
+//!     longer used. Only available with the `std` feature, for tests and tooling.
+//! * `SlabHandle` - a handle to either a static `BSlab` or a `HeapSlab`, for code that
+//!     allocates from a slab without caring where it is stored.
 //!
 //! ## Example
 //!
@@ -75,22 +86,61 @@
 //! This probably does not handle unwind safety correctly!
 //! Please verify before using in non-abort-panic environments!
 
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
+pub mod any_slab;
 pub mod byte_slab;
 pub mod slab_arc;
 pub mod slab_box;
 pub mod slab_slice_arc;
 pub mod managed_arc_slab;
+pub mod sized_slab;
+pub mod slab_writer;
+pub mod slab_chain;
+pub mod slab_handle;
+#[cfg(feature = "stats")]
+pub mod stats;
+#[cfg(feature = "leak_check")]
+pub mod leak_check;
+#[cfg(feature = "std")]
+pub mod heap_slab;
+#[cfg(feature = "postcard")]
+pub mod de;
+pub mod waiters;
+
+// Targets without compare-and-swap (like thumbv6m) get their atomics from a
+// critical section instead
+#[cfg(feature = "atomic-polyfill")]
+pub(crate) use atomic_polyfill as atomic;
+#[cfg(not(feature = "atomic-polyfill"))]
+pub(crate) use core::sync::atomic;
 
 pub use crate::{
+    any_slab::{AnyAlloc, AnyArc, AnyBox, AnySliceArc},
     byte_slab::BSlab,
     slab_arc::{SlabArc, RerooterKey},
     slab_box::SlabBox,
     slab_slice_arc::{SlabSliceArc, SlabStrArc},
     managed_arc_slab::{ManagedArcSlab, ManagedArcStr, Reroot},
+    sized_slab::{SizedArc, SizedBox, SizedSlab, SizedSliceArc, SliceError},
+    slab_writer::{SlabWriter, WriteError},
+    slab_chain::{ChainError, SlabChain},
+    slab_handle::SlabHandle,
+    waiters::{Waiters, MAX_ALLOC_WAITERS},
 };
 
+#[cfg(feature = "stats")]
+pub use crate::stats::SlabStats;
+
+#[cfg(feature = "leak_check")]
+pub use crate::leak_check::Outstanding;
+
+#[cfg(feature = "std")]
+pub use crate::heap_slab::HeapSlab;
+
+#[cfg(feature = "postcard")]
+pub use crate::de::{from_slab_arc, from_slab_slice_arc};
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -191,4 +241,163 @@ mod tests {
         drop(sl_2_2);
         assert!(SLAB.alloc_box().is_some());
     }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        static SLAB: BSlab<4, 64> = BSlab::new();
+        SLAB.init().unwrap();
+        assert_eq!(SLAB.free_count(), 4);
+
+        let box_1 = SLAB.alloc_box().unwrap();
+        let arc_2 = SLAB.alloc_box().unwrap().into_arc();
+        let arc_2_2 = arc_2.clone();
+        let box_3 = SLAB.alloc_box().unwrap();
+        assert_eq!(SLAB.free_count(), 1);
+
+        drop(box_1);
+        drop(arc_2);
+        assert_eq!(SLAB.free_count(), 2);
+        drop(arc_2_2);
+        drop(box_3);
+
+        let all: Vec<_> = (0..4).map(|_| SLAB.alloc_box().unwrap()).collect();
+        assert!(SLAB.alloc_box().is_none());
+        assert!(SLAB.alloc_box().is_none());
+        drop(all);
+
+        assert_eq!(
+            SLAB.stats(),
+            SlabStats {
+                free_count: 4,
+                high_water_used: 4,
+                alloc_failures: 2,
+                total_allocs: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn async_alloc_waits_for_free() {
+        use std::{
+            future::Future,
+            pin::Pin,
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Arc,
+            },
+            task::{Context, Poll, Wake, Waker},
+        };
+
+        struct Flag(AtomicBool);
+
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        static SLAB: BSlab<2, 64> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let box_1 = SLAB.alloc_box().unwrap();
+        let arc_2 = SLAB.alloc_box().unwrap().into_arc();
+        let arc_2_2 = arc_2.clone();
+
+        let mut fut = Box::pin(SLAB.alloc_box_async());
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        // Dropping one reference of an arc doesn't free anything
+        drop(arc_2);
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        drop(arc_2_2);
+        assert!(flag.0.load(Ordering::SeqCst));
+
+        let box_2 = match Pin::new(&mut fut).poll(&mut cx) {
+            Poll::Ready(sbox) => sbox,
+            Poll::Pending => panic!("Not ready!"),
+        };
+
+        // Waiting again, woken by dropping a box this time
+        flag.0.store(false, Ordering::SeqCst);
+        let mut fut = Box::pin(SLAB.alloc_box_async());
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+        drop(box_1);
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_ready());
+
+        drop(box_2);
+    }
+
+    #[cfg(feature = "atomic_free_list")]
+    #[test]
+    fn no_init_needed() {
+        static SLAB: BSlab<4, 64> = BSlab::new();
+        assert!(SLAB.is_init().is_ok());
+
+        let mut allocs = vec![];
+        for i in 0..4 {
+            let mut alloc_box = SLAB.alloc_box().unwrap();
+            assert!(alloc_box.iter().all(|b| *b == 0));
+            alloc_box[0] = i;
+            allocs.push(alloc_box);
+        }
+        assert!(SLAB.alloc_box().is_none());
+
+        // Freeing from the middle makes exactly that element available
+        let arc = allocs.remove(1).into_arc();
+        let arc_2 = arc.clone();
+        drop(arc);
+        assert!(SLAB.alloc_box().is_none());
+        drop(arc_2);
+
+        let alloc_box = SLAB.alloc_box().unwrap();
+        assert_eq!(alloc_box[0], 1);
+        assert!(SLAB.alloc_box().is_none());
+
+        // Initializing is still allowed, once
+        assert!(SLAB.init().is_ok());
+        assert!(SLAB.init().is_err());
+    }
+
+    #[cfg(feature = "leak_check")]
+    #[test]
+    fn leak_check() {
+        static SLAB: BSlab<4, 64> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let line = line!() + 1;
+        let box_1 = SLAB.alloc_box().unwrap();
+        let arc_2 = SLAB.alloc_box().unwrap().into_arc();
+        let arc_2_2 = arc_2.clone();
+        drop(arc_2);
+
+        let outstanding: Vec<Outstanding> = SLAB.outstanding().collect();
+        assert_eq!(outstanding.len(), 2);
+        let site = outstanding[0].site.unwrap();
+        assert_eq!(site.file(), file!());
+        assert_eq!(site.line(), line);
+        assert_eq!(outstanding[1].site.unwrap().line(), line + 1);
+
+        drop(box_1);
+        drop(arc_2_2);
+        SLAB.assert_all_freed();
+    }
+
+    #[cfg(feature = "leak_check")]
+    #[test]
+    #[should_panic(expected = "1 slab element(s) were never freed")]
+    fn leak_check_panics() {
+        static SLAB: BSlab<2, 64> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let _box_1 = SLAB.alloc_box().unwrap();
+        SLAB.assert_all_freed();
+    }
 }
@@ -115,17 +115,22 @@ impl<'a, const N: usize, const SZ: usize> ManagedArcSlab<'a, N, SZ> {
         match self {
             ManagedArcSlab::Owned(e) => Some(ManagedArcSlab::Owned(e)),
             ManagedArcSlab::Borrowed(b) => {
-                if b.is_empty() {
-                    // TODO: nuance
-                    return None;
-                }
-
                 // TODO: yolo ub
                 let start: usize = key.start as usize;
                 let end: usize = key.end as usize;
                 let b_start: usize = b.as_ptr() as usize;
 
-                if (start <= b_start) && (b_start < end) {
+                // An empty slice may point anywhere, so it becomes an empty
+                // view of the start of the root instead
+                let offset = if b.is_empty() {
+                    Some(0)
+                } else if (start <= b_start) && (b_start < end) {
+                    Some(b_start - start)
+                } else {
+                    None
+                };
+
+                if let Some(offset) = offset {
                     let bslab: &'static BSlab<N, SZ> = unsafe { &*key.slab.cast::<BSlab<N, SZ>>() };
 
                     // NOTE: We *don't* increase the refcount for this arc, as it technically aliases the
@@ -137,7 +142,7 @@ impl<'a, const N: usize, const SZ: usize> ManagedArcSlab<'a, N, SZ> {
                     };
 
                     let ssa = arc
-                        .sub_slice_arc(b_start - start, b.len())
+                        .sub_slice_arc(offset, b.len())
                         .ok()?;
 
                     // Okay, now forget that arc ever happened
@@ -260,17 +265,22 @@ impl<'a, const N: usize, const SZ: usize> ManagedArcStr<'a, N, SZ> {
         match self {
             ManagedArcStr::Owned(e) => Some(ManagedArcStr::Owned(e)),
             ManagedArcStr::Borrowed(b) => {
-                if b.is_empty() {
-                    // TODO: nuance
-                    return None;
-                }
-
                 // TODO: yolo ub
                 let start: usize = key.start as usize;
                 let end: usize = key.end as usize;
                 let b_start: usize = b.as_ptr() as usize;
 
-                if (start <= b_start) && (b_start < end) {
+                // An empty slice may point anywhere, so it becomes an empty
+                // view of the start of the root instead
+                let offset = if b.is_empty() {
+                    Some(0)
+                } else if (start <= b_start) && (b_start < end) {
+                    Some(b_start - start)
+                } else {
+                    None
+                };
+
+                if let Some(offset) = offset {
                     let bslab: &'static BSlab<N, SZ> = unsafe { &*key.slab.cast::<BSlab<N, SZ>>() };
 
                     // NOTE: We *don't* increase the refcount for this arc, as it technically aliases the
@@ -282,7 +292,7 @@ impl<'a, const N: usize, const SZ: usize> ManagedArcStr<'a, N, SZ> {
                     };
 
                     let ssa = arc
-                        .sub_slice_arc(b_start - start, b.len())
+                        .sub_slice_arc(offset, b.len())
                         .ok()?
                         .into_str_arc()
                         .ok()?;
@@ -353,6 +363,24 @@ reroot_nop!([i8, i16, i32, i64]);
 reroot_nop!([bool, char, ()]);
 reroot_nop!([f32, f64]);
 
+impl<const N: usize, const SZ: usize> Reroot for SlabArc<N, SZ> {
+    type Retval = SlabArc<N, SZ>;
+
+    #[inline(always)]
+    fn reroot(self, _key: &RerooterKey) -> Result<Self::Retval, ()> {
+        Ok(self)
+    }
+}
+
+impl<const N: usize, const SZ: usize> Reroot for SlabSliceArc<N, SZ> {
+    type Retval = SlabSliceArc<N, SZ>;
+
+    #[inline(always)]
+    fn reroot(self, _key: &RerooterKey) -> Result<Self::Retval, ()> {
+        Ok(self)
+    }
+}
+
 impl<T, E> Reroot for Result<T, E>
 where
     T: Reroot,
@@ -400,7 +428,7 @@ mod test {
         let arc_1 = sbox.into_arc();
 
         let brw = ManagedArcSlab::<4, 128>::Borrowed(&arc_1[..4]);
-        let own: ManagedArcSlab<'static, 4, 128> = brw.reroot(&arc_1).unwrap();
+        let own: ManagedArcSlab<'static, 4, 128> = brw.reroot(&arc_1.rerooter_key()).unwrap();
 
         match own {
             ManagedArcSlab::Owned(ssa) => {
@@ -408,6 +436,34 @@ mod test {
             }
             _ => panic!("Not owned!"),
         }
+
+        // Empty slices don't need to point into the arc
+        let brw = ManagedArcSlab::<4, 128>::Borrowed(&[]);
+        let own = brw.reroot(&arc_1.rerooter_key()).unwrap();
+        assert!(matches!(own, ManagedArcSlab::Owned(ref ssa) if ssa.is_empty()));
     }
 
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn from_slab_arc() {
+        use crate::{from_slab_slice_arc, ManagedArcStr, SlabWriter};
+
+        static SLAB: BSlab<4, 128> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let msg: Option<ManagedArcStr<4, 128>> = Some(ManagedArcStr::from_slice("hello"));
+        let ssa = SlabWriter::serialize(&msg, SLAB.alloc_box().unwrap()).unwrap();
+
+        let owned = from_slab_slice_arc::<Option<ManagedArcStr<4, 128>>, 4, 128>(&ssa).unwrap();
+        drop(ssa);
+
+        match owned {
+            Some(ManagedArcStr::Owned(sa)) => assert_eq!(&*sa, "hello"),
+            _ => panic!("Not owned!"),
+        }
+
+        // Bad data is reported, rather than panicking
+        let ssa = SlabWriter::serialize(&[0xFFu8; 4][..], SLAB.alloc_box().unwrap()).unwrap();
+        assert!(from_slab_slice_arc::<ManagedArcStr<4, 128>, 4, 128>(&ssa).is_err());
+    }
 }
@@ -0,0 +1,520 @@
+//! A slab allocator with several size classes
+//!
+//! A `SizedSlab` hands out allocations from three `BSlab`s of different
+//! element sizes, picking the smallest one that fits the requested length.
+//! This avoids spending a large element on a small message.
+//!
+//! `SizedBox`, `SizedArc` and `SizedSliceArc` are enums over the `SlabBox`,
+//! `SlabArc` and `SlabSliceArc` of each class, and behave like them. Match on
+//! the variant to get at the allocation of a specific class, for example to
+//! build a `ManagedArcSlab` from it, or convert them into an `AnyBox`,
+//! `AnyArc` or `AnySliceArc` to hold them without naming the classes.
+
+use core::ops::{Deref, DerefMut};
+
+use serde::Serialize;
+
+use crate::{
+    any_slab::{AnyAlloc, AnyArc, AnyBox, AnySliceArc},
+    byte_slab::BSlab,
+    slab_arc::SlabArc,
+    slab_box::SlabBox,
+    slab_slice_arc::SlabSliceArc,
+};
+
+// Apply the same expression to whichever class the value is
+macro_rules! each_class {
+    ($kind:ident, $val:expr, $inner:ident => $body:expr) => {
+        match $val {
+            $kind::Small($inner) => $body,
+            $kind::Medium($inner) => $body,
+            $kind::Large($inner) => $body,
+        }
+    };
+}
+
+// Apply the same expression to whichever class the value is, keeping the class
+macro_rules! map_class {
+    ($kind:ident => $out:ident, $val:expr, $inner:ident => $body:expr) => {
+        match $val {
+            $kind::Small($inner) => $out::Small($body),
+            $kind::Medium($inner) => $out::Medium($body),
+            $kind::Large($inner) => $out::Large($body),
+        }
+    };
+}
+
+/// An error taking a view of a sized allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SliceError {
+    /// `start` and `len` reach past the end of the allocation
+    OutOfBounds,
+}
+
+/// Three `BSlab`s of increasing element size, behind one handle
+///
+/// The `BSlab`s are separate static items, and must each be initialized
+/// before use. `S1`, `S2` and `S3` should be in increasing order.
+///
+/// ## Example
+///
+/// ```rust
+/// use byte_slab::{BSlab, SizedSlab};
+///
+/// static SMALL: BSlab<32, 64> = BSlab::new();
+/// static MEDIUM: BSlab<8, 256> = BSlab::new();
+/// static LARGE: BSlab<4, 1024> = BSlab::new();
+///
+/// static SLAB: SizedSlab<32, 64, 8, 256, 4, 1024> = SizedSlab::new(&SMALL, &MEDIUM, &LARGE);
+///
+/// fn main() {
+///     SMALL.init().unwrap();
+///     MEDIUM.init().unwrap();
+///     LARGE.init().unwrap();
+///
+///     let sbox = SLAB.alloc_box(4).unwrap();
+///     assert_eq!(sbox.len(), 64);
+///
+///     let sbox = SLAB.alloc_box(300).unwrap();
+///     assert_eq!(sbox.len(), 1024);
+///
+///     // Nothing is big enough for this one
+///     assert!(SLAB.alloc_box(2048).is_none());
+/// }
+/// ```
+pub struct SizedSlab<
+    const N1: usize,
+    const S1: usize,
+    const N2: usize,
+    const S2: usize,
+    const N3: usize,
+    const S3: usize,
+> {
+    small: &'static BSlab<N1, S1>,
+    medium: &'static BSlab<N2, S2>,
+    large: &'static BSlab<N3, S3>,
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > SizedSlab<N1, S1, N2, S2, N3, S3>
+{
+    pub const fn new(
+        small: &'static BSlab<N1, S1>,
+        medium: &'static BSlab<N2, S2>,
+        large: &'static BSlab<N3, S3>,
+    ) -> Self {
+        Self {
+            small,
+            medium,
+            large,
+        }
+    }
+
+    /// Allocate a Box of at least `len` bytes.
+    ///
+    /// The smallest class that fits is used, or the next larger one if that
+    /// class has no pages available. This function will return `None` if
+    /// `len` is larger than the largest class, or if no class that fits has
+    /// pages available.
+    #[track_caller]
+    pub fn alloc_box(&self, len: usize) -> Option<SizedBox<N1, S1, N2, S2, N3, S3>> {
+        if len <= S1 {
+            if let Some(sbox) = self.small.alloc_box() {
+                return Some(SizedBox::Small(sbox));
+            }
+        }
+
+        if len <= S2 {
+            if let Some(sbox) = self.medium.alloc_box() {
+                return Some(SizedBox::Medium(sbox));
+            }
+        }
+
+        if len <= S3 {
+            if let Some(sbox) = self.large.alloc_box() {
+                return Some(SizedBox::Large(sbox));
+            }
+        }
+
+        None
+    }
+
+    /// The largest allocation that may be requested
+    pub const fn max_len(&self) -> usize {
+        S3
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > AnyAlloc for SizedSlab<N1, S1, N2, S2, N3, S3>
+{
+    #[track_caller]
+    fn alloc_any(&'static self, len: usize) -> Option<AnyBox> {
+        self.alloc_box(len).map(AnyBox::from)
+    }
+}
+
+/// An owned allocation from one of the classes of a `SizedSlab`
+pub enum SizedBox<
+    const N1: usize,
+    const S1: usize,
+    const N2: usize,
+    const S2: usize,
+    const N3: usize,
+    const S3: usize,
+> {
+    Small(SlabBox<N1, S1>),
+    Medium(SlabBox<N2, S2>),
+    Large(SlabBox<N3, S3>),
+}
+
+/// A reference counted allocation from one of the classes of a `SizedSlab`
+#[derive(Clone)]
+pub enum SizedArc<
+    const N1: usize,
+    const S1: usize,
+    const N2: usize,
+    const S2: usize,
+    const N3: usize,
+    const S3: usize,
+> {
+    Small(SlabArc<N1, S1>),
+    Medium(SlabArc<N2, S2>),
+    Large(SlabArc<N3, S3>),
+}
+
+/// A partial view of an allocation from one of the classes of a `SizedSlab`
+#[derive(Clone)]
+pub enum SizedSliceArc<
+    const N1: usize,
+    const S1: usize,
+    const N2: usize,
+    const S2: usize,
+    const N3: usize,
+    const S3: usize,
+> {
+    Small(SlabSliceArc<N1, S1>),
+    Medium(SlabSliceArc<N2, S2>),
+    Large(SlabSliceArc<N3, S3>),
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > SizedBox<N1, S1, N2, S2, N3, S3>
+{
+    /// Convert the `SizedBox` into a `SizedArc` of the same class
+    pub fn into_arc(self) -> SizedArc<N1, S1, N2, S2, N3, S3> {
+        map_class!(SizedBox => SizedArc, self, sbox => sbox.into_arc())
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > Deref for SizedBox<N1, S1, N2, S2, N3, S3>
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        each_class!(SizedBox, self, sbox => &sbox[..])
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > DerefMut for SizedBox<N1, S1, N2, S2, N3, S3>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        each_class!(SizedBox, self, sbox => &mut sbox[..])
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > SizedArc<N1, S1, N2, S2, N3, S3>
+{
+    /// Create a `SizedSliceArc` with a full view of the underlying data
+    pub fn full_sub_slice_arc(&self) -> SizedSliceArc<N1, S1, N2, S2, N3, S3> {
+        map_class!(SizedArc => SizedSliceArc, self, arc => arc.full_sub_slice_arc())
+    }
+
+    /// Create a `SizedSliceArc` with a partial view of the underlying data.
+    ///
+    /// This function will fail if `start` and `len` do not describe a valid
+    /// region of the `SizedArc`.
+    pub fn sub_slice_arc(
+        &self,
+        start: usize,
+        len: usize,
+    ) -> Result<SizedSliceArc<N1, S1, N2, S2, N3, S3>, SliceError> {
+        let ssa = map_class!(SizedArc => SizedSliceArc, self, arc => {
+            arc.sub_slice_arc(start, len).map_err(|_| SliceError::OutOfBounds)?
+        });
+        Ok(ssa)
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > Deref for SizedArc<N1, S1, N2, S2, N3, S3>
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        each_class!(SizedArc, self, arc => &arc[..])
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > SizedSliceArc<N1, S1, N2, S2, N3, S3>
+{
+    /// Create a (smaller) `SizedSliceArc` from this one, with a partial view
+    /// of the underlying data.
+    ///
+    /// This function will fail if `start` and `len` do not describe a valid
+    /// region of the `SizedSliceArc`.
+    pub fn sub_slice_arc(
+        &self,
+        start: usize,
+        len: usize,
+    ) -> Result<SizedSliceArc<N1, S1, N2, S2, N3, S3>, SliceError> {
+        let ssa = map_class!(SizedSliceArc => SizedSliceArc, self, ssa => {
+            ssa.sub_slice_arc(start, len).map_err(|_| SliceError::OutOfBounds)?
+        });
+        Ok(ssa)
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > Deref for SizedSliceArc<N1, S1, N2, S2, N3, S3>
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        each_class!(SizedSliceArc, self, ssa => ssa.deref())
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > Serialize for SizedSliceArc<N1, S1, N2, S2, N3, S3>
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let data: &[u8] = self.deref();
+        data.serialize(serializer)
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > From<SizedBox<N1, S1, N2, S2, N3, S3>> for AnyBox
+{
+    fn from(sbox: SizedBox<N1, S1, N2, S2, N3, S3>) -> Self {
+        each_class!(SizedBox, sbox, sbox => sbox.into())
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > From<SizedArc<N1, S1, N2, S2, N3, S3>> for AnyArc
+{
+    fn from(arc: SizedArc<N1, S1, N2, S2, N3, S3>) -> Self {
+        each_class!(SizedArc, arc, arc => arc.into())
+    }
+}
+
+impl<
+        const N1: usize,
+        const S1: usize,
+        const N2: usize,
+        const S2: usize,
+        const N3: usize,
+        const S3: usize,
+    > From<SizedSliceArc<N1, S1, N2, S2, N3, S3>> for AnySliceArc
+{
+    fn from(ssa: SizedSliceArc<N1, S1, N2, S2, N3, S3>) -> Self {
+        each_class!(SizedSliceArc, ssa, ssa => ssa.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        AnyAlloc, AnySliceArc, BSlab, ManagedArcSlab, SizedBox, SizedSlab, SizedSliceArc,
+        SliceError,
+    };
+    use std::ops::Deref;
+
+    #[test]
+    fn picks_smallest_class() {
+        static SMALL: BSlab<2, 64> = BSlab::new();
+        static MEDIUM: BSlab<2, 256> = BSlab::new();
+        static LARGE: BSlab<2, 1024> = BSlab::new();
+        static SLAB: SizedSlab<2, 64, 2, 256, 2, 1024> = SizedSlab::new(&SMALL, &MEDIUM, &LARGE);
+
+        SMALL.init().unwrap();
+        MEDIUM.init().unwrap();
+        LARGE.init().unwrap();
+
+        let mut allocs = vec![];
+        for _ in 0..2 {
+            let sbox = SLAB.alloc_box(64).unwrap();
+            assert!(matches!(sbox, SizedBox::Small(_)));
+            allocs.push(sbox);
+        }
+
+        // Small is used up, move up a class
+        for _ in 0..2 {
+            let sbox = SLAB.alloc_box(4).unwrap();
+            assert!(matches!(sbox, SizedBox::Medium(_)));
+            allocs.push(sbox);
+        }
+
+        let large = SLAB.alloc_box(65).unwrap();
+        assert!(matches!(large, SizedBox::Large(_)));
+        allocs.push(large);
+        allocs.push(SLAB.alloc_box(1).unwrap());
+        assert!(SLAB.alloc_box(1).is_none());
+
+        // Freeing a small box doesn't help a large request
+        drop(allocs.remove(0));
+        assert!(SLAB.alloc_box(300).is_none());
+        assert!(matches!(SLAB.alloc_box(3), Some(SizedBox::Small(_))));
+
+        drop(allocs);
+        assert!(SLAB.alloc_box(1025).is_none());
+    }
+
+    #[test]
+    fn arcs_and_slices() {
+        static SMALL: BSlab<2, 64> = BSlab::new();
+        static MEDIUM: BSlab<2, 256> = BSlab::new();
+        static LARGE: BSlab<2, 1024> = BSlab::new();
+        static SLAB: SizedSlab<2, 64, 2, 256, 2, 1024> = SizedSlab::new(&SMALL, &MEDIUM, &LARGE);
+
+        SMALL.init().unwrap();
+        MEDIUM.init().unwrap();
+        LARGE.init().unwrap();
+
+        let mut sbox = SLAB.alloc_box(100).unwrap();
+        assert_eq!(sbox.len(), 256);
+        sbox[..4].copy_from_slice(&[1, 2, 3, 4]);
+
+        let arc = sbox.into_arc();
+        let ssa = arc.sub_slice_arc(1, 2).unwrap();
+        assert_eq!(&[2, 3], ssa.deref());
+        assert_eq!(arc.sub_slice_arc(200, 100).err(), Some(SliceError::OutOfBounds));
+
+        // The class allocation works with the usual types
+        let managed = match ssa {
+            SizedSliceArc::Medium(ssa) => ManagedArcSlab::<2, 256>::Owned(ssa),
+            _ => panic!("Wrong class!"),
+        };
+        assert_eq!(&[2, 3], managed.deref());
+
+        // Still held by the slice
+        let other = MEDIUM.alloc_box().unwrap();
+        drop(arc);
+        assert!(MEDIUM.alloc_box().is_none());
+        drop(managed);
+        assert!(MEDIUM.alloc_box().is_some());
+        drop(other);
+    }
+
+    #[test]
+    fn any_alloc() {
+        static SMALL: BSlab<1, 64> = BSlab::new();
+        static MEDIUM: BSlab<1, 256> = BSlab::new();
+        static LARGE: BSlab<1, 1024> = BSlab::new();
+        static SLAB: SizedSlab<1, 64, 1, 256, 1, 1024> = SizedSlab::new(&SMALL, &MEDIUM, &LARGE);
+
+        SMALL.init().unwrap();
+        MEDIUM.init().unwrap();
+        LARGE.init().unwrap();
+
+        let alloc: &'static dyn AnyAlloc = &SLAB;
+        let small = alloc.alloc_any(10).unwrap();
+        assert_eq!(small.len(), 64);
+        let large: AnySliceArc = SLAB
+            .alloc_box(300)
+            .unwrap()
+            .into_arc()
+            .sub_slice_arc(0, 300)
+            .unwrap()
+            .into();
+        assert_eq!(large.len(), 300);
+        assert!(LARGE.alloc_box().is_none());
+
+        drop(small);
+        drop(large);
+        assert!(SMALL.alloc_box().is_some());
+        assert!(LARGE.alloc_box().is_some());
+    }
+}
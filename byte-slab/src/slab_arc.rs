@@ -7,11 +7,12 @@
 //! for reuse automatically when the reference count reaches zero.
 
 use crate::slab_slice_arc::SlabSliceArc;
-use core::{
-    ops::Deref,
-    sync::atomic::Ordering,
-};
+use core::ops::Deref;
 use core::marker::PhantomData;
+#[cfg(feature = "std")]
+use core::any::TypeId;
+
+use crate::atomic::Ordering;
 
 use crate::byte_slab::BSlab;
 
@@ -66,6 +67,8 @@ pub struct RerooterKey<'a> {
     pub(crate) end: *const u8,
     pub(crate) pdlt: PhantomData<&'a ()>,
     pub(crate) slab: *const (),
+    #[cfg(feature = "std")]
+    pub(crate) slab_type: TypeId,
     pub(crate) idx: usize,
 }
 
@@ -88,6 +91,8 @@ impl<const N: usize, const SZ: usize> SlabArc<N, SZ> {
             end: unsafe { slice.as_ptr().add(slice.len()) },
             pdlt: PhantomData,
             slab: (self.slab as *const BSlab<N, SZ>).cast(),
+            #[cfg(feature = "std")]
+            slab_type: TypeId::of::<BSlab<N, SZ>>(),
             idx: self.idx,
         }
     }
@@ -120,14 +125,23 @@ impl<const N: usize, const SZ: usize> Drop for SlabArc<N, SZ> {
     fn drop(&mut self) {
         // drop refct
         let arc = unsafe { self.slab.get_idx_unchecked(self.idx).arc };
-        let refct = arc.fetch_sub(1, Ordering::SeqCst);
+        let mut refct = arc.load(Ordering::SeqCst);
+
+        loop {
+            #[cfg(feature = "leak_check")]
+            assert!(refct != 0, "SlabArc refcount underflow on element {}", self.idx);
 
-        // We just dropped the refct to zero. Release the structure
-        if refct == 1 {
-            if let Ok(q) = self.slab.get_q() {
-                while let Err(_) = q.enqueue(self.idx) {}
+            // We are the last reference, so nobody else can change the refct.
+            // Release the structure, which drops the refct to zero
+            if refct == 1 {
+                self.slab.free_idx(self.idx);
+                return;
             }
 
+            match arc.compare_exchange(refct, refct - 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return,
+                Err(cur) => refct = cur,
+            }
         }
     }
 }
@@ -7,10 +7,9 @@
 
 use core::ops::DerefMut;
 use core::ops::Deref;
-use core::{
-    mem::forget,
-    sync::atomic::Ordering,
-};
+use core::mem::forget;
+
+use crate::atomic::Ordering;
 
 use crate::byte_slab::BSlab;
 use crate::slab_arc::SlabArc;
@@ -56,15 +55,11 @@ impl<const N: usize, const SZ: usize> Drop for SlabBox<N, SZ> {
     fn drop(&mut self) {
         let arc = unsafe { self.slab.get_idx_unchecked(self.idx).arc };
 
-        // drop refct
-        let zero = arc.compare_exchange(1, 0, Ordering::SeqCst, Ordering::SeqCst);
+        // A box is the only reference, `free_idx()` drops the refct
         // TODO: Make debug assert?
-        assert!(zero.is_ok());
+        assert_eq!(arc.load(Ordering::SeqCst), 1);
 
-        // TODO: Why is this necessary?
-        if let Ok(q) = self.slab.get_q() {
-            while let Err(_) = q.enqueue(self.idx) {}
-        }
+        self.slab.free_idx(self.idx);
 
         // TODO: Zero on drop? As option?
     }
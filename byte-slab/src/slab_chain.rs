@@ -0,0 +1,277 @@
+//! A buffer spanning several slab elements
+//!
+//! A `SlabChain` links up to `C` `SlabSliceArc`s into one logical buffer, for
+//! data that is larger than a single element of the `BSlab`. The segments are
+//! not copied, unless the data is reassembled into one contiguous buffer.
+
+use core::iter::FusedIterator;
+
+use heapless::Vec;
+use serde::{ser::SerializeSeq, Serialize};
+
+use crate::byte_slab::BSlab;
+use crate::slab_slice_arc::SlabSliceArc;
+use crate::slab_writer::SlabWriter;
+
+/// An error building or copying a `SlabChain`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChainError {
+    /// The slab has no elements available
+    NoAlloc,
+
+    /// The data needs more than `C` segments, or more than `SZ` bytes when
+    /// reassembled
+    TooLong,
+
+    /// The destination buffer is smaller than the chain
+    BufferTooSmall,
+}
+
+/// A chain of up to `C` reference counted segments, acting as one buffer
+///
+/// ## Example
+///
+/// ```rust
+/// use byte_slab::{BSlab, SlabChain};
+///
+/// static SLAB: BSlab<8, 16> = BSlab::new();
+///
+/// fn main() {
+///     SLAB.init().unwrap();
+///
+///     // 40 bytes takes three 16 byte elements
+///     let data: [u8; 40] = core::array::from_fn(|i| i as u8);
+///     let chain: SlabChain<8, 16, 4> = SlabChain::from_slice(&SLAB, &data).unwrap();
+///     assert_eq!(chain.len(), 40);
+///     assert_eq!(chain.segments().len(), 3);
+///
+///     // Reassemble into one contiguous buffer
+///     let mut buf = [0u8; 64];
+///     let used = chain.copy_to(&mut buf).unwrap();
+///     assert_eq!(&buf[..used], &data[..]);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct SlabChain<const N: usize, const SZ: usize, const C: usize> {
+    segments: Vec<SlabSliceArc<N, SZ>, C>,
+}
+
+impl<const N: usize, const SZ: usize, const C: usize> SlabChain<N, SZ, C> {
+    /// Create an empty chain
+    pub const fn new() -> Self {
+        Self {
+            segments: Vec::new(),
+        }
+    }
+
+    /// Copy `data` into as many newly allocated elements as needed.
+    ///
+    /// This function will fail if `data` needs more than `C` elements, or if
+    /// the slab runs out of elements. Anything allocated so far is freed.
+    pub fn from_slice(slab: &'static BSlab<N, SZ>, data: &[u8]) -> Result<Self, ChainError> {
+        let mut chain = Self::new();
+
+        for chunk in data.chunks(SZ) {
+            if chain.segments.is_full() {
+                return Err(ChainError::TooLong);
+            }
+
+            let mut writer = SlabWriter::new(slab.alloc_box().ok_or(ChainError::NoAlloc)?);
+            writer.extend(chunk).map_err(|_| ChainError::TooLong)?;
+            chain.push(writer.finish()).map_err(|_| ChainError::TooLong)?;
+        }
+
+        Ok(chain)
+    }
+
+    /// Add a segment to the end of the chain.
+    ///
+    /// If the chain is full, the segment is given back.
+    pub fn push(&mut self, segment: SlabSliceArc<N, SZ>) -> Result<(), SlabSliceArc<N, SZ>> {
+        self.segments.push(segment)
+    }
+
+    /// The segments of the chain, in order
+    pub fn segments(&self) -> &[SlabSliceArc<N, SZ>] {
+        &self.segments
+    }
+
+    /// The total length of all segments, in bytes
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|seg| seg.len()).sum()
+    }
+
+    /// Is the chain free of any data?
+    pub fn is_empty(&self) -> bool {
+        self.segments.iter().all(|seg| seg.is_empty())
+    }
+
+    /// Iterate over all bytes of the chain
+    pub fn bytes(&self) -> Bytes<'_, N, SZ> {
+        Bytes {
+            segments: &self.segments,
+            pos: 0,
+        }
+    }
+
+    /// Copy the whole chain into `buf`, returning the number of bytes copied.
+    ///
+    /// This function will fail, without copying anything, if `buf` is too
+    /// small.
+    pub fn copy_to(&self, buf: &mut [u8]) -> Result<usize, ChainError> {
+        let len = self.len();
+        let buf = buf.get_mut(..len).ok_or(ChainError::BufferTooSmall)?;
+
+        let mut rest = buf;
+        for seg in self.segments.iter() {
+            let (now, later) = rest.split_at_mut(seg.len());
+            now.copy_from_slice(seg);
+            rest = later;
+        }
+
+        Ok(len)
+    }
+
+    /// Copy the whole chain into a single newly allocated element.
+    ///
+    /// This function will fail if the chain is longer than `SZ`, or if the
+    /// slab has no elements available.
+    pub fn reassemble(
+        &self,
+        slab: &'static BSlab<N, SZ>,
+    ) -> Result<SlabSliceArc<N, SZ>, ChainError> {
+        if self.len() > SZ {
+            return Err(ChainError::TooLong);
+        }
+
+        let mut writer = SlabWriter::new(slab.alloc_box().ok_or(ChainError::NoAlloc)?);
+        for seg in self.segments.iter() {
+            writer.extend(seg).map_err(|_| ChainError::TooLong)?;
+        }
+
+        Ok(writer.finish())
+    }
+}
+
+impl<const N: usize, const SZ: usize, const C: usize> Default for SlabChain<N, SZ, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serialized the same as a single slice of all the bytes in the chain
+impl<const N: usize, const SZ: usize, const C: usize> Serialize for SlabChain<N, SZ, C> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for byte in self.bytes() {
+            seq.serialize_element(&byte)?;
+        }
+        seq.end()
+    }
+}
+
+/// An iterator over the bytes of a `SlabChain`
+pub struct Bytes<'a, const N: usize, const SZ: usize> {
+    segments: &'a [SlabSliceArc<N, SZ>],
+    pos: usize,
+}
+
+impl<'a, const N: usize, const SZ: usize> Iterator for Bytes<'a, N, SZ> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            let (first, rest) = self.segments.split_first()?;
+
+            if let Some(byte) = first.get(self.pos) {
+                self.pos += 1;
+                return Some(*byte);
+            }
+
+            self.segments = rest;
+            self.pos = 0;
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self
+            .segments
+            .iter()
+            .map(|seg| seg.len())
+            .sum::<usize>()
+            .saturating_sub(self.pos);
+        (len, Some(len))
+    }
+}
+
+impl<'a, const N: usize, const SZ: usize> ExactSizeIterator for Bytes<'a, N, SZ> {}
+impl<'a, const N: usize, const SZ: usize> FusedIterator for Bytes<'a, N, SZ> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chain_and_reassemble() {
+        static SLAB: BSlab<8, 16> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let data: [u8; 40] = core::array::from_fn(|i| i as u8);
+
+        // Too many segments, and nothing is leaked
+        assert_eq!(
+            SlabChain::<8, 16, 2>::from_slice(&SLAB, &data).err(),
+            Some(ChainError::TooLong),
+        );
+
+        let mut chain = SlabChain::<8, 16, 4>::from_slice(&SLAB, &data).unwrap();
+        assert!(chain.bytes().eq(data.iter().copied()));
+        assert_eq!(chain.bytes().len(), 40);
+        assert_eq!(chain.reassemble(&SLAB).err(), Some(ChainError::TooLong));
+
+        let mut small = [0u8; 39];
+        assert_eq!(chain.copy_to(&mut small), Err(ChainError::BufferTooSmall));
+
+        // Segments may be any part of an element
+        let extra = SlabChain::<8, 16, 1>::from_slice(&SLAB, &[0xAA; 16]).unwrap();
+        let extra = extra.segments()[0].sub_slice_arc(4, 2).unwrap();
+        assert!(chain.push(extra.clone()).is_ok());
+        assert!(chain.push(extra.clone()).is_err());
+        assert_eq!(chain.len(), 42);
+
+        let mut short = SlabChain::<8, 16, 2>::from_slice(&SLAB, &data[..10]).unwrap();
+        assert!(short.push(extra).is_ok());
+        let ssa = short.reassemble(&SLAB).unwrap();
+        assert_eq!(&ssa[..10], &data[..10]);
+        assert_eq!(&ssa[10..], &[0xAA, 0xAA]);
+
+        // Three for `chain`, one for `extra`, one for `short` and one for `ssa`
+        let last = SLAB.alloc_box().unwrap();
+        let last_2 = SLAB.alloc_box().unwrap();
+        assert!(SLAB.alloc_box().is_none());
+        assert_eq!(short.reassemble(&SLAB).err(), Some(ChainError::NoAlloc));
+        drop(last_2);
+        drop(last);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn serializes_as_bytes() {
+        static SLAB: BSlab<4, 8> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let data: [u8; 20] = core::array::from_fn(|i| i as u8);
+        let chain = SlabChain::<4, 8, 3>::from_slice(&SLAB, &data).unwrap();
+
+        let mut buf_1 = [0u8; 32];
+        let mut buf_2 = [0u8; 32];
+        assert_eq!(
+            postcard::to_slice(&chain, &mut buf_1).unwrap(),
+            postcard::to_slice(&data[..], &mut buf_2).unwrap(),
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! A handle to a slab, wherever it is stored
+//!
+//! Code that allocates from a slab, but doesn't care whether the slab is a
+//! static item or a `HeapSlab`, can hold a `SlabHandle`. Handles to a static
+//! `BSlab` come from `BSlab::handle()`, and cost nothing to clone. Handles to
+//! a `HeapSlab` keep the slab alive, like the `HeapSlab` itself.
+
+use crate::byte_slab::BSlab;
+use crate::slab_box::SlabBox;
+use core::{future::Future, ops::Deref};
+
+/// A cloneable handle to a `BSlab`
+///
+/// ## Example
+///
+/// ```rust
+/// use byte_slab::{BSlab, SlabHandle};
+///
+/// static SLAB: BSlab<4, 128> = BSlab::new();
+/// static HANDLE: SlabHandle<4, 128> = SLAB.handle();
+///
+/// fn main() {
+///     SLAB.init().unwrap();
+///
+///     let handle = HANDLE.clone();
+///     let sbox = handle.alloc_box().unwrap();
+///     assert_eq!(sbox.len(), 128);
+/// }
+/// ```
+pub struct SlabHandle<const N: usize, const SZ: usize> {
+    pub(crate) slab: &'static BSlab<N, SZ>,
+}
+
+impl<const N: usize, const SZ: usize> SlabHandle<N, SZ> {
+    /// Allocate a new Box of `SZ`.
+    ///
+    /// This function will return `None` if there are no pages available.
+    #[track_caller]
+    pub fn alloc_box(&self) -> Option<SlabBox<N, SZ>> {
+        self.slab.alloc_box()
+    }
+
+    /// Allocate a new Box of `SZ`, waiting for one to be freed if there are
+    /// no pages available. See `BSlab::alloc_box_async()`.
+    #[track_caller]
+    pub fn alloc_box_async(&self) -> impl Future<Output = SlabBox<N, SZ>> + '_ {
+        self.slab.alloc_box_async()
+    }
+}
+
+impl<const N: usize, const SZ: usize> BSlab<N, SZ> {
+    /// Get a handle to this slab
+    pub const fn handle(&'static self) -> SlabHandle<N, SZ> {
+        SlabHandle { slab: self }
+    }
+}
+
+impl<const N: usize, const SZ: usize> From<&'static BSlab<N, SZ>> for SlabHandle<N, SZ> {
+    fn from(slab: &'static BSlab<N, SZ>) -> Self {
+        slab.handle()
+    }
+}
+
+impl<const N: usize, const SZ: usize> Clone for SlabHandle<N, SZ> {
+    fn clone(&self) -> Self {
+        #[cfg(feature = "std")]
+        self.slab.heap_refs.acquire();
+
+        Self { slab: self.slab }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<const N: usize, const SZ: usize> Drop for SlabHandle<N, SZ> {
+    fn drop(&mut self) {
+        unsafe { self.slab.release_heap_ref() }
+    }
+}
+
+impl<const N: usize, const SZ: usize> Deref for SlabHandle<N, SZ> {
+    type Target = BSlab<N, SZ>;
+
+    fn deref(&self) -> &Self::Target {
+        self.slab
+    }
+}
@@ -0,0 +1,233 @@
+//! Writing into an allocation, piece by piece
+//!
+//! A `SlabWriter` takes ownership of a `SlabBox`, and keeps track of how much
+//! has been written to it. Once done, it yields a `SlabSliceArc` of exactly
+//! the written bytes, so the length never has to be tracked by hand.
+//!
+//! With the `postcard` feature, a `SlabWriter` is also a postcard flavor, and
+//! `SlabWriter::serialize()` and `SlabWriter::serialize_cobs()` encode a
+//! message straight into a `SlabSliceArc`.
+
+use core::fmt;
+use core::ops::{Index, IndexMut};
+
+use crate::slab_box::SlabBox;
+use crate::slab_slice_arc::SlabSliceArc;
+
+#[cfg(feature = "postcard")]
+use postcard::flavors::{Cobs, SerFlavor};
+#[cfg(feature = "postcard")]
+use serde::Serialize;
+
+/// An error writing into a `SlabWriter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WriteError {
+    /// The data does not fit in the remaining space
+    Full,
+}
+
+/// A cursor writing into a `SlabBox`
+///
+/// ## Example
+///
+/// ```rust
+/// use byte_slab::{BSlab, SlabWriter};
+/// use core::fmt::Write;
+///
+/// static SLAB: BSlab<4, 128> = BSlab::new();
+///
+/// fn main() {
+///     SLAB.init().unwrap();
+///
+///     let mut writer = SlabWriter::new(SLAB.alloc_box().unwrap());
+///     writer.extend(&[1, 2, 3]).unwrap();
+///     write!(&mut writer, "{}", 45).unwrap();
+///
+///     let ssa = writer.finish();
+///     assert_eq!(&ssa[..], &[1, 2, 3, b'4', b'5']);
+/// }
+/// ```
+pub struct SlabWriter<const N: usize, const SZ: usize> {
+    sbox: SlabBox<N, SZ>,
+    len: usize,
+}
+
+impl<const N: usize, const SZ: usize> SlabWriter<N, SZ> {
+    /// Start writing at the beginning of a `SlabBox`
+    pub fn new(sbox: SlabBox<N, SZ>) -> Self {
+        Self { sbox, len: 0 }
+    }
+
+    /// The number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Has anything been written yet?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of bytes that may still be written
+    pub fn remaining(&self) -> usize {
+        SZ - self.len
+    }
+
+    /// The bytes written so far
+    pub fn written(&self) -> &[u8] {
+        &self.sbox[..self.len]
+    }
+
+    /// Write a single byte.
+    ///
+    /// This function will fail if the `SlabBox` is full.
+    pub fn push(&mut self, byte: u8) -> Result<(), WriteError> {
+        let slot = self.sbox.get_mut(self.len).ok_or(WriteError::Full)?;
+        *slot = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Write all of `data`.
+    ///
+    /// This function will fail, without writing anything, if `data` does not
+    /// fit in the remaining space.
+    pub fn extend(&mut self, data: &[u8]) -> Result<(), WriteError> {
+        let end = self.len.checked_add(data.len()).ok_or(WriteError::Full)?;
+        self.sbox.get_mut(self.len..end).ok_or(WriteError::Full)?.copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Discard everything written so far
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Give back the `SlabBox`, along with the number of bytes written
+    pub fn into_inner(self) -> (SlabBox<N, SZ>, usize) {
+        (self.sbox, self.len)
+    }
+
+    /// Finish writing, returning exactly the written bytes
+    pub fn finish(self) -> SlabSliceArc<N, SZ> {
+        SlabSliceArc {
+            arc: self.sbox.into_arc(),
+            start: 0,
+            len: self.len,
+        }
+    }
+}
+
+impl<const N: usize, const SZ: usize> fmt::Write for SlabWriter<N, SZ> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.extend(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Indexes into the bytes written so far
+impl<const N: usize, const SZ: usize> Index<usize> for SlabWriter<N, SZ> {
+    type Output = u8;
+
+    fn index(&self, idx: usize) -> &u8 {
+        &self.written()[idx]
+    }
+}
+
+impl<const N: usize, const SZ: usize> IndexMut<usize> for SlabWriter<N, SZ> {
+    fn index_mut(&mut self, idx: usize) -> &mut u8 {
+        &mut self.sbox[..self.len][idx]
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl<const N: usize, const SZ: usize> SerFlavor for SlabWriter<N, SZ> {
+    type Output = SlabSliceArc<N, SZ>;
+
+    fn try_extend(&mut self, data: &[u8]) -> Result<(), ()> {
+        self.extend(data).map_err(drop)
+    }
+
+    fn try_push(&mut self, data: u8) -> Result<(), ()> {
+        self.push(data).map_err(drop)
+    }
+
+    fn release(self) -> Result<Self::Output, ()> {
+        Ok(self.finish())
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl<const N: usize, const SZ: usize> SlabWriter<N, SZ> {
+    /// Serialize `value` into `sbox`, returning exactly the encoded bytes
+    pub fn serialize<T>(value: &T, sbox: SlabBox<N, SZ>) -> postcard::Result<SlabSliceArc<N, SZ>>
+    where
+        T: Serialize + ?Sized,
+    {
+        postcard::serialize_with_flavor(value, Self::new(sbox))
+    }
+
+    /// Serialize and COBS encode `value` into `sbox`, returning exactly the
+    /// encoded bytes, including the zero terminator
+    pub fn serialize_cobs<T>(
+        value: &T,
+        sbox: SlabBox<N, SZ>,
+    ) -> postcard::Result<SlabSliceArc<N, SZ>>
+    where
+        T: Serialize + ?Sized,
+    {
+        postcard::serialize_with_flavor(value, Cobs::try_new(Self::new(sbox))?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::BSlab;
+    use core::fmt::Write;
+
+    #[test]
+    fn exact_length() {
+        static SLAB: BSlab<2, 8> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let mut writer = SlabWriter::new(SLAB.alloc_box().unwrap());
+        assert!(writer.is_empty());
+        writer.push(1).unwrap();
+        writer.extend(&[2, 3, 4]).unwrap();
+        assert_eq!(writer.remaining(), 4);
+
+        // Too long, nothing is written
+        assert_eq!(writer.extend(&[0; 5]), Err(WriteError::Full));
+        assert!(write!(&mut writer, "hello").is_err());
+        assert_eq!(writer.written(), &[1, 2, 3, 4]);
+
+        write!(&mut writer, "abcd").unwrap();
+        assert_eq!(writer.push(0), Err(WriteError::Full));
+
+        let ssa = writer.finish();
+        assert_eq!(&ssa[..], b"\x01\x02\x03\x04abcd");
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard() {
+        static SLAB: BSlab<4, 64> = BSlab::new();
+        SLAB.init().unwrap();
+
+        let msg: (u32, &str, [u8; 3]) = (300, "hi", [0, 1, 0]);
+        let mut buf = [0u8; 64];
+
+        let ssa = SlabWriter::serialize(&msg, SLAB.alloc_box().unwrap()).unwrap();
+        assert_eq!(&ssa[..], &*postcard::to_slice(&msg, &mut buf).unwrap());
+
+        let ssa = SlabWriter::serialize_cobs(&msg, SLAB.alloc_box().unwrap()).unwrap();
+        assert_eq!(&ssa[..], &*postcard::to_slice_cobs(&msg, &mut buf).unwrap());
+        assert_eq!(ssa.last(), Some(&0));
+
+        // Doesn't fit
+        let big = [0xAAu8; 64];
+        assert!(SlabWriter::serialize(&big[..], SLAB.alloc_box().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! Allocation statistics for a `BSlab`
+//!
+//! Only available with the `stats` feature. Counting costs a few atomic
+//! operations per allocation and free.
+
+use crate::atomic::{AtomicUsize, Ordering};
+
+/// A snapshot of the allocation statistics of a `BSlab`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SlabStats {
+    /// Elements currently available for allocation
+    pub free_count: usize,
+
+    /// The most elements ever in use at once
+    pub high_water_used: usize,
+
+    /// Times `alloc_box()` returned `None`
+    pub alloc_failures: usize,
+
+    /// Successful allocations since startup
+    pub total_allocs: usize,
+}
+
+/// Running totals, kept by the `BSlab`
+pub(crate) struct StatCounters {
+    in_use: AtomicUsize,
+    high_water_used: AtomicUsize,
+    alloc_failures: AtomicUsize,
+    total_allocs: AtomicUsize,
+}
+
+impl StatCounters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            in_use: AtomicUsize::new(0),
+            high_water_used: AtomicUsize::new(0),
+            alloc_failures: AtomicUsize::new(0),
+            total_allocs: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn alloc(&self) {
+        let in_use = self.in_use.fetch_add(1, Ordering::SeqCst) + 1;
+        self.high_water_used.fetch_max(in_use, Ordering::SeqCst);
+        self.total_allocs.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn alloc_failed(&self) {
+        self.alloc_failures.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn free(&self) {
+        self.in_use.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn snapshot(&self, total: usize) -> SlabStats {
+        SlabStats {
+            free_count: total.saturating_sub(self.in_use.load(Ordering::SeqCst)),
+            high_water_used: self.high_water_used.load(Ordering::SeqCst),
+            alloc_failures: self.alloc_failures.load(Ordering::SeqCst),
+            total_allocs: self.total_allocs.load(Ordering::SeqCst),
+        }
+    }
+}
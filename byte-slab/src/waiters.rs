@@ -0,0 +1,156 @@
+//! Wakers of tasks waiting for a free slab element
+//!
+//! Elements may be freed from any context, including interrupts, so waking
+//! never blocks. Each waiting task claims a slot of its own, and each slot
+//! follows the same protocol as the `AtomicWaker` from `futures`.
+//!
+//! `Waiters` is public so other kinds of room, such as space in a queue, can
+//! be waited for in the same way.
+
+use crate::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::{cell::UnsafeCell, task::Waker};
+
+/// The number of tasks that may wait for an allocation at once. Any more
+/// than this are polled again immediately, rather than waiting to be woken.
+pub const MAX_ALLOC_WAITERS: usize = 8;
+
+const WAITING: u8 = 0b00;
+const REGISTERING: u8 = 0b01;
+const WAKING: u8 = 0b10;
+
+struct WaiterSlot {
+    claimed: AtomicBool,
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+impl WaiterSlot {
+    fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(
+            WAITING,
+            REGISTERING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                // We are the only one who may touch the waker until we leave
+                // the REGISTERING state
+                unsafe {
+                    let slot = &mut *self.waker.get();
+                    match slot {
+                        Some(old) if old.will_wake(waker) => {}
+                        _ => *slot = Some(waker.clone()),
+                    }
+                }
+
+                let res = self.state.compare_exchange(
+                    REGISTERING,
+                    WAITING,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+
+                if res.is_err() {
+                    // A wake came in while we were registering, and left it
+                    // to us
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.swap(WAITING, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            Err(WAKING) => {
+                // Being woken right now, poll again
+                waker.wake_by_ref();
+            }
+            Err(_) => {
+                // Slots are not shared between tasks, so we can't be racing
+                // another register
+            }
+        }
+    }
+
+    fn wake(&self) {
+        match self.state.fetch_or(WAKING, Ordering::AcqRel) {
+            WAITING => {
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Ordering::Release);
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+            _ => {
+                // Either registering, which will wake for us, or someone
+                // else is already waking
+            }
+        }
+    }
+}
+
+/// A fixed set of tasks waiting to be woken
+///
+/// A future claims a slot the first time it has to wait, registers its
+/// waker each time it is polled, and releases the slot when dropped. A
+/// future that can't claim a slot should wake itself instead.
+pub struct Waiters {
+    slots: [WaiterSlot; MAX_ALLOC_WAITERS],
+}
+
+// Waiters may be `Sync`, as the wakers are only accessed while holding
+// the REGISTERING or WAKING state of their slot
+unsafe impl Sync for Waiters {}
+
+impl Waiters {
+    /// A set with every slot free
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: WaiterSlot = WaiterSlot {
+            claimed: AtomicBool::new(false),
+            state: AtomicU8::new(WAITING),
+            waker: UnsafeCell::new(None),
+        };
+
+        Self {
+            slots: [EMPTY; MAX_ALLOC_WAITERS],
+        }
+    }
+
+    /// Claim a slot to wait in, if there are any left
+    pub fn claim(&self) -> Option<usize> {
+        self.slots.iter().position(|slot| {
+            slot.claimed
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })
+    }
+
+    /// Give up a claimed slot
+    pub fn release(&self, idx: usize) {
+        if let Some(slot) = self.slots.get(idx) {
+            slot.claimed.store(false, Ordering::Release);
+        }
+    }
+
+    /// Register the waker of a claimed slot
+    pub fn register(&self, idx: usize, waker: &Waker) {
+        match self.slots.get(idx) {
+            Some(slot) => slot.register(waker),
+            None => waker.wake_by_ref(),
+        }
+    }
+
+    /// Wake every waiting task
+    pub fn wake_all(&self) {
+        self.slots
+            .iter()
+            .filter(|slot| slot.claimed.load(Ordering::Acquire))
+            .for_each(WaiterSlot::wake);
+    }
+}
+
+impl Default for Waiters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
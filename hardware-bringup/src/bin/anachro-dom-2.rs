@@ -11,26 +11,34 @@ use nrf52840_hal::{
 // use groundhog::RollingTimer;
 use anachro_485::icd::{SLAB_SIZE, TOTAL_SLABS};
 use anachro_485::{
-    dispatch::{Dispatch, IoQueue},
+    dispatch::{Dispatch, IoQueue, PacketAlloc},
     dom::{token::Token, AddrTable32, DISCOVERY_PORT, TOKEN_PORT},
 };
-use byte_slab::BSlab;
+use byte_slab::{BSlab, SizedSlab};
 use groundhog_nrf52::GlobalRollingTimer;
 use rand_chacha::ChaCha8Rng;
 use uarte_485::{DefaultTo, Pin485, Uarte485};
+use anachro_485::timing::BusTiming;
 
 use anachro_485::dom::discover::Discovery;
 use cassette::{pin_mut, Cassette};
 
-static IOQ: IoQueue = IoQueue::new();
+static IOQ: IoQueue<TOTAL_SLABS, SLAB_SIZE> = IoQueue::new();
 static BSLAB: BSlab<TOTAL_SLABS, SLAB_SIZE> = BSlab::new();
+
+// Classes for short messages and frames, such as token grants and releases
+static SMALL_SLAB: BSlab<32, 64> = BSlab::new();
+static MEDIUM_SLAB: BSlab<16, 128> = BSlab::new();
+static LARGE_SLAB: BSlab<8, 256> = BSlab::new();
+static SIZED_SLAB: SizedSlab<32, 64, 16, 128, 8, 256> =
+    SizedSlab::new(&SMALL_SLAB, &MEDIUM_SLAB, &LARGE_SLAB);
 static ADDR_TABLE: AddrTable32 = AddrTable32::new();
 
 #[rtic::app(device = nrf52840_hal::pac, peripherals = true, monotonic = groundhog_nrf52::GlobalRollingTimer)]
 const APP: () = {
     struct Resources {
-        usart: Uarte485<TIMER2, Ppi3, UARTE0, GlobalRollingTimer>,
-        dispatch: Dispatch<8>,
+        usart: Uarte485<TIMER2, Ppi3, UARTE0, GlobalRollingTimer, TOTAL_SLABS, SLAB_SIZE>,
+        dispatch: Dispatch<'static, 8, TOTAL_SLABS, SLAB_SIZE>,
         opt_rng: Option<(ChaCha8Rng, ChaCha8Rng)>,
     }
 
@@ -39,6 +47,9 @@ const APP: () = {
         defmt::info!("Hello, world!");
         defmt::info!("Dom on Port 2 (Cap)");
         BSLAB.init().unwrap();
+        SMALL_SLAB.init().unwrap();
+        MEDIUM_SLAB.init().unwrap();
+        LARGE_SLAB.init().unwrap();
 
         let board = cx.device;
 
@@ -72,9 +83,13 @@ const APP: () = {
             },
             IOQ.take_io_handle().unwrap(),
             DefaultTo::Sending,
+            BusTiming::default(),
         );
 
-        let dispatch: Dispatch<8> = Dispatch::new(&IOQ, &BSLAB);
+        let dispatch: Dispatch<8, TOTAL_SLABS, SLAB_SIZE> = Dispatch::with_packet_alloc(
+            &IOQ,
+            PacketAlloc::with_sized(BSLAB.handle(), &SIZED_SLAB),
+        );
         dispatch.set_addr(0);
 
         init::LateResources {
@@ -87,26 +102,31 @@ const APP: () = {
     #[idle(resources = [dispatch, opt_rng])]
     fn idle(ctx: idle::Context) -> ! {
         rtic::pend(Interrupt::UARTE0_UART0);
+        let dispatch: &'static Dispatch<8, TOTAL_SLABS, SLAB_SIZE> = ctx.resources.dispatch;
 
         let (rand_1, rand_2) = ctx.resources.opt_rng.take().unwrap();
 
         // DISCO
-        let disco_socket = ctx
-            .resources
-            .dispatch
-            .register_port(DISCOVERY_PORT)
-            .unwrap();
-
-        let mut dom_disco: Discovery<GlobalRollingTimer, _> =
-            Discovery::new(disco_socket, rand_1, &BSLAB, &ADDR_TABLE);
+        let disco_socket = dispatch.register_port(DISCOVERY_PORT).unwrap();
+
+        let mut dom_disco: Discovery<GlobalRollingTimer, _, 8, TOTAL_SLABS, SLAB_SIZE> =
+            Discovery::new(
+                dispatch,
+                disco_socket,
+                rand_1,
+                BSLAB.handle(),
+                &ADDR_TABLE,
+                BusTiming::default(),
+            );
         let dom_disco_future = dom_disco.poll();
         pin_mut!(dom_disco_future);
 
         // GRANT
-        let grant_socket = ctx.resources.dispatch.register_port(TOKEN_PORT).unwrap();
+        let grant_socket = dispatch.register_port(TOKEN_PORT).unwrap();
 
-        let mut dom_token: Token<GlobalRollingTimer, _> =
-            Token::new(grant_socket, rand_2, &BSLAB, &ADDR_TABLE);
+        let token_alloc = PacketAlloc::with_sized(BSLAB.handle(), &SIZED_SLAB);
+        let mut dom_token: Token<GlobalRollingTimer, _, TOTAL_SLABS, SLAB_SIZE> =
+            Token::new(grant_socket, rand_2, token_alloc, &ADDR_TABLE, BusTiming::default());
         let dom_token_future = dom_token.poll();
         pin_mut!(dom_token_future);
 
@@ -119,7 +139,7 @@ const APP: () = {
             cas_dom_token.poll_on();
 
             // Process messages
-            ctx.resources.dispatch.process_messages();
+            dispatch.process_messages();
         }
     }
 
@@ -14,26 +14,37 @@ use nrf52840_hal::{
 // use groundhog::RollingTimer;
 use anachro_485::icd::{SLAB_SIZE, TOTAL_SLABS};
 use anachro_485::{
-    dispatch::{Dispatch, IoQueue},
+    dispatch::{Dispatch, IoQueue, PacketAlloc},
     dom::{DISCOVERY_PORT, TOKEN_PORT},
     sub::token::Token,
 };
-use byte_slab::BSlab;
+use byte_slab::{BSlab, SizedSlab};
 use groundhog_nrf52::GlobalRollingTimer;
 use rand_chacha::ChaCha8Rng;
 use uarte_485::{DefaultTo, Pin485, Uarte485};
+use anachro_485::timing::BusTiming;
 
 use anachro_485::sub::discover::Discovery;
 use cassette::{pin_mut, Cassette};
 
-static IOQ: IoQueue = IoQueue::new();
+static IOQ: IoQueue<TOTAL_SLABS, SLAB_SIZE> = IoQueue::new();
 static BSLAB: BSlab<TOTAL_SLABS, SLAB_SIZE> = BSlab::new();
-static DISPATCH: Dispatch<8> = Dispatch::new(&IOQ, &BSLAB);
+
+// Classes for short messages and frames, such as token grants and releases
+static SMALL_SLAB: BSlab<32, 64> = BSlab::new();
+static MEDIUM_SLAB: BSlab<16, 128> = BSlab::new();
+static LARGE_SLAB: BSlab<8, 256> = BSlab::new();
+static SIZED_SLAB: SizedSlab<32, 64, 16, 128, 8, 256> =
+    SizedSlab::new(&SMALL_SLAB, &MEDIUM_SLAB, &LARGE_SLAB);
+static DISPATCH: Dispatch<8, TOTAL_SLABS, SLAB_SIZE> = Dispatch::with_packet_alloc(
+    &IOQ,
+    PacketAlloc::with_sized(BSLAB.handle(), &SIZED_SLAB),
+);
 
 #[rtic::app(device = nrf52840_hal::pac, peripherals = true, monotonic = groundhog_nrf52::GlobalRollingTimer)]
 const APP: () = {
     struct Resources {
-        usart: Uarte485<TIMER2, Ppi3, UARTE0, GlobalRollingTimer>,
+        usart: Uarte485<TIMER2, Ppi3, UARTE0, GlobalRollingTimer, TOTAL_SLABS, SLAB_SIZE>,
         opt_rng: Option<(ChaCha8Rng, ChaCha8Rng)>,
         led1: Pin<Output<PushPull>>,
         led2: Pin<Output<PushPull>>,
@@ -44,6 +55,9 @@ const APP: () = {
         defmt::info!("Hello, world!");
         defmt::info!("Sub on Port 1 (Bus)");
         BSLAB.init().unwrap();
+        SMALL_SLAB.init().unwrap();
+        MEDIUM_SLAB.init().unwrap();
+        LARGE_SLAB.init().unwrap();
 
         let board = cx.device;
 
@@ -79,6 +93,7 @@ const APP: () = {
             },
             IOQ.take_io_handle().unwrap(),
             DefaultTo::Receiving,
+            BusTiming::default(),
         );
 
         for _ in 0..3 {
@@ -108,13 +123,14 @@ const APP: () = {
         let disco_socket = DISPATCH.register_port(DISCOVERY_PORT).unwrap();
         let token_socket = DISPATCH.register_port(TOKEN_PORT).unwrap();
 
-        let mut sub_disco: Discovery<GlobalRollingTimer, _> =
-            Discovery::new(rand_1, &DISPATCH, disco_socket, &BSLAB);
+        let mut sub_disco: Discovery<GlobalRollingTimer, _, 8, TOTAL_SLABS, SLAB_SIZE> =
+            Discovery::new(rand_1, &DISPATCH, disco_socket, BSLAB.handle(), BusTiming::default());
         let sub_disco_future = sub_disco.obtain_addr();
         pin_mut!(sub_disco_future);
 
-        let mut sub_token: Token<GlobalRollingTimer, _> =
-            Token::new(rand_2, &DISPATCH, token_socket, &BSLAB);
+        let token_alloc = PacketAlloc::with_sized(BSLAB.handle(), &SIZED_SLAB);
+        let mut sub_token: Token<GlobalRollingTimer, _, 8, TOTAL_SLABS, SLAB_SIZE> =
+            Token::new(rand_2, &DISPATCH, token_socket, token_alloc, BusTiming::default());
         let sub_token_future = sub_token.poll();
         pin_mut!(sub_token_future);
 
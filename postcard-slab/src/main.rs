@@ -1,10 +1,11 @@
-use byte_slab::{BSlab, SlabBox, SlabArc, SlabSliceArc};
-use serde::{Deserialize, Deserializer, Serialize};
+use byte_slab::{from_slab_arc, BSlab, SlabSliceArc};
+use byte_slab_derive::Reroot;
+use serde::Deserialize;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Reroot)]
 struct Contains {
-    #[serde(deserialize_with = "not_right")]
-    simple: SlabArc<16, 1024>,
+    #[serde(deserialize_with = "byte_slab::de::slab_slice_arc")]
+    simple: SlabSliceArc<16, 1024>,
 }
 
 static SLAB: BSlab<16, 1024> = BSlab::new();
@@ -12,11 +13,15 @@ static SLAB: BSlab<16, 1024> = BSlab::new();
 fn main() {
     println!("Hello, world!");
     SLAB.init().unwrap();
-}
 
-fn not_right<'de, D, T>(arg: D) -> Result<T, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    todo!()
+    let mut sbox = SLAB.alloc_box().unwrap();
+    // Serialized the same as a `Contains`
+    postcard::to_slice(&[1u8, 2, 3, 4][..], &mut sbox[..]).unwrap();
+    let arc = sbox.into_arc();
+
+    // The field holds its own reference to the allocation it was read from
+    let owned = from_slab_arc::<Contains, 16, 1024>(&arc).unwrap();
+    drop(arc);
+
+    println!("{:?}", &owned.simple[..]);
 }
@@ -1,26 +1,32 @@
 use std::{
     iter::FromIterator,
-    ops::Deref,
-    sync::{Arc, Mutex},
+    sync::{atomic::AtomicBool, Arc},
     thread::{sleep, spawn, JoinHandle},
     time::Duration,
 };
 
 use anachro_485::{
-    dispatch::{Dispatch, IoQueue, TimeStampBox},
-    dom::{discover::Discovery as DomDiscovery, MANAGEMENT_PORT},
+    dom::{discover::Discovery as DomDiscovery, AddrTable32, DISCOVERY_PORT},
     icd::{SLAB_SIZE, TOTAL_SLABS},
     sub::discover::Discovery as SubDiscovery,
+    timing::BusTiming,
+};
+use sim_485::{
+    groundhog_sim::GlobalRollingTimer,
+    node::{run_node, SimSlab},
+    Rs485Bus,
 };
-use groundhog::RollingTimer;
-use sim_485::{groundhog_sim::GlobalRollingTimer, Rs485Bus, Rs485Device};
 
-use byte_slab::BSlab;
 use cassette::{pin_mut, Cassette};
 use rand::thread_rng;
 
+static ADDR_TABLE: AddrTable32 = AddrTable32::new();
+
+// Nodes run until the process exits
+static STOP: AtomicBool = AtomicBool::new(false);
+
 fn main() {
-    let arc_bus = Rs485Bus::new_arc();
+    let arc_bus = Rs485Bus::new_arc(BusTiming::from_baud(115_200, SLAB_SIZE as u32));
 
     let mut network = Vec::from_iter([
         make_me_a_dom(&arc_bus),
@@ -59,247 +65,84 @@ fn main() {
     ]);
 
     network.drain(..).for_each(|h| {
-        let _ = h.logic.join();
-        let _ = h.io.join();
+        let _ = h.join();
     });
 }
 
-struct DevHdl {
-    logic: JoinHandle<()>,
-    io: JoinHandle<()>,
-}
-
-fn make_me_a_dom(arc_bus: &Arc<Rs485Bus>) -> DevHdl {
-    let mut dev_1 = Rs485Device::new(&arc_bus);
-
-    let care_lock_1 = Arc::new(Mutex::new(Option::<CarePackage>::None));
-    let care_lock_2 = care_lock_1.clone();
+fn make_me_a_dom(arc_bus: &Arc<Rs485Bus>) -> JoinHandle<()> {
+    let arc_bus = arc_bus.clone();
+    let timing = arc_bus.timing();
 
-    let logic = spawn(move || {
-        let slab: &'static BSlab<TOTAL_SLABS, SLAB_SIZE> = Box::leak(Box::new(BSlab::new()));
-        let ioq: &'static IoQueue = Box::leak(Box::new(IoQueue::new()));
-        let dispatch: &'static Dispatch<8> = Box::leak(Box::new(Dispatch::new(ioq, slab)));
-        slab.init().unwrap();
-        dispatch.set_addr(0);
-
-        *care_lock_2.lock().unwrap() = Some(CarePackage {
-            slab,
-            ioq,
-            dispatch,
-        });
+    spawn(move || {
+        let slab = SimSlab::new();
 
-        drop(care_lock_2);
+        run_node(&arc_bus, &slab, &STOP, |dispatch, slab| {
+            dispatch.set_addr(0);
 
-        let socket = dispatch.register_port(MANAGEMENT_PORT).unwrap();
+            let socket = dispatch.register_port(DISCOVERY_PORT).unwrap();
 
-        let mut dom_disco: DomDiscovery<GlobalRollingTimer, _> =
-            DomDiscovery::new(socket, thread_rng(), slab);
-        let dom_disco_future = dom_disco.poll();
-        pin_mut!(dom_disco_future);
+            let mut dom_disco: DomDiscovery<GlobalRollingTimer, _, 8, TOTAL_SLABS, SLAB_SIZE> =
+                DomDiscovery::new(
+                    dispatch,
+                    socket,
+                    thread_rng(),
+                    slab.handle(),
+                    &ADDR_TABLE,
+                    timing,
+                );
+            let dom_disco_future = dom_disco.poll();
+            pin_mut!(dom_disco_future);
 
-        let mut cas_dom = Cassette::new(dom_disco_future);
+            let mut cas_dom = Cassette::new(dom_disco_future);
 
-        loop {
-            // Process messages
-            dispatch.process_messages();
+            loop {
+                // Process messages
+                dispatch.process_messages();
 
-            // Check the actual tasks
-            cas_dom.poll_on();
+                // Check the actual tasks
+                cas_dom.poll_on();
 
-            // Rate limiting
-            sleep(Duration::from_micros(500));
-        }
-    });
-
-    let io = spawn(move || {
-        let CarePackage {
-            slab,
-            ioq,
-            dispatch,
-        } = loop {
-            if let Ok(mut care_g) = care_lock_1.lock() {
-                if let Some(care) = care_g.take() {
-                    break care;
-                }
+                // Rate limiting
+                sleep(Duration::from_micros(500));
             }
-
-            sleep(Duration::from_millis(10));
-        };
-
-        let mut io_hdl = ioq.take_io_handle().unwrap();
-        let mut carry = vec![];
-        let timer = GlobalRollingTimer::default();
-
-        loop {
-            sleep(Duration::from_micros(500));
-
-            if let Some(msg) = io_hdl.pop_outgoing() {
-                dev_1.disable_listen();
-                dev_1.enable_transmit();
-                dev_1.send(msg.deref());
-                dev_1.disable_transmit();
-                dev_1.enable_listen();
-            }
-
-            carry.extend_from_slice(&dev_1.receive());
-
-            let pos = if let Some(pos) = carry.iter().position(|b| *b == 0) {
-                pos + 1
-            } else {
-                continue;
-            };
-            let mut remain = carry.split_off(pos);
-
-            core::mem::swap(&mut carry, &mut remain);
-            let current = remain;
-
-            if pos >= SLAB_SIZE {
-                println!("TOO BIG");
-            } else {
-                if let Some(mut sbox) = slab.alloc_box() {
-                    sbox[..pos].copy_from_slice(&current);
-
-                    // TODO: This is a hack!
-                    sbox[pos..].fill(0);
-
-                    io_hdl
-                        .push_incoming(TimeStampBox {
-                            packet: sbox,
-                            len: pos,
-                            tick: timer.get_ticks(),
-                        })
-                        .map_err(drop)
-                        .unwrap();
-                } else {
-                    println!("No alloc for io!");
-                }
-            }
-        }
-    });
-
-    DevHdl { logic, io }
-}
-
-struct CarePackage {
-    slab: &'static BSlab<TOTAL_SLABS, SLAB_SIZE>,
-    ioq: &'static IoQueue,
-    dispatch: &'static Dispatch<8>,
-}
-
-fn make_me_a_sub(arc_bus: &Arc<Rs485Bus>) -> DevHdl {
-    let mut dev_2 = Rs485Device::new(arc_bus);
-    dev_2.enable_listen();
-
-    let care_lock_1 = Arc::new(Mutex::new(Option::<CarePackage>::None));
-    let care_lock_2 = care_lock_1.clone();
-
-    let logic = spawn(move || {
-        let slab: &'static BSlab<TOTAL_SLABS, SLAB_SIZE> = Box::leak(Box::new(BSlab::new()));
-        let ioq: &'static IoQueue = Box::leak(Box::new(IoQueue::new()));
-        let dispatch: &'static Dispatch<8> = Box::leak(Box::new(Dispatch::new(ioq, slab)));
-        slab.init().unwrap();
-
-        *care_lock_2.lock().unwrap() = Some(CarePackage {
-            slab,
-            ioq,
-            dispatch,
         });
+    })
+}
 
-        drop(care_lock_2);
-
-        let socket = dispatch.register_port(MANAGEMENT_PORT).unwrap();
-
-        let mut sub_disco_1: SubDiscovery<GlobalRollingTimer, _> =
-            SubDiscovery::new(thread_rng(), dispatch, socket, slab);
-        let sub_disco_future_1 = sub_disco_1.obtain_addr();
-        pin_mut!(sub_disco_future_1);
-        let mut cas_sub_1 = Cassette::new(sub_disco_future_1);
-        let mut cas_sub_1_done = false;
-
-        loop {
-            dispatch.process_messages();
-            if !cas_sub_1_done {
-                if let Some(x) = cas_sub_1.poll_on() {
-                    match x {
-                        Ok(y) => {
-                            cas_sub_1_done = true;
-                            println!("cas_sub_1 addr: {:?}", y);
+fn make_me_a_sub(arc_bus: &Arc<Rs485Bus>) -> JoinHandle<()> {
+    let arc_bus = arc_bus.clone();
+    let timing = arc_bus.timing();
+
+    spawn(move || {
+        let slab = SimSlab::new();
+
+        run_node(&arc_bus, &slab, &STOP, |dispatch, slab| {
+            let socket = dispatch.register_port(DISCOVERY_PORT).unwrap();
+
+            let mut sub_disco_1: SubDiscovery<GlobalRollingTimer, _, 8, TOTAL_SLABS, SLAB_SIZE> =
+                SubDiscovery::new(thread_rng(), dispatch, socket, slab.handle(), timing);
+            let sub_disco_future_1 = sub_disco_1.obtain_addr();
+            pin_mut!(sub_disco_future_1);
+            let mut cas_sub_1 = Cassette::new(sub_disco_future_1);
+            let mut cas_sub_1_done = false;
+
+            loop {
+                dispatch.process_messages();
+                if !cas_sub_1_done {
+                    if let Some(x) = cas_sub_1.poll_on() {
+                        match x {
+                            Ok(y) => {
+                                cas_sub_1_done = true;
+                                println!("cas_sub_1 addr: {:?}", y);
+                            }
+                            Err(e) => panic!("err! {:?}", e),
                         }
-                        Err(e) => panic!("err! {:?}", e),
                     }
                 }
-            }
-
-            // Rate limiting
-            sleep(Duration::from_micros(500));
-        }
-    });
-
-    let io = spawn(move || {
-        let CarePackage {
-            slab,
-            ioq,
-            dispatch,
-        } = loop {
-            if let Ok(mut care_g) = care_lock_1.lock() {
-                if let Some(care) = care_g.take() {
-                    break care;
-                }
-            }
 
-            sleep(Duration::from_millis(10));
-        };
-
-        let mut io_hdl = ioq.take_io_handle().unwrap();
-        let mut carry = vec![];
-        let timer = GlobalRollingTimer::default();
-
-        loop {
-            sleep(Duration::from_micros(500));
-
-            if let Some(msg) = io_hdl.pop_outgoing() {
-                dev_2.disable_listen();
-                dev_2.enable_transmit();
-                dev_2.send(msg.deref());
-                dev_2.disable_transmit();
-                dev_2.enable_listen();
-            }
-
-            carry.extend_from_slice(&dev_2.receive());
-
-            let pos = if let Some(pos) = carry.iter().position(|b| *b == 0) {
-                pos + 1
-            } else {
-                continue;
-            };
-            let mut remain = carry.split_off(pos);
-
-            core::mem::swap(&mut carry, &mut remain);
-            let current = remain;
-
-            if pos >= SLAB_SIZE {
-                println!("TOO BIG");
-            } else {
-                if let Some(mut sbox) = slab.alloc_box() {
-                    sbox[..pos].copy_from_slice(&current);
-
-                    // TODO: This is a hack!
-                    sbox[pos..].fill(0);
-
-                    io_hdl
-                        .push_incoming(TimeStampBox {
-                            packet: sbox,
-                            tick: timer.get_ticks(),
-                            len: pos,
-                        })
-                        .map_err(drop)
-                        .unwrap();
-                } else {
-                    println!("No alloc for io!");
-                }
+                // Rate limiting
+                sleep(Duration::from_micros(500));
             }
-        }
-    });
-
-    DevHdl { logic, io }
+        });
+    })
 }
@@ -0,0 +1,82 @@
+//! Print decoded anachro-485 traffic
+//!
+//! Usage:
+//!
+//! * `monitor <PATH>` reads raw bus bytes from a file or serial device.
+//!   Serial devices should be configured first, e.g. with
+//!   `stty -F /dev/ttyUSB0 1000000 raw`.
+//! * `monitor -` reads raw bus bytes from stdin.
+//! * `monitor --pcapng <PATH>` reads a capture made with `PcapngWriter`.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, Read},
+    process::exit,
+};
+
+use anachro_485::capture::{BusCapture, CaptureDir, PcapngReader};
+use sim_485::monitor::{describe_frame, FrameSplitter, MonitorTap};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["--pcapng", path] => File::open(path).and_then(replay),
+        ["-"] => live(io::stdin()),
+        [path] if !path.starts_with("--") => File::open(path).and_then(live),
+        _ => {
+            eprintln!("usage: monitor [--pcapng] <PATH|->");
+            exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("monitor: {}", e);
+        exit(1);
+    }
+}
+
+fn live<R: Read>(mut inp: R) -> io::Result<()> {
+    let tap = MonitorTap::new();
+    let mut splitter = FrameSplitter::new();
+    let mut buf = [0u8; 256];
+
+    loop {
+        let used = match inp.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(used) => used,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+
+        for frame in splitter.push(&buf[..used]) {
+            tap.capture(CaptureDir::Incoming, None, &frame);
+        }
+    }
+}
+
+fn replay(inp: File) -> io::Result<()> {
+    let mut reader = PcapngReader::new(io::BufReader::new(inp))?;
+    let mut first = None;
+
+    while let Some(frame) = reader.next_frame()? {
+        let start = *first.get_or_insert(frame.timestamp_us);
+        let dir = match frame.dir {
+            Some(CaptureDir::Incoming) => "<<",
+            Some(CaptureDir::Outgoing) => ">>",
+            None => "--",
+        };
+
+        println!(
+            "{:>10.6} {} {}",
+            frame.timestamp_us.saturating_sub(start) as f64 / 1_000_000.0,
+            dir,
+            describe_frame(&frame.data)
+        );
+    }
+
+    Ok(())
+}
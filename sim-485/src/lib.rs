@@ -1,8 +1,16 @@
 #![allow(unused_imports, dead_code)]
 
 pub mod groundhog_sim;
+pub mod monitor;
+pub mod node;
+
+use anachro_485::{
+    capture::{BusCapture, CaptureDir},
+    timing::BusTiming,
+};
 
 use std::{
+    fmt,
     sync::{
         atomic::{
             AtomicBool, AtomicU32, AtomicU8, AtomicUsize,
@@ -15,14 +23,24 @@ use std::{
     time::Duration,
 };
 
-// TODO: NOT a constant frequency
-// const BUS_FREQUENCY_HZ: u64 = BITS_PER_DATA_BYTE * 1_000_000;
-const BUS_FREQUENCY_HZ: u64 = 115200;
-// const BUS_FREQUENCY_HZ: u64 = 8_000_000;
 
-// Assuming 8N1 UART mode
-const BITS_PER_DATA_BYTE: u64 = 9;
-const NANOS_PER_BYTE: u64 = (1_000_000_000 * BITS_PER_DATA_BYTE) / BUS_FREQUENCY_HZ;
+// The protocol logs with defmt, which has nowhere to go on the host
+#[defmt::global_logger]
+struct NullLogger;
+
+unsafe impl defmt::Logger for NullLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
 
 static BUS_CTR: AtomicU32 = AtomicU32::new(1);
 static SIM_CTR: AtomicU32 = AtomicU32::new(1);
@@ -38,24 +56,48 @@ pub struct Rs485Device {
 
 #[derive(Debug)]
 pub struct Rs485Bus {
-    // TODO: Baud rate? Not a constant?
+    timing: BusTiming,
+    nanos_per_byte: u64,
     shared: Mutex<Rs485BusShared>,
     senders: AtomicU32,
     sim_bus_ident: u32,
+    tap: Mutex<BusTap>,
+}
+
+struct BusTap(Option<&'static dyn BusCapture>);
+
+impl fmt::Debug for BusTap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BusTap").field(&self.0.is_some()).finish()
+    }
 }
 
 impl Rs485Bus {
-    pub fn new_arc() -> Arc<Self> {
+    pub fn new_arc(timing: BusTiming) -> Arc<Self> {
         let shared = Mutex::new(Rs485BusShared::default());
         let senders = AtomicU32::new(0);
+        // The same (rounded up) byte time the bus timing is built on
+        let nanos_per_byte = timing.byte_us as u64 * 1_000;
 
         Arc::new(Self {
+            timing,
+            nanos_per_byte,
             shared,
             senders,
             sim_bus_ident: BUS_CTR.fetch_add(1, Ordering::SeqCst),
+            tap: Mutex::new(BusTap(None)),
         })
     }
 
+    /// Record every frame sent on the bus, as a listener would have seen it
+    pub fn set_capture(&self, capture: &'static dyn BusCapture) {
+        self.tap.lock().expect("Failed to lock mutex on capture").0 = Some(capture);
+    }
+
+    pub fn timing(&self) -> BusTiming {
+        self.timing
+    }
+
     fn add_device(&self, funnel: DeviceFunnel) {
         let mut lock = self
             .shared
@@ -69,29 +111,46 @@ impl Rs485Bus {
         lock.funnels.push(funnel);
     }
 
+    fn remove_device(&self, sim_ident: u32) {
+        let mut lock = self
+            .shared
+            .lock()
+            .expect("Failed to lock mutex on device remove");
+        lock.funnels.retain(|f| f.sim_ident != sim_ident);
+    }
+
     fn send_data(&self, data: &[u8]) {
         // The bus should be active at the time of sending
         let mut lock = self
             .shared
             .lock()
             .expect("Failed to lock mutex on data send");
+        let mut seen = Vec::with_capacity(data.len());
         for byte in data {
             // ha ha! rate limiting!
             let senders_before_good = self.senders.load(SeqCst) == 1;
-            sleep(Duration::from_nanos(NANOS_PER_BYTE));
+            sleep(Duration::from_nanos(self.nanos_per_byte));
             let senders_after_good = self.senders.load(SeqCst) == 1;
 
+            let byte = if senders_before_good && senders_after_good {
+                *byte
+            } else {
+                // println!("Corrupted byte!");
+                0xAF
+            };
+            seen.push(byte);
+
             for dev in lock.funnels.iter_mut() {
                 if dev.listening.load(SeqCst) {
-                    if senders_before_good && senders_after_good {
-                        dev.sender.send(*byte).unwrap();
-                    } else {
-                        // println!("Corrupted byte!");
-                        dev.sender.send(0xAF).unwrap();
-                    }
+                    dev.sender.send(byte).unwrap();
                 }
             }
         }
+        drop(lock);
+
+        if let Some(cap) = self.tap.lock().expect("Failed to lock mutex on capture").0 {
+            cap.capture(CaptureDir::Incoming, None, &seen);
+        }
     }
 }
 
@@ -161,3 +220,12 @@ impl Rs485Device {
         payload
     }
 }
+
+impl Drop for Rs485Device {
+    fn drop(&mut self) {
+        if self.sending {
+            self.disable_transmit();
+        }
+        self.bus.remove_device(self.sim_dev_ident);
+    }
+}
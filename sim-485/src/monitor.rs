@@ -0,0 +1,247 @@
+//! Decoding and printing bus traffic, for humans
+//!
+//! Used by the `monitor` binary, and usable as a `BusCapture` with
+//! `Rs485Bus::set_capture()` to watch a simulated bus directly.
+
+use std::{fmt::Write, ops::Deref, time::Instant};
+
+use anachro_485::{
+    capture::{decode_frame, BusCapture, CaptureDir, CapturedMessage},
+    dom::{DISCOVERY_PORT, LOG_PORT, MANAGEMENT_PORT, OTA_PORT, TOKEN_PORT},
+    icd::{
+        AddrPort, DomDiscoveryPayload, DomMgmtPayload, DomOtaPayload, DomTokenGrantPayload,
+        LogRecord, SubDiscoveryPayload, SubMgmtPayload, SubOtaPayload, SubTokenReleasePayload,
+        LOCAL_DOM_ADDR, SLAB_SIZE, TOTAL_SLABS,
+    },
+};
+use postcard::{from_bytes, take_from_bytes};
+
+/// The largest frame the monitor will try to decode
+pub const MAX_FRAME: usize = 4 * SLAB_SIZE;
+
+/// Splits a raw byte stream into COBS frames
+#[derive(Debug, Default)]
+pub struct FrameSplitter {
+    carry: Vec<u8>,
+}
+
+impl FrameSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add bytes from the stream, returning any frames they complete.
+    /// Each frame includes its zero terminator.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+
+        for &byte in data {
+            self.carry.push(byte);
+            if byte == 0 {
+                frames.push(core::mem::take(&mut self.carry));
+            } else if self.carry.len() > MAX_FRAME {
+                // Junk, or a lost terminator. Hold on to the newest bytes
+                // only, in case the end of a real frame is in there
+                self.carry.drain(..SLAB_SIZE);
+            }
+        }
+
+        frames
+    }
+}
+
+/// The name of a well known port
+pub fn port_name(port: u16) -> Option<&'static str> {
+    match port {
+        DISCOVERY_PORT => Some("discovery"),
+        TOKEN_PORT => Some("token"),
+        LOG_PORT => Some("log"),
+        OTA_PORT => Some("ota"),
+        MANAGEMENT_PORT => Some("mgmt"),
+        _ => None,
+    }
+}
+
+/// Describe a single captured frame on one line
+pub fn describe_frame(frame: &[u8]) -> String {
+    // Lone terminators are just line noise
+    if frame.iter().all(|b| *b == 0) {
+        return format!("<empty frame, {} bytes>", frame.len());
+    }
+
+    let mut buf = vec![0u8; frame.len()];
+    match decode_frame(frame, &mut buf) {
+        Ok(msg) => describe_message(&msg),
+        Err(e) => format!("<{:?} error> {}", e, hex(frame)),
+    }
+}
+
+fn describe_message(msg: &CapturedMessage<'_>) -> String {
+    let from_dom = msg.src.addr.get_exact_local_addr() == Some(LOCAL_DOM_ADDR);
+
+    let body = match msg.dst.port {
+        DISCOVERY_PORT if from_dom => debug::<DomDiscoveryPayload>(msg.payload),
+        DISCOVERY_PORT => debug::<SubDiscoveryPayload>(msg.payload),
+        TOKEN_PORT if from_dom => debug::<DomTokenGrantPayload>(msg.payload),
+        TOKEN_PORT => debug::<SubTokenReleasePayload>(msg.payload),
+        OTA_PORT if from_dom => describe_ota(msg.payload),
+        OTA_PORT => debug::<SubOtaPayload>(msg.payload),
+        LOG_PORT if !from_dom => describe_logs(msg.payload),
+        MANAGEMENT_PORT if from_dom => debug::<DomMgmtPayload>(msg.payload),
+        MANAGEMENT_PORT => debug::<SubMgmtPayload>(msg.payload),
+        _ => None,
+    };
+
+    let sent = match msg.tick {
+        Some(tick) => format!(" @{}", tick),
+        None => String::new(),
+    };
+
+    format!(
+        "{} -> {}{} | {}",
+        describe_addr(&msg.src),
+        describe_addr(&msg.dst),
+        sent,
+        body.unwrap_or_else(|| hex(msg.payload)),
+    )
+}
+
+fn describe_addr(ap: &AddrPort) -> String {
+    let addr = match ap.addr.get_exact_local_addr() {
+        Some(addr) => format!("{}", addr),
+        None => format!("{:?}", ap.addr),
+    };
+
+    match port_name(ap.port) {
+        Some(name) => format!("{}:{}({})", addr, ap.port, name),
+        None => format!("{}:{}", addr, ap.port),
+    }
+}
+
+fn debug<'a, T>(payload: &'a [u8]) -> Option<String>
+where
+    T: serde::Deserialize<'a> + core::fmt::Debug,
+{
+    from_bytes::<T>(payload).ok().map(|t| format!("{:?}", t))
+}
+
+fn describe_ota(payload: &[u8]) -> Option<String> {
+    // Chunks are large, and the contents are not interesting
+    match from_bytes::<DomOtaPayload<TOTAL_SLABS, SLAB_SIZE>>(payload).ok()? {
+        DomOtaPayload::Chunk { idx, data, .. } => {
+            Some(format!("Chunk {{ idx: {}, len: {} }}", idx, data.len()))
+        }
+        other => Some(format!("{:?}", other)),
+    }
+}
+
+fn describe_logs(mut payload: &[u8]) -> Option<String> {
+    let mut out = String::new();
+
+    while !payload.is_empty() {
+        let (record, rest) = take_from_bytes::<LogRecord<TOTAL_SLABS, SLAB_SIZE>>(payload).ok()?;
+        payload = rest;
+
+        write!(
+            &mut out,
+            "[{} {:?}] {:?} ",
+            record.tick,
+            record.level,
+            record.text.deref()
+        )
+        .ok()?;
+    }
+
+    Some(out)
+}
+
+fn hex(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() * 3);
+    for (i, byte) in data.iter().enumerate() {
+        if i != 0 {
+            out.push(' ');
+        }
+        write!(&mut out, "{:02X}", byte).ok();
+    }
+    out
+}
+
+/// A `BusCapture` that prints every frame to stdout
+pub struct MonitorTap {
+    start: Instant,
+}
+
+impl MonitorTap {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonitorTap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusCapture for MonitorTap {
+    fn capture(&self, dir: CaptureDir, _tick: Option<u32>, frame: &[u8]) {
+        let dir = match dir {
+            CaptureDir::Incoming => "<<",
+            CaptureDir::Outgoing => ">>",
+        };
+
+        println!(
+            "{:>10.6} {} {}",
+            self.start.elapsed().as_secs_f64(),
+            dir,
+            describe_frame(frame)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_across_reads() {
+        let mut splitter = FrameSplitter::new();
+
+        assert!(splitter.push(&[1, 2]).is_empty());
+        assert!(splitter.push(&[3]).is_empty());
+        assert_eq!(splitter.push(&[4, 0, 5]), vec![vec![1, 2, 3, 4, 0]]);
+        assert_eq!(splitter.push(&[6, 0]), vec![vec![5, 6, 0]]);
+    }
+
+    #[test]
+    fn back_to_back_delimiters() {
+        let mut splitter = FrameSplitter::new();
+
+        assert_eq!(
+            splitter.push(&[0, 0, 1, 0, 0]),
+            vec![vec![0], vec![0], vec![1, 0], vec![0]]
+        );
+        assert!(splitter.push(&[]).is_empty());
+    }
+
+    #[test]
+    fn too_large() {
+        let mut splitter = FrameSplitter::new();
+
+        // Junk with no terminator is trimmed as it arrives
+        let junk = vec![0xAA; MAX_FRAME + 1];
+        assert!(splitter.push(&junk).is_empty());
+        assert!(splitter.carry.len() <= MAX_FRAME);
+
+        // Only the newest bytes are kept with the frame that ends it
+        let frames = splitter.push(&[1, 2, 0]);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), MAX_FRAME + 1 - SLAB_SIZE + 3);
+        assert!(frames[0].ends_with(&[0xAA, 1, 2, 0]));
+
+        // And the next frame is unaffected
+        assert_eq!(splitter.push(&[3, 0]), vec![vec![3, 0]]);
+    }
+}
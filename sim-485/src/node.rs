@@ -0,0 +1,160 @@
+//! A simulated node: one slab, IO queue and dispatcher on a bus
+//!
+//! `run_node()` owns the whole stack of a node for as long as it runs, and
+//! drops all of it once the node is stopped, so a simulation can start and
+//! stop nodes without leaking their slabs.
+
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    thread::{scope, sleep},
+    time::Duration,
+};
+
+use anachro_485::{
+    dispatch::{Dispatch, IoQueue, TimeStampBox},
+    icd::{SLAB_SIZE, TOTAL_SLABS},
+};
+use byte_slab::HeapSlab;
+use groundhog::RollingTimer;
+
+use crate::{groundhog_sim::GlobalRollingTimer, Rs485Bus, Rs485Device};
+
+pub type SimSlab = HeapSlab<TOTAL_SLABS, SLAB_SIZE>;
+pub type SimDispatch<'a> = Dispatch<'a, 8, TOTAL_SLABS, SLAB_SIZE>;
+
+/// Run a node on `bus` until `stop` is set.
+///
+/// `logic` is run on its own thread with the node's dispatcher and slab, and
+/// should drive the dispatcher (and any tasks) until `stop` is set. The IO
+/// side runs on another thread. This returns once both threads are done,
+/// with every part of the node's stack dropped.
+pub fn run_node<F>(bus: &Arc<Rs485Bus>, slab: &SimSlab, stop: &AtomicBool, logic: F)
+where
+    F: FnOnce(&SimDispatch<'_>, &SimSlab) + Send,
+{
+    let mut dev = Rs485Device::new(bus);
+    dev.enable_listen();
+
+    let ioq: IoQueue<TOTAL_SLABS, SLAB_SIZE> = IoQueue::new();
+
+    scope(|s| {
+        s.spawn(|| {
+            let dispatch: SimDispatch<'_> = Dispatch::new(&ioq, slab.handle());
+            logic(&dispatch, slab);
+        });
+        s.spawn(|| run_io(dev, &ioq, slab, stop));
+    });
+}
+
+fn run_io(
+    mut dev: Rs485Device,
+    ioq: &IoQueue<TOTAL_SLABS, SLAB_SIZE>,
+    slab: &SimSlab,
+    stop: &AtomicBool,
+) {
+    let mut io_hdl = ioq.take_io_handle().unwrap();
+    let mut carry = vec![];
+    let timer = GlobalRollingTimer::default();
+
+    while !stop.load(SeqCst) {
+        sleep(Duration::from_micros(500));
+
+        if let Some(msg) = io_hdl.pop_outgoing() {
+            dev.disable_listen();
+            dev.enable_transmit();
+            dev.send(msg.packet.deref());
+            dev.disable_transmit();
+            dev.enable_listen();
+        }
+
+        carry.extend_from_slice(&dev.receive());
+
+        let pos = if let Some(pos) = carry.iter().position(|b| *b == 0) {
+            pos + 1
+        } else {
+            continue;
+        };
+        let mut remain = carry.split_off(pos);
+
+        core::mem::swap(&mut carry, &mut remain);
+        let current = remain;
+
+        if pos >= SLAB_SIZE {
+            println!("TOO BIG");
+        } else if let Some(mut sbox) = slab.alloc_box() {
+            sbox[..pos].copy_from_slice(&current);
+
+            // TODO: This is a hack!
+            sbox[pos..].fill(0);
+
+            io_hdl
+                .push_incoming(TimeStampBox {
+                    packet: sbox,
+                    len: pos,
+                    tick: timer.get_ticks(),
+                })
+                .map_err(drop)
+                .unwrap();
+        } else {
+            println!("No alloc for io!");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anachro_485::{
+        icd::{AddrPort, VecAddr, LOCAL_BROADCAST_ADDR},
+        timing::BusTiming,
+    };
+    use std::thread::spawn;
+
+    #[test]
+    fn frees_slab() {
+        let bus = Rs485Bus::new_arc(BusTiming::from_baud(1_000_000, SLAB_SIZE as u32));
+        let stop = Arc::new(AtomicBool::new(false));
+        let slabs = [SimSlab::new(), SimSlab::new()];
+
+        let stopper = {
+            let stop = stop.clone();
+            spawn(move || {
+                sleep(Duration::from_millis(100));
+                stop.store(true, SeqCst);
+            })
+        };
+
+        scope(|s| {
+            for (addr, slab) in slabs.iter().enumerate() {
+                let (bus, stop) = (&bus, &*stop);
+                s.spawn(move || {
+                    run_node(bus, slab, stop, |dispatch, _slab| {
+                        dispatch.set_addr(addr as u8);
+                        let socket = dispatch.register_port(100).unwrap();
+                        let dst = AddrPort::from_parts(
+                            VecAddr::from_local_addr(LOCAL_BROADCAST_ADDR),
+                            100,
+                        );
+
+                        // The sub never reads, so messages are left in every queue
+                        while !stop.load(SeqCst) {
+                            if addr == 0 {
+                                let _ = socket.try_send_msg([1u8; 8], dst.clone());
+                            }
+                            dispatch.process_messages();
+                            sleep(Duration::from_micros(500));
+                        }
+                    })
+                });
+            }
+        });
+        stopper.join().unwrap();
+
+        // Only our own handles are left
+        assert!(slabs.iter().all(|slab| slab.ref_count() == 1));
+    }
+}
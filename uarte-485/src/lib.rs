@@ -8,10 +8,10 @@ use core::{
 };
 
 use anachro_485::{
-    dispatch::{IoHandle, TimeStampBox},
-    icd::{SLAB_SIZE, TOTAL_SLABS},
+    dispatch::{IoHandle, PacketBuf, TimeStampBox},
+    timing::{BusTiming, IO_POLL_US},
 };
-use byte_slab::{BSlab, ManagedArcSlab, SlabBox};
+use byte_slab::{BSlab, SlabBox};
 use defmt::{error, info, warn};
 use groundhog::RollingTimer;
 use nrf52840_hal::{
@@ -24,44 +24,44 @@ use nrf52840_hal::{
     uarte::{Baudrate, Instance as UarteInstance, Parity, Pins},
 };
 
-type UarteBSlab = BSlab<TOTAL_SLABS, SLAB_SIZE>;
-type UarteBox = SlabBox<TOTAL_SLABS, SLAB_SIZE>;
-type UarteMas = ManagedArcSlab<'static, TOTAL_SLABS, SLAB_SIZE>;
+type UarteBox<const N: usize, const SZ: usize> = SlabBox<N, SZ>;
+type UartePacket<const N: usize, const SZ: usize> = PacketBuf<N, SZ>;
 
 struct ReceiveTime {
     start: u32,
     ticks: u32,
 }
 
-pub struct Uarte485<Timer, Channel, Uarte, Clock>
+pub struct Uarte485<Timer, Channel, Uarte, Clock, const N: usize, const SZ: usize>
 where
     Timer: TimerInstance,
     Channel: Ppi + ConfigurablePpi,
     Uarte: Uarte485Instance,
     Clock: RollingTimer<Tick = u32> + Default,
 {
-    alloc: &'static UarteBSlab,
+    alloc: &'static BSlab<N, SZ>,
     timer: Timer,
     channel: Channel,
     uarte: Uarte,
     pins: InternalPin485,
-    state: State485,
-    io_hdl: IoHandle,
+    state: State485<N, SZ>,
+    io_hdl: IoHandle<'static, N, SZ>,
     _clock: PhantomData<Clock>,
     default_to: DefaultTo,
+    timing: BusTiming,
 
     receive_for: Option<ReceiveTime>,
 }
 
-enum State485 {
-    Idle,                       // 0b00
-    RxAwaitFirstByte(UarteBox), // 0b10
-    RxReceiving(UarteBox),      // 0b11
-    TxSending(UarteMas),        // 0b01
+enum State485<const N: usize, const SZ: usize> {
+    Idle,                              // 0b00
+    RxAwaitFirstByte(UarteBox<N, SZ>), // 0b10
+    RxReceiving(UarteBox<N, SZ>),      // 0b11
+    TxSending(UartePacket<N, SZ>),     // 0b01
     Invalid,
 }
 
-impl State485 {
+impl<const N: usize, const SZ: usize> State485<N, SZ> {
     fn log_state(&self) {
         match self {
             State485::Idle => defmt::trace!("state: Idle"),
@@ -154,7 +154,24 @@ pub enum DefaultTo {
     Receiving,
 }
 
-impl<Timer, Channel, Uarte, Clock> Uarte485<Timer, Channel, Uarte, Clock>
+fn nrf_baudrate(baud: u32) -> Option<Baudrate> {
+    Some(match baud {
+        9_600 => Baudrate::BAUD9600,
+        19_200 => Baudrate::BAUD19200,
+        38_400 => Baudrate::BAUD38400,
+        57_600 => Baudrate::BAUD57600,
+        115_200 => Baudrate::BAUD115200,
+        230_400 => Baudrate::BAUD230400,
+        250_000 => Baudrate::BAUD250000,
+        460_800 => Baudrate::BAUD460800,
+        921_600 => Baudrate::BAUD921600,
+        1_000_000 => Baudrate::BAUD1M,
+        _ => return None,
+    })
+}
+
+impl<Timer, Channel, Uarte, Clock, const N: usize, const SZ: usize>
+    Uarte485<Timer, Channel, Uarte, Clock, N, SZ>
 where
     Timer: TimerInstance,
     Channel: Ppi + ConfigurablePpi,
@@ -162,13 +179,14 @@ where
     Clock: RollingTimer<Tick = u32> + Default,
 {
     pub fn new(
-        alloc: &'static UarteBSlab,
+        alloc: &'static BSlab<N, SZ>,
         timer: Timer,
         mut channel: Channel,
         uarte: Uarte,
         pins: Pin485,
-        ioh: IoHandle,
+        ioh: IoHandle<'static, N, SZ>,
         default_to: DefaultTo,
+        timing: BusTiming,
     ) -> Self {
         let pins = InternalPin485 {
             ctl: pins.ctl,
@@ -199,8 +217,11 @@ where
                 w
             });
 
-            // TODO: Variable baudrate?
-            uarte.baudrate.write(|w| w.baudrate().baud1m());
+            let baudrate = match nrf_baudrate(timing.baud) {
+                Some(baudrate) => baudrate,
+                None => defmt::panic!("Unsupported baud rate {=u32}!", timing.baud),
+            };
+            uarte.baudrate.write(|w| w.baudrate().variant(baudrate));
             uarte.intenclr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
         }
 
@@ -230,6 +251,7 @@ where
             state: State485::Idle,
             io_hdl: ioh,
             default_to,
+            timing,
             _clock: PhantomData,
             receive_for: None,
         }
@@ -273,7 +295,7 @@ where
         }
     }
 
-    pub fn prepare_send(&mut self, msg: &UarteMas) {
+    pub fn prepare_send(&mut self, msg: &UartePacket<N, SZ>) {
         defmt::assert!(EASY_DMA_SIZE >= msg.len());
 
         // GPIOs
@@ -333,7 +355,7 @@ where
         }
     }
 
-    pub fn complete_send(&mut self, msg: &UarteMas) -> Result<(), ()> {
+    pub fn complete_send(&mut self, msg: &UartePacket<N, SZ>) -> Result<(), ()> {
         let endtx = self.uarte.events_endtx.read().events_endtx().bit_is_set();
         if !endtx {
             return Err(());
@@ -369,8 +391,8 @@ where
         Ok(())
     }
 
-    pub fn prepare_recv_initial(&mut self, sbox: &mut UarteBox) {
-        defmt::assert!(EASY_DMA_SIZE >= SLAB_SIZE);
+    pub fn prepare_recv_initial(&mut self, sbox: &mut UarteBox<N, SZ>) {
+        defmt::assert!(EASY_DMA_SIZE >= SZ);
 
         // Manage timer
         {
@@ -384,8 +406,7 @@ where
             // This is the timer that triggers when idle
             self.timer.enable_interrupt();
 
-            // TODO: Don't hardcode 1000us
-            self.timer.timer_start(1_000u32);
+            self.timer.timer_start(self.timing.io_first_byte_us);
         }
 
         // Manage gpios
@@ -463,19 +484,17 @@ where
             // This is the timer that triggers when idle
             self.timer.enable_interrupt();
 
-            // TODO: Don't hardcode 100us
-            self.timer.timer_start(100u32);
+            self.timer.timer_start(self.timing.io_quiet_us);
 
-            // This resets the timer every time we get a byte
-            // Each byte takes ~9uS at 1mbit, so this is 10 or so
-            // "quiet bytes" to allow a full timeout
+            // This resets the timer every time we get a byte, so
+            // the line must be quiet for the whole time to time out
             self.channel.enable();
         }
 
         Ok(())
     }
 
-    pub fn complete_recv(&mut self, sbox: UarteBox) {
+    pub fn complete_recv(&mut self, sbox: UarteBox<N, SZ>) {
         {
             self.timer.disable_interrupt();
             self.timer.timer_cancel();
@@ -564,7 +583,7 @@ where
                         again = Again::Yes;
                         State485::Idle
                     } else {
-                        self.setup_timer_interrupt_oneshot_us(1_000);
+                        self.setup_timer_interrupt_oneshot_us(self.timing.io_first_byte_us);
                         State485::RxAwaitFirstByte(sbox)
                     }
                 } else {
@@ -629,7 +648,7 @@ where
                 && !self.io_hdl.auth().is_send_authd())
     }
 
-    fn handle_idle(&mut self) -> State485 {
+    fn handle_idle(&mut self) -> State485<N, SZ> {
         // Okay, figure out where to go from here.
         //
         // * If a send is auth'd, or if we default to send, do that
@@ -656,7 +675,7 @@ where
                 self.uarte
                     .intenclr
                     .write(|w| unsafe { w.bits(0xFFFF_FFFF) });
-                self.setup_timer_interrupt_oneshot_us(1_000);
+                self.setup_timer_interrupt_oneshot_us(IO_POLL_US);
                 State485::Idle
             }
         } else {